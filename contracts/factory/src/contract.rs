@@ -0,0 +1,456 @@
+#[cfg(not(feature = "library"))]
+use cosmwasm_std::entry_point;
+use cosmwasm_std::{
+    to_binary, Addr, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdError,
+    StdResult, CosmosMsg, WasmMsg, SubMsg, Reply, SubMsgResult,
+};
+use animal_swap::contract::{parse_instantiate_address, instantiate_address_from_events};
+use animal_swap::msg::{InstantiateMsg as PoolInstantiateMsg, LpTokenConfig};
+use crate::msg::{
+    ExecuteMsg, InstantiateMsg, QueryMsg, ConfigResponse, PoolResponse, PoolsResponse,
+};
+use crate::state::{CONFIG, Config, POOLS, POOL_TOKENS, PoolRecord, PENDING_POOL, PendingPool};
+
+const INSTANTIATE_POOL_REPLY_ID: u64 = 0;
+const MAX_POOLS_PAGE_SIZE: u32 = 100;
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn instantiate(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    msg: InstantiateMsg,
+) -> StdResult<Response> {
+    let pool_admin = match msg.pool_admin {
+        Some(admin) => deps.api.addr_validate(&admin)?,
+        None => info.sender.clone(),
+    };
+
+    let config = Config {
+        owner: info.sender,
+        pool_code_id: msg.pool_code_id,
+        pool_code_hash: msg.pool_code_hash,
+        pool_admin,
+        contract_manager: deps.api.addr_validate(&msg.contract_manager)?,
+        token_erth_contract: deps.api.addr_validate(&msg.token_erth_contract)?,
+        token_erth_hash: msg.token_erth_hash,
+        registration_contract: deps.api.addr_validate(&msg.registration_contract)?,
+        registration_hash: msg.registration_hash,
+        lp_token_code_id: msg.lp_token_code_id,
+        lp_token_hash: msg.lp_token_hash,
+        lp_staking_contract: deps.api.addr_validate(&msg.lp_staking_contract)?,
+        lp_staking_hash: msg.lp_staking_hash,
+        protocol_fee: msg.protocol_fee,
+    };
+
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "instantiate"))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn execute(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, StdError> {
+    match msg {
+        ExecuteMsg::CreatePool {
+            token_b_contract,
+            token_b_hash,
+            token_b_symbol,
+            lp_token_decimals,
+            lp_token_config,
+            entropy,
+        } => execute_create_pool(
+            deps, info, token_b_contract, token_b_hash, token_b_symbol, lp_token_decimals, lp_token_config, entropy,
+        ),
+        ExecuteMsg::RegisterPool { token_b_contract, token_b_hash, token_b_symbol, contract, hash } =>
+            execute_register_pool(deps, info, token_b_contract, token_b_hash, token_b_symbol, contract, hash),
+        ExecuteMsg::RemovePool { token_b_contract } => execute_remove_pool(deps, info, token_b_contract),
+        ExecuteMsg::UpdateConfig { key, value } => execute_update_config(deps, info, key, value),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn execute_create_pool(
+    deps: DepsMut,
+    info: MessageInfo,
+    token_b_contract: String,
+    token_b_hash: String,
+    token_b_symbol: String,
+    lp_token_decimals: u8,
+    lp_token_config: Option<LpTokenConfig>,
+    entropy: String,
+) -> Result<Response, StdError> {
+    let config = CONFIG.load(deps.storage)?;
+
+    if info.sender != config.owner {
+        return Err(StdError::generic_err("unauthorized"));
+    }
+
+    let token_b_contract = deps.api.addr_validate(&token_b_contract)?;
+
+    if POOLS.contains(deps.storage, &token_b_contract) {
+        return Err(StdError::generic_err("A pool for this token already exists"));
+    }
+    if PENDING_POOL.may_load(deps.storage)?.is_some() {
+        return Err(StdError::generic_err("Another pool is being created"));
+    }
+
+    let pool_instantiate_msg = PoolInstantiateMsg {
+        contract_manager: config.contract_manager.to_string(),
+        token_erth_contract: config.token_erth_contract.to_string(),
+        token_erth_hash: config.token_erth_hash.clone(),
+        token_b_contract: token_b_contract.to_string(),
+        token_b_hash: token_b_hash.clone(),
+        token_b_denom: None,
+        registration_contract: config.registration_contract.to_string(),
+        registration_hash: config.registration_hash.clone(),
+        token_b_symbol: token_b_symbol.clone(),
+        lp_token_decimals,
+        lp_token_hash: config.lp_token_hash.clone(),
+        lp_token_code_id: config.lp_token_code_id,
+        lp_token_contract: None,
+        lp_staking_contract: config.lp_staking_contract.to_string(),
+        lp_staking_hash: config.lp_staking_hash.clone(),
+        protocol_fee: config.protocol_fee,
+        lp_token_config,
+        fee_side: None,
+        stable_swap_amp: None,
+        weights: None,
+        concentrated: None,
+        entropy,
+    };
+
+    // Labels must be unique, so include the token address alongside the symbol
+    let pool_msg = WasmMsg::Instantiate {
+        admin: Some(config.pool_admin.to_string()),
+        code_id: config.pool_code_id,
+        code_hash: config.pool_code_hash.clone(),
+        msg: to_binary(&pool_instantiate_msg)?,
+        funds: vec![],
+        label: format!("ERTH-{} Animal Swap Pool {}", token_b_symbol, token_b_contract),
+    };
+
+    PENDING_POOL.save(deps.storage, &PendingPool {
+        token_b_contract: token_b_contract.clone(),
+        token_b_hash,
+        token_b_symbol,
+    })?;
+
+    Ok(Response::new()
+        .add_submessage(SubMsg::reply_always(CosmosMsg::Wasm(pool_msg), INSTANTIATE_POOL_REPLY_ID))
+        .add_attribute("action", "create_pool")
+        .add_attribute("token_b_contract", token_b_contract))
+}
+
+pub fn execute_register_pool(
+    deps: DepsMut,
+    info: MessageInfo,
+    token_b_contract: String,
+    token_b_hash: String,
+    token_b_symbol: String,
+    contract: String,
+    hash: String,
+) -> Result<Response, StdError> {
+    let config = CONFIG.load(deps.storage)?;
+
+    if info.sender != config.owner {
+        return Err(StdError::generic_err("unauthorized"));
+    }
+
+    let token_b_contract = deps.api.addr_validate(&token_b_contract)?;
+
+    if POOLS.contains(deps.storage, &token_b_contract) {
+        return Err(StdError::generic_err("A pool for this token already exists"));
+    }
+
+    let pool = PoolRecord {
+        token_b_contract: token_b_contract.clone(),
+        token_b_hash,
+        token_b_symbol,
+        contract: deps.api.addr_validate(&contract)?,
+        hash,
+    };
+    POOLS.insert(deps.storage, &token_b_contract, &pool)?;
+    POOL_TOKENS.add_suffix(pool.contract.as_bytes()).save(deps.storage, &token_b_contract)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "register_pool")
+        .add_attribute("token_b_contract", token_b_contract)
+        .add_attribute("pool_contract", pool.contract))
+}
+
+pub fn execute_remove_pool(
+    deps: DepsMut,
+    info: MessageInfo,
+    token_b_contract: String,
+) -> Result<Response, StdError> {
+    let config = CONFIG.load(deps.storage)?;
+
+    if info.sender != config.owner {
+        return Err(StdError::generic_err("unauthorized"));
+    }
+
+    let token_b_contract = deps.api.addr_validate(&token_b_contract)?;
+
+    let pool = POOLS
+        .get(deps.storage, &token_b_contract)
+        .ok_or_else(|| StdError::generic_err("No pool for this token"))?;
+    POOLS.remove(deps.storage, &token_b_contract)?;
+    POOL_TOKENS.add_suffix(pool.contract.as_bytes()).remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("action", "remove_pool")
+        .add_attribute("token_b_contract", token_b_contract))
+}
+
+pub fn execute_update_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    key: String,
+    value: String,
+) -> Result<Response, StdError> {
+    let mut config = CONFIG.load(deps.storage)?;
+
+    if info.sender != config.owner {
+        return Err(StdError::generic_err("unauthorized"));
+    }
+
+    match key.as_str() {
+        "owner" => {
+            config.owner = deps.api.addr_validate(&value)?;
+        }
+        "pool_code_id" => {
+            config.pool_code_id = value.parse().map_err(|_| StdError::generic_err("Invalid pool_code_id"))?;
+        }
+        "pool_code_hash" => {
+            config.pool_code_hash = value.clone();
+        }
+        "pool_admin" => {
+            config.pool_admin = deps.api.addr_validate(&value)?;
+        }
+        "contract_manager" => {
+            config.contract_manager = deps.api.addr_validate(&value)?;
+        }
+        "lp_token_code_id" => {
+            config.lp_token_code_id = value.parse().map_err(|_| StdError::generic_err("Invalid lp_token_code_id"))?;
+        }
+        "lp_token_hash" => {
+            config.lp_token_hash = value.clone();
+        }
+        "lp_staking_contract" => {
+            config.lp_staking_contract = deps.api.addr_validate(&value)?;
+        }
+        "lp_staking_hash" => {
+            config.lp_staking_hash = value.clone();
+        }
+        "protocol_fee" => {
+            config.protocol_fee = value.parse().map_err(|_| StdError::generic_err("Invalid protocol_fee"))?;
+        }
+        _ => return Err(StdError::generic_err("Invalid config key")),
+    }
+
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "update_config")
+        .add_attribute("key", key)
+        .add_attribute("value", value))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, _env: Env, msg: Reply) -> StdResult<Response> {
+    match msg.id {
+        INSTANTIATE_POOL_REPLY_ID => handle_instantiate_pool_reply(deps, msg),
+        _ => Err(StdError::generic_err("Unknown reply ID")),
+    }
+}
+
+fn handle_instantiate_pool_reply(deps: DepsMut, msg: Reply) -> StdResult<Response> {
+    let config = CONFIG.load(deps.storage)?;
+    let pending = PENDING_POOL
+        .may_load(deps.storage)?
+        .ok_or_else(|| StdError::generic_err("No pool is being created"))?;
+    PENDING_POOL.remove(deps.storage);
+
+    let res = match msg.result {
+        SubMsgResult::Ok(res) => res,
+        SubMsgResult::Err(err) => {
+            return Err(StdError::generic_err(format!("Pool instantiation failed: {}", err)))
+        }
+    };
+
+    // Prefer the address from the instantiate response data, falling back to the events
+    let contract_address = match res.data.as_ref().map(|data| parse_instantiate_address(data)) {
+        Some(Ok(address)) => address,
+        _ => instantiate_address_from_events(&res.events, config.pool_code_id)?,
+    };
+    let pool_contract = deps.api.addr_validate(&contract_address)?;
+
+    POOLS.insert(deps.storage, &pending.token_b_contract, &PoolRecord {
+        token_b_contract: pending.token_b_contract.clone(),
+        token_b_hash: pending.token_b_hash,
+        token_b_symbol: pending.token_b_symbol,
+        contract: pool_contract.clone(),
+        hash: config.pool_code_hash,
+    })?;
+    POOL_TOKENS.add_suffix(pool_contract.as_bytes()).save(deps.storage, &pending.token_b_contract)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "instantiate_pool")
+        .add_attribute("token_b_contract", pending.token_b_contract)
+        .add_attribute("pool_contract", pool_contract))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::Config {} => to_binary(&ConfigResponse {
+            config: CONFIG.load(deps.storage)?,
+        }),
+        QueryMsg::Pool { token_b_contract } => {
+            let token_b_contract = deps.api.addr_validate(&token_b_contract)?;
+            to_binary(&query_pool(deps, token_b_contract)?)
+        },
+        QueryMsg::PoolByContract { contract } => {
+            let contract = deps.api.addr_validate(&contract)?;
+            to_binary(&query_pool_by_contract(deps, &contract)?)
+        },
+        QueryMsg::Pools { page, page_size } => to_binary(&query_pools(deps, page, page_size)?),
+    }
+}
+
+pub fn query_pool_by_contract(deps: Deps, contract: &Addr) -> StdResult<PoolResponse> {
+    let token_b_contract = POOL_TOKENS
+        .add_suffix(contract.as_bytes())
+        .may_load(deps.storage)?
+        .ok_or_else(|| StdError::generic_err("Not an indexed pool"))?;
+
+    query_pool(deps, token_b_contract)
+}
+
+pub fn query_pool(deps: Deps, token_b_contract: Addr) -> StdResult<PoolResponse> {
+    let pool = POOLS
+        .get(deps.storage, &token_b_contract)
+        .ok_or_else(|| StdError::generic_err("No pool for this token"))?;
+
+    Ok(PoolResponse { pool })
+}
+
+pub fn query_pools(deps: Deps, page: u32, page_size: u32) -> StdResult<PoolsResponse> {
+    let page_size = page_size.min(MAX_POOLS_PAGE_SIZE);
+
+    let pools = POOLS
+        .paging(deps.storage, page, page_size)?
+        .into_iter()
+        .map(|(_, pool)| pool)
+        .collect();
+
+    Ok(PoolsResponse {
+        pools,
+        total: POOLS.get_len(deps.storage)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::{from_binary, Event, SubMsgResponse, Uint128};
+
+    fn setup(deps: DepsMut) {
+        let msg = InstantiateMsg {
+            pool_code_id: 7,
+            pool_code_hash: "pool_hash".to_string(),
+            pool_admin: None,
+            contract_manager: "manager".to_string(),
+            token_erth_contract: "erth".to_string(),
+            token_erth_hash: "erth_hash".to_string(),
+            registration_contract: "registration".to_string(),
+            registration_hash: "registration_hash".to_string(),
+            lp_token_code_id: 8,
+            lp_token_hash: "lp_token_hash".to_string(),
+            lp_staking_contract: "lp_staking".to_string(),
+            lp_staking_hash: "lp_staking_hash".to_string(),
+            protocol_fee: Uint128::new(50),
+        };
+        instantiate(deps, mock_env(), mock_info("owner", &[]), msg).unwrap();
+    }
+
+    fn create_pool_msg(token_b: &str) -> ExecuteMsg {
+        ExecuteMsg::CreatePool {
+            token_b_contract: token_b.to_string(),
+            token_b_hash: "token_b_hash".to_string(),
+            token_b_symbol: "ANML".to_string(),
+            lp_token_decimals: 8,
+            lp_token_config: None,
+            entropy: "entropy".to_string(),
+        }
+    }
+
+    fn instantiate_event(address: &str, code_id: u64) -> Event {
+        Event::new("instantiate")
+            .add_attribute("contract_address", address)
+            .add_attribute("code_id", code_id.to_string())
+    }
+
+    // The pool instantiates its LP token in turn, whose event can come first
+    fn pool_reply(address: &str) -> Reply {
+        Reply {
+            id: INSTANTIATE_POOL_REPLY_ID,
+            result: SubMsgResult::Ok(SubMsgResponse {
+                events: vec![instantiate_event("lp_token", 8), instantiate_event(address, 7)],
+                data: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn create_pool_indexes_instantiated_pool() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), create_pool_msg("token_b")).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        let CosmosMsg::Wasm(WasmMsg::Instantiate { msg, .. }) = &res.messages[0].msg else {
+            panic!("expected the pool instantiation");
+        };
+        let pool_msg = from_binary::<PoolInstantiateMsg>(msg).unwrap();
+        assert_eq!(pool_msg.lp_token_decimals, 8);
+        assert_eq!(pool_msg.entropy, "entropy");
+
+        reply(deps.as_mut(), mock_env(), pool_reply("pool")).unwrap();
+
+        let pool = query_pool(deps.as_ref(), Addr::unchecked("token_b")).unwrap().pool;
+        assert_eq!(pool.contract, Addr::unchecked("pool"));
+        assert_eq!(pool.hash, "pool_hash");
+        assert_eq!(query_pool_by_contract(deps.as_ref(), &Addr::unchecked("pool")).unwrap().pool, pool);
+        assert!(PENDING_POOL.may_load(&deps.storage).unwrap().is_none());
+        assert_eq!(query_pools(deps.as_ref(), 0, 10).unwrap().total, 1);
+    }
+
+    #[test]
+    fn create_pool_rejects_duplicates_and_strangers() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("stranger", &[]), create_pool_msg("token_b")).unwrap_err();
+        assert_eq!(err, StdError::generic_err("unauthorized"));
+
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), create_pool_msg("token_b")).unwrap();
+        reply(deps.as_mut(), mock_env(), pool_reply("pool")).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), create_pool_msg("token_b")).unwrap_err();
+        assert_eq!(err, StdError::generic_err("A pool for this token already exists"));
+
+        // A removed pool is no longer recognized by its address either
+        let remove = ExecuteMsg::RemovePool { token_b_contract: "token_b".to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), remove).unwrap();
+        let err = query_pool_by_contract(deps.as_ref(), &Addr::unchecked("pool")).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Not an indexed pool"));
+    }
+}
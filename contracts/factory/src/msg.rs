@@ -0,0 +1,81 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cosmwasm_std::Uint128;
+
+use animal_swap::msg::LpTokenConfig;
+
+use crate::state::{Config, PoolRecord};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct InstantiateMsg {
+    pub pool_code_id: u64,
+    pub pool_code_hash: String,
+    pub pool_admin: Option<String>,
+    pub contract_manager: String,
+    pub token_erth_contract: String,
+    pub token_erth_hash: String,
+    pub registration_contract: String,
+    pub registration_hash: String,
+    pub lp_token_code_id: u64,
+    pub lp_token_hash: String,
+    pub lp_staking_contract: String,
+    pub lp_staking_hash: String,
+    pub protocol_fee: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecuteMsg {
+    CreatePool {
+        token_b_contract: String,
+        token_b_hash: String,
+        token_b_symbol: String,
+        // Overridden by `lp_token_config.decimals` when that is set
+        lp_token_decimals: u8,
+        lp_token_config: Option<LpTokenConfig>,
+        // Passed to the pool to seed its viewing keys
+        entropy: String,
+    },
+    // Indexes a pool that was deployed by hand
+    RegisterPool {
+        token_b_contract: String,
+        token_b_hash: String,
+        token_b_symbol: String,
+        contract: String,
+        hash: String,
+    },
+    RemovePool {
+        token_b_contract: String,
+    },
+    UpdateConfig {
+        key: String,
+        value: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryMsg {
+    Config {},
+    Pool { token_b_contract: String },
+    // The pool record of an indexed pool contract, letting pools recognize each other
+    PoolByContract { contract: String },
+    Pools { page: u32, page_size: u32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ConfigResponse {
+    pub config: Config,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PoolResponse {
+    pub pool: PoolRecord,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PoolsResponse {
+    pub pools: Vec<PoolRecord>,
+    pub total: u32,
+}
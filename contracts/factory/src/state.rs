@@ -0,0 +1,53 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cosmwasm_std::{Addr, Uint128};
+
+use secret_toolkit_storage::{Keymap, Item};
+
+// Shared parameters every pool deployed by the factory is instantiated with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct Config {
+    pub owner: Addr,
+    pub pool_code_id: u64,
+    pub pool_code_hash: String,
+    pub pool_admin: Addr, // Contract admin of new pools, able to migrate them
+    pub contract_manager: Addr,
+    pub token_erth_contract: Addr,
+    pub token_erth_hash: String,
+    pub registration_contract: Addr,
+    pub registration_hash: String,
+    pub lp_token_code_id: u64,
+    pub lp_token_hash: String,
+    pub lp_staking_contract: Addr,
+    pub lp_staking_hash: String,
+    pub protocol_fee: Uint128,
+}
+
+// Everything needed to route through a pool, `contract` and `hash` fill a `HopDetails`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PoolRecord {
+    pub token_b_contract: Addr,
+    pub token_b_hash: String,
+    pub token_b_symbol: String,
+    pub contract: Addr,
+    pub hash: String,
+}
+
+// Token B of the pool being instantiated, read back in the reply
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PendingPool {
+    pub token_b_contract: Addr,
+    pub token_b_hash: String,
+    pub token_b_symbol: String,
+}
+
+pub static CONFIG: Item<Config> = Item::new(b"config");
+
+// Registry of ERTH pairs keyed by token B
+pub static POOLS: Keymap<Addr, PoolRecord> = Keymap::new(b"pools");
+
+// Token B of each indexed pool, suffixed with the pool's contract address
+pub static POOL_TOKENS: Item<Addr> = Item::new(b"pool_tokens");
+
+pub static PENDING_POOL: Item<PendingPool> = Item::new(b"pending_pool");
@@ -0,0 +1,420 @@
+#[cfg(not(feature = "library"))]
+use cosmwasm_std::entry_point;
+use cosmwasm_std::{
+    to_binary, from_binary, Addr, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdError,
+    StdResult, CosmosMsg, WasmMsg, QueryRequest, WasmQuery, Uint128,
+};
+use secret_toolkit::snip20;
+use animal_swap::msg::{
+    HopDetails, QueryMsg as PoolQueryMsg, QuerySwapResponse, ReceiveMsg as PoolReceiveMsg,
+};
+use animal_swap_factory::msg::{PoolResponse, QueryMsg as FactoryQueryMsg};
+use animal_swap_factory::state::PoolRecord;
+use crate::msg::{
+    ExecuteMsg, InstantiateMsg, QueryMsg, ReceiveMsg, ConfigResponse, RouteHop, RouteResponse,
+};
+use crate::state::{CONFIG, Config};
+
+// One swap through a pool of the route
+struct Leg {
+    pool: PoolRecord,
+    input_token: Addr,
+    output_token: Addr,
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn instantiate(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: InstantiateMsg,
+) -> StdResult<Response> {
+    let config = Config {
+        owner: info.sender,
+        factory_contract: deps.api.addr_validate(&msg.factory_contract)?,
+        factory_hash: msg.factory_hash,
+        token_erth_contract: deps.api.addr_validate(&msg.token_erth_contract)?,
+        token_erth_hash: msg.token_erth_hash,
+    };
+
+    CONFIG.save(deps.storage, &config)?;
+
+    // Every route touches ERTH, so it can always be sent in
+    let register_msg = register_receive_msg(&env, &config.token_erth_contract, &config.token_erth_hash)?;
+
+    Ok(Response::new()
+        .add_message(register_msg)
+        .add_attribute("action", "instantiate"))
+}
+
+fn register_receive_msg(env: &Env, token: &Addr, token_hash: &str) -> StdResult<CosmosMsg> {
+    Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: token.to_string(),
+        code_hash: token_hash.to_string(),
+        msg: to_binary(&snip20::HandleMsg::RegisterReceive {
+            code_hash: env.contract.code_hash.clone(),
+            padding: None,
+        })?,
+        funds: vec![],
+    }))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn execute(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, StdError> {
+    match msg {
+        ExecuteMsg::Receive { sender: _, from, amount, msg, memo: _, padding: _ } =>
+            execute_receive(deps, info, from, amount, msg),
+        ExecuteMsg::RegisterToken { token_contract, padding: _ } =>
+            execute_register_token(deps, env, token_contract),
+        ExecuteMsg::UpdateConfig { key, value, padding: _ } => execute_update_config(deps, info, key, value),
+    }
+}
+
+pub fn execute_receive(
+    deps: DepsMut,
+    info: MessageInfo,
+    from: String,
+    amount: Uint128,
+    msg: Binary,
+) -> Result<Response, StdError> {
+    let msg: ReceiveMsg = from_binary(&msg)?;
+    let from_addr = deps.api.addr_validate(&from)?;
+
+    match msg {
+        ReceiveMsg::Swap { output_token, min_received, padding: _ } =>
+            receive_swap(deps, info, from_addr, amount, output_token, min_received),
+    }
+}
+
+fn receive_swap(
+    deps: DepsMut,
+    info: MessageInfo,
+    from: Addr,
+    amount: Uint128,
+    output_token: String,
+    min_received: Option<Uint128>,
+) -> Result<Response, StdError> {
+    let config = CONFIG.load(deps.storage)?;
+    let input_token = info.sender;
+    let output_token = deps.api.addr_validate(&output_token)?;
+
+    let legs = route_legs(deps.as_ref(), &config, &input_token, &output_token)?;
+    let route = simulate_route(deps.as_ref(), &legs, amount)?;
+
+    // Fail early, the last pool enforces `min_received` again against the executed amounts
+    if let Some(min) = min_received {
+        if route.output_amount < min {
+            return Err(StdError::generic_err("Output amount is less than the minimum received amount"));
+        }
+    }
+
+    let first = &legs[0];
+    let input_hash = if input_token == config.token_erth_contract {
+        config.token_erth_hash.clone()
+    } else {
+        first.pool.token_b_hash.clone()
+    };
+
+    // The first pool hops to the second itself, paying out to `from` at the end
+    let swap_msg = PoolReceiveMsg::Swap {
+        min_received,
+        hop: legs.get(1).map(|leg| HopDetails {
+            contract: leg.pool.contract.to_string(),
+            hash: leg.pool.hash.clone(),
+        }),
+        user: Some(from.clone()),
+        padding: None,
+    };
+
+    let send_msg = snip20::HandleMsg::Send {
+        recipient: first.pool.contract.to_string(),
+        recipient_code_hash: Some(first.pool.hash.clone()),
+        amount,
+        msg: Some(to_binary(&swap_msg)?),
+        memo: None,
+        padding: None,
+    };
+
+    Ok(Response::new()
+        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: input_token.to_string(),
+            code_hash: input_hash,
+            msg: to_binary(&send_msg)?,
+            funds: vec![],
+        }))
+        .add_attribute("action", "route_swap")
+        .add_attribute("from", from)
+        .add_attribute("input_token", input_token)
+        .add_attribute("output_token", output_token)
+        .add_attribute("input_amount", amount.to_string())
+        .add_attribute("expected_output_amount", route.output_amount.to_string())
+        .add_attribute("hops", legs.len().to_string()))
+}
+
+pub fn execute_register_token(
+    deps: DepsMut,
+    env: Env,
+    token_contract: String,
+) -> Result<Response, StdError> {
+    let config = CONFIG.load(deps.storage)?;
+    let token_contract = deps.api.addr_validate(&token_contract)?;
+
+    // Only tokens with a pool can be routed, which is also where their code hash comes from
+    let token_hash = if token_contract == config.token_erth_contract {
+        config.token_erth_hash
+    } else {
+        query_pool(deps.as_ref(), &config, &token_contract)?.token_b_hash
+    };
+
+    Ok(Response::new()
+        .add_message(register_receive_msg(&env, &token_contract, &token_hash)?)
+        .add_attribute("action", "register_token")
+        .add_attribute("token_contract", token_contract))
+}
+
+pub fn execute_update_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    key: String,
+    value: String,
+) -> Result<Response, StdError> {
+    let mut config = CONFIG.load(deps.storage)?;
+
+    if info.sender != config.owner {
+        return Err(StdError::generic_err("unauthorized"));
+    }
+
+    match key.as_str() {
+        "owner" => {
+            config.owner = deps.api.addr_validate(&value)?;
+        }
+        "factory_contract" => {
+            config.factory_contract = deps.api.addr_validate(&value)?;
+        }
+        "factory_hash" => {
+            config.factory_hash = value.clone();
+        }
+        _ => return Err(StdError::generic_err("Invalid config key")),
+    }
+
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "update_config")
+        .add_attribute("key", key)
+        .add_attribute("value", value))
+}
+
+fn query_pool(deps: Deps, config: &Config, token_b_contract: &Addr) -> StdResult<PoolRecord> {
+    let response: PoolResponse = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: config.factory_contract.to_string(),
+        code_hash: config.factory_hash.clone(),
+        msg: to_binary(&FactoryQueryMsg::Pool {
+            token_b_contract: token_b_contract.to_string(),
+        })?,
+    }))?;
+
+    Ok(response.pool)
+}
+
+// The factory keeps one pool per token against ERTH, so the only route is that token's pool, or
+// the input token's pool into ERTH followed by the output token's
+fn route_legs(deps: Deps, config: &Config, input_token: &Addr, output_token: &Addr) -> StdResult<Vec<Leg>> {
+    if input_token == output_token {
+        return Err(StdError::generic_err("Input and output tokens must differ"));
+    }
+
+    let erth = &config.token_erth_contract;
+    let leg = |token_b: &Addr, input_token: &Addr, output_token: &Addr| -> StdResult<Leg> {
+        let pool = query_pool(deps, config, token_b)
+            .map_err(|_| StdError::generic_err("No route between these tokens"))?;
+        Ok(Leg {
+            pool,
+            input_token: input_token.clone(),
+            output_token: output_token.clone(),
+        })
+    };
+
+    if input_token == erth {
+        Ok(vec![leg(output_token, input_token, output_token)?])
+    } else if output_token == erth {
+        Ok(vec![leg(input_token, input_token, output_token)?])
+    } else {
+        Ok(vec![leg(input_token, input_token, erth)?, leg(output_token, erth, output_token)?])
+    }
+}
+
+fn simulate_route(deps: Deps, legs: &[Leg], amount: Uint128) -> StdResult<RouteResponse> {
+    let mut hops = Vec::with_capacity(legs.len());
+    let mut amount = amount;
+
+    for leg in legs {
+        let response: QuerySwapResponse = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr: leg.pool.contract.to_string(),
+            code_hash: leg.pool.hash.clone(),
+            msg: to_binary(&PoolQueryMsg::SimulateSwap {
+                input_token: leg.input_token.to_string(),
+                amount,
+            })?,
+        }))?;
+
+        amount = response.output_amount;
+        hops.push(RouteHop {
+            pool_contract: leg.pool.contract.clone(),
+            pool_hash: leg.pool.hash.clone(),
+            input_token: leg.input_token.clone(),
+            output_token: leg.output_token.clone(),
+            output_amount: response.output_amount,
+            protocol_fee_amount: response.protocol_fee_amount,
+        });
+    }
+
+    Ok(RouteResponse {
+        hops,
+        output_amount: amount,
+    })
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::Config {} => to_binary(&ConfigResponse {
+            config: CONFIG.load(deps.storage)?,
+        }),
+        QueryMsg::SimulateRoute { input_token, output_token, amount } => {
+            let config = CONFIG.load(deps.storage)?;
+            let input_token = deps.api.addr_validate(&input_token)?;
+            let output_token = deps.api.addr_validate(&output_token)?;
+            let legs = route_legs(deps, &config, &input_token, &output_token)?;
+            to_binary(&simulate_route(deps, &legs, amount)?)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage};
+    use cosmwasm_std::{ContractResult, OwnedDeps, SystemResult};
+
+    fn pool_record(token_b_contract: &str) -> PoolRecord {
+        PoolRecord {
+            token_b_contract: Addr::unchecked(token_b_contract),
+            token_b_hash: format!("{}_hash", token_b_contract),
+            token_b_symbol: "TOKEN".to_string(),
+            contract: Addr::unchecked(format!("pool_{}", token_b_contract)),
+            hash: "pool_hash".to_string(),
+        }
+    }
+
+    // Factory pools for token_b and token_c, each quoting half of the input
+    fn mock_deps() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, msg, .. } if contract_addr == "factory" => {
+                let response = match from_binary(msg).unwrap() {
+                    FactoryQueryMsg::Pool { token_b_contract } if ["token_b", "token_c"].contains(&token_b_contract.as_str()) => {
+                        to_binary(&PoolResponse { pool: pool_record(&token_b_contract) })
+                    }
+                    FactoryQueryMsg::Pool { .. } => {
+                        return SystemResult::Ok(ContractResult::Err("Pool not found".to_string()));
+                    }
+                    _ => panic!("unexpected factory query"),
+                };
+                SystemResult::Ok(ContractResult::Ok(response.unwrap()))
+            }
+            WasmQuery::Smart { msg, .. } => {
+                let PoolQueryMsg::SimulateSwap { amount, .. } = from_binary(msg).unwrap() else {
+                    panic!("unexpected pool query");
+                };
+                let response = QuerySwapResponse {
+                    protocol_fee_amount: Uint128::zero(),
+                    output_amount: amount / Uint128::new(2),
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&response).unwrap()))
+            }
+            _ => panic!("unexpected query"),
+        });
+
+        let msg = InstantiateMsg {
+            factory_contract: "factory".to_string(),
+            factory_hash: "factory_hash".to_string(),
+            token_erth_contract: "erth".to_string(),
+            token_erth_hash: "erth_hash".to_string(),
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deps
+    }
+
+    fn swap_receive(output_token: &str, min_received: Option<Uint128>) -> ExecuteMsg {
+        ExecuteMsg::Receive {
+            sender: "user".to_string(),
+            from: "user".to_string(),
+            amount: Uint128::new(1000),
+            msg: to_binary(&ReceiveMsg::Swap {
+                output_token: output_token.to_string(),
+                min_received,
+                padding: None,
+            }).unwrap(),
+            memo: None,
+            padding: None,
+        }
+    }
+
+    #[test]
+    fn routes_between_two_tokens_through_erth() {
+        let mut deps = mock_deps();
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info("token_b", &[]), swap_receive("token_c", None)).unwrap();
+        assert_eq!(res.messages.len(), 1);
+
+        let CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, code_hash, msg, .. }) = &res.messages[0].msg else {
+            panic!("expected a wasm execute");
+        };
+        assert_eq!(contract_addr, "token_b");
+        assert_eq!(code_hash, "token_b_hash");
+
+        let swap_msg = PoolReceiveMsg::Swap {
+            min_received: None,
+            hop: Some(HopDetails {
+                contract: "pool_token_c".to_string(),
+                hash: "pool_hash".to_string(),
+            }),
+            user: Some(Addr::unchecked("user")),
+            padding: None,
+        };
+        assert_eq!(msg, &to_binary(&snip20::HandleMsg::Send {
+            recipient: "pool_token_b".to_string(),
+            recipient_code_hash: Some("pool_hash".to_string()),
+            amount: Uint128::new(1000),
+            msg: Some(to_binary(&swap_msg).unwrap()),
+            memo: None,
+            padding: None,
+        }).unwrap());
+    }
+
+    #[test]
+    fn enforces_min_received_on_the_simulated_route() {
+        let mut deps = mock_deps();
+
+        let route: RouteResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::SimulateRoute {
+            input_token: "erth".to_string(),
+            output_token: "token_c".to_string(),
+            amount: Uint128::new(1000),
+        }).unwrap()).unwrap();
+        assert_eq!(route.hops.len(), 1);
+        assert_eq!(route.output_amount, Uint128::new(500));
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("token_b", &[]), swap_receive("token_c", Some(Uint128::new(251)))).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Output amount is less than the minimum received amount"));
+
+        // A token without a pool has no route
+        let err = execute(deps.as_mut(), mock_env(), mock_info("token_b", &[]), swap_receive("token_x", None)).unwrap_err();
+        assert_eq!(err, StdError::generic_err("No route between these tokens"));
+    }
+}
@@ -0,0 +1,79 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cosmwasm_std::{Addr, Binary, Uint128};
+
+use crate::state::Config;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct InstantiateMsg {
+    pub factory_contract: String,
+    pub factory_hash: String,
+    pub token_erth_contract: String,
+    pub token_erth_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecuteMsg {
+    Receive {
+        sender: String,
+        from: String,
+        amount: Uint128,
+        msg: Binary,
+        memo: Option<String>,
+        padding: Option<String>,
+    },
+    // Registers the router with a token the factory has a pool for, so it can be sent in
+    RegisterToken {
+        token_contract: String,
+        padding: Option<String>,
+    },
+    UpdateConfig {
+        key: String,
+        value: String,
+        padding: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiveMsg {
+    Swap {
+        output_token: String,
+        min_received: Option<Uint128>,
+        padding: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryMsg {
+    Config {},
+    SimulateRoute {
+        input_token: String,
+        output_token: String,
+        amount: Uint128,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ConfigResponse {
+    pub config: Config,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct RouteHop {
+    pub pool_contract: Addr,
+    pub pool_hash: String,
+    pub input_token: Addr,
+    pub output_token: Addr,
+    pub output_amount: Uint128,
+    pub protocol_fee_amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct RouteResponse {
+    pub hops: Vec<RouteHop>,
+    pub output_amount: Uint128,
+}
@@ -0,0 +1,17 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cosmwasm_std::Addr;
+
+use secret_toolkit_storage::Item;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct Config {
+    pub owner: Addr,
+    pub factory_contract: Addr, // Routes are built from the pools this factory indexes
+    pub factory_hash: String,
+    pub token_erth_contract: Addr,
+    pub token_erth_hash: String,
+}
+
+pub static CONFIG: Item<Config> = Item::new(b"config");
@@ -3,8 +3,8 @@ use std::fs::create_dir_all;
 
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
-use secret_raffle::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, RaffleNumberResponse};
-use secret_raffle::state::State;
+use animal_swap::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
+use animal_swap::state::State;
 
 fn main() {
     let mut out_dir = current_dir().unwrap();
@@ -16,5 +16,4 @@ fn main() {
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
     export_schema(&schema_for!(State), &out_dir);
-    export_schema(&schema_for!(RaffleNumberResponse), &out_dir);
 }
@@ -0,0 +1,194 @@
+use cosmwasm_std::{Decimal256, StdError, StdResult, Storage, Uint128, Uint256};
+
+use crate::curve::{Curve, apply_price};
+use crate::state::PairSide;
+
+// Bisection steps when searching for the clearing price, enough to pin it to the last decimal
+const CLEARING_ITERATIONS: u32 = 128;
+
+// `amount` of the `side` token sold into the batch
+pub struct BatchOrder {
+    pub side: PairSide,
+    pub amount: Uint128,
+}
+
+pub struct Clearing {
+    pub outputs: Vec<Uint128>, // Paid out to each order in the other token
+    pub net_side: PairSide,    // Side whose excess is swapped against the curve
+    pub net_in: Uint128,
+    pub net_out: Uint128,
+    pub price: Decimal256,     // Other token per unit of the net side, the same for every order
+}
+
+// Nets the orders against each other and swaps only the excess of one side along the curve. The
+// price is the highest one at which that excess still pays for itself, which is where the curve's
+// average price equals the price every order gets. Outputs are pro rata and rounded down, so the
+// pool keeps the dust.
+pub fn clear_batch(
+    storage: &dyn Storage,
+    curve: &dyn Curve,
+    reserve_erth: Uint128,
+    reserve_b: Uint128,
+    orders: &[BatchOrder],
+) -> StdResult<Clearing> {
+    let total = |side: PairSide| -> Uint128 {
+        orders.iter().filter(|order| order.side == side).map(|order| order.amount).sum()
+    };
+    let (erth_in, b_in) = (total(PairSide::TokenErth), total(PairSide::TokenB));
+    let has_liquidity = !reserve_erth.is_zero() && !reserve_b.is_zero();
+
+    // The side worth more at the spot price is the one left over after netting
+    let erth_excess = if erth_in.is_zero() || b_in.is_zero() || !has_liquidity {
+        !erth_in.is_zero()
+    } else {
+        let price = curve.spot_price(PairSide::TokenErth, reserve_erth, reserve_b)?;
+        apply_price(erth_in, price)? >= b_in
+    };
+    let (net_side, x_in, y_in, reserve_x, reserve_y) = if erth_excess {
+        (PairSide::TokenErth, erth_in, b_in, reserve_erth, reserve_b)
+    } else {
+        (PairSide::TokenB, b_in, erth_in, reserve_b, reserve_erth)
+    };
+
+    if x_in.is_zero() {
+        return Ok(Clearing {
+            outputs: vec![Uint128::zero(); orders.len()],
+            net_side,
+            net_in: Uint128::zero(),
+            net_out: Uint128::zero(),
+            price: Decimal256::zero(),
+        });
+    }
+
+    // The excess sold to the curve at `price` and what it pays out, if that covers the other side
+    let net_trade = |price: Decimal256| -> Option<(Uint128, Uint128)> {
+        let matched = if y_in.is_zero() {
+            Uint256::zero()
+        } else {
+            let one = Decimal256::one().atomics();
+            (Uint256::from(y_in) * one + price.atomics() - Uint256::one()) / price.atomics()
+        };
+        let net_in = Uint128::try_from(Uint256::from(x_in).saturating_sub(matched)).ok()?;
+        let net_out = if net_in.is_zero() {
+            Uint128::zero()
+        } else {
+            curve.output_amount(storage, net_side, net_in, reserve_x, reserve_y).ok()?
+        };
+
+        (net_out <= reserve_y && y_in + net_out >= apply_price(x_in, price).ok()?).then_some((net_in, net_out))
+    };
+
+    // Peer to peer at the ratio of the two sides always clears, the curve's spot price is the ceiling
+    let mut low = Decimal256::from_ratio(y_in, x_in);
+    let mut best = net_trade(low).unwrap_or_default();
+    let mut high = if has_liquidity {
+        curve.spot_price(net_side, reserve_x, reserve_y)?.max(low)
+    } else {
+        low
+    };
+
+    for _ in 0..CLEARING_ITERATIONS {
+        if high.atomics() - low.atomics() <= Uint256::one() {
+            break;
+        }
+
+        let mid = Decimal256::new((low.atomics() + high.atomics()) / Uint256::from(2u8));
+        match net_trade(mid) {
+            Some(trade) => {
+                low = mid;
+                best = trade;
+            }
+            None => high = mid,
+        }
+    }
+
+    let (net_in, net_out) = best;
+    let x_available = x_in - net_in;
+    let y_available = y_in + net_out;
+
+    // A one sided batch the curve can't pay anything for, e.g. with no liquidity to sell into
+    if y_available.is_zero() {
+        return Err(StdError::generic_err("The batch can't be cleared"));
+    }
+
+    let outputs = orders
+        .iter()
+        .map(|order| {
+            if order.side == net_side {
+                y_available.multiply_ratio(order.amount, x_in)
+            } else {
+                x_available.multiply_ratio(order.amount, y_in)
+            }
+        })
+        .collect();
+
+    Ok(Clearing {
+        outputs,
+        net_side,
+        net_in,
+        net_out,
+        price: Decimal256::from_ratio(y_available, x_in),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::ConstantProduct;
+    use cosmwasm_std::testing::MockStorage;
+
+    fn order(side: PairSide, amount: u128) -> BatchOrder {
+        BatchOrder { side, amount: Uint128::new(amount) }
+    }
+
+    #[test]
+    fn opposing_orders_net_before_touching_the_curve() {
+        let reserve = Uint128::new(1_000_000);
+        let orders = [
+            order(PairSide::TokenErth, 30_000),
+            order(PairSide::TokenB, 20_000),
+            order(PairSide::TokenErth, 10_000),
+        ];
+
+        let clearing = clear_batch(&MockStorage::new(), &ConstantProduct, reserve, reserve, &orders).unwrap();
+
+        // Only the 20k of ERTH nobody on the other side wanted reaches the curve
+        assert_eq!(clearing.net_side, PairSide::TokenErth);
+        assert!(clearing.net_in > Uint128::new(19_500) && clearing.net_in < Uint128::new(20_000));
+        assert_eq!(
+            clearing.net_out,
+            ConstantProduct.output_amount(&MockStorage::new(), PairSide::TokenErth, clearing.net_in, reserve, reserve).unwrap()
+        );
+
+        // The B seller gets the ERTH sellers' price to within rounding, and nothing is paid out of thin air
+        let b_seller_paid = apply_price(clearing.outputs[1], clearing.price).unwrap();
+        assert!(b_seller_paid.u128().abs_diff(20_000) <= 2);
+        assert!(clearing.outputs[0] + clearing.outputs[2] <= Uint128::new(20_000) + clearing.net_out);
+        assert!(clearing.outputs[1] <= Uint128::new(40_000) - clearing.net_in);
+
+        // Far better than the ~38.5k the ERTH sellers would get swapping 40k one after another
+        let sequential = ConstantProduct
+            .output_amount(&MockStorage::new(), PairSide::TokenErth, Uint128::new(40_000), reserve, reserve)
+            .unwrap();
+        assert!(clearing.outputs[0] + clearing.outputs[2] > sequential);
+    }
+
+    #[test]
+    fn one_sided_batch_clears_like_a_single_swap() {
+        let (reserve_erth, reserve_b) = (Uint128::new(1_000_000), Uint128::new(2_000_000));
+        let orders = [order(PairSide::TokenB, 60_000), order(PairSide::TokenB, 40_000)];
+
+        let clearing = clear_batch(&MockStorage::new(), &ConstantProduct, reserve_erth, reserve_b, &orders).unwrap();
+
+        let single = ConstantProduct
+            .output_amount(&MockStorage::new(), PairSide::TokenB, Uint128::new(100_000), reserve_b, reserve_erth)
+            .unwrap();
+        assert_eq!(clearing.net_in, Uint128::new(100_000));
+        assert_eq!(clearing.net_out, single);
+        assert_eq!(clearing.outputs, vec![single.multiply_ratio(3u8, 5u8), single.multiply_ratio(2u8, 5u8)]);
+
+        // Without liquidity on the other side there is nothing to clear into
+        let result = clear_batch(&MockStorage::new(), &ConstantProduct, Uint128::zero(), reserve_b, &orders);
+        assert_eq!(result.err(), Some(StdError::generic_err("The batch can't be cleared")));
+    }
+}
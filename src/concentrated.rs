@@ -0,0 +1,738 @@
+use std::collections::BTreeMap;
+
+use cosmwasm_std::{Decimal256, StdError, StdResult, Storage, Uint128, Uint256, Uint512};
+
+use crate::curve::Curve;
+use crate::state::{ConcentratedPool, PairSide, Position, TickInfo, CONCENTRATED_POOL, TICKS, TICK_BITMAP};
+
+// Prices between 1.0001^-400000 and 1.0001^400000, roughly 4e-18 to 2e17 token B per ERTH
+pub const MAX_TICK: i32 = 400_000;
+pub const MIN_TICK: i32 = -MAX_TICK;
+
+// sqrt(1.0001), the ratio between the sqrt prices of neighbouring ticks
+const SQRT_TICK_RATIO: Decimal256 = Decimal256::raw(1_000_049_998_750_062_496);
+
+// Sqrt prices are handled as `Decimal256` atomics, fee growth as fees per liquidity times 2^128
+const DECIMAL_SCALE: u128 = 1_000_000_000_000_000_000;
+const FEE_GROWTH_SHIFT: u32 = 128;
+
+// Initialized ticks per bitmap word, counted in multiples of the tick spacing
+const WORD_BITS: i32 = 128;
+
+// Initialized ticks of the pool, read from `TICKS` as they are reached. Changes are held here
+// until `save`, so a simulated swap never writes.
+#[derive(Clone, Debug, Default)]
+pub struct Ticks {
+    tick_spacing: i32,
+    changed: BTreeMap<i32, Option<TickInfo>>, // `None` once a tick is no longer initialized
+    words: BTreeMap<i32, u128>,
+}
+
+impl Ticks {
+    pub fn new(tick_spacing: u32) -> Self {
+        Ticks {
+            tick_spacing: tick_spacing as i32,
+            ..Ticks::default()
+        }
+    }
+
+    pub fn get(&self, storage: &dyn Storage, tick: i32) -> StdResult<Option<TickInfo>> {
+        match self.changed.get(&tick) {
+            Some(info) => Ok(info.clone()),
+            None => TICKS.add_suffix(&tick.to_be_bytes()).may_load(storage),
+        }
+    }
+
+    fn set(&mut self, storage: &dyn Storage, tick: i32, info: TickInfo) -> StdResult<()> {
+        if self.get(storage, tick)?.is_none() {
+            self.flip(storage, tick)?;
+        }
+        self.changed.insert(tick, Some(info));
+        Ok(())
+    }
+
+    fn remove(&mut self, storage: &dyn Storage, tick: i32) -> StdResult<()> {
+        if self.get(storage, tick)?.is_some() {
+            self.flip(storage, tick)?;
+        }
+        self.changed.insert(tick, None);
+        Ok(())
+    }
+
+    fn word(&self, storage: &dyn Storage, index: i32) -> StdResult<u128> {
+        match self.words.get(&index) {
+            Some(word) => Ok(*word),
+            None => Ok(TICK_BITMAP.add_suffix(&index.to_be_bytes()).may_load(storage)?.unwrap_or_default()),
+        }
+    }
+
+    fn flip(&mut self, storage: &dyn Storage, tick: i32) -> StdResult<()> {
+        let compressed = tick.div_euclid(self.tick_spacing);
+        let index = compressed.div_euclid(WORD_BITS);
+        let word = self.word(storage, index)? ^ (1u128 << compressed.rem_euclid(WORD_BITS));
+        self.words.insert(index, word);
+        Ok(())
+    }
+
+    // The next initialized tick at or below `tick` going down, or above it going up, looking no
+    // further than the bitmap word it falls in. Returns the word's last tick, uninitialized, when
+    // there is none, so each step of a swap reads a single word.
+    fn next_within_word(&self, storage: &dyn Storage, tick: i32, downwards: bool) -> StdResult<(i32, bool)> {
+        let compressed = tick.div_euclid(self.tick_spacing) + if downwards { 0 } else { 1 };
+        let (index, bit) = (compressed.div_euclid(WORD_BITS), compressed.rem_euclid(WORD_BITS) as u32);
+        let word = self.word(storage, index)?;
+        let base = index * WORD_BITS;
+
+        let (next, initialized) = if downwards {
+            let masked = word & (u128::MAX >> (WORD_BITS as u32 - 1 - bit));
+            match masked {
+                0 => (base, false),
+                _ => (base + (WORD_BITS - 1 - masked.leading_zeros() as i32), true),
+            }
+        } else {
+            let masked = word & (u128::MAX << bit);
+            match masked {
+                0 => (base + WORD_BITS - 1, false),
+                _ => (base + masked.trailing_zeros() as i32, true),
+            }
+        };
+
+        Ok((next * self.tick_spacing, initialized))
+    }
+
+    pub fn save(&self, storage: &mut dyn Storage) -> StdResult<()> {
+        for (tick, info) in &self.changed {
+            let item = TICKS.add_suffix(&tick.to_be_bytes());
+            match info {
+                Some(info) => item.save(storage, info)?,
+                None => item.remove(storage),
+            }
+        }
+        for (index, word) in &self.words {
+            TICK_BITMAP.add_suffix(&index.to_be_bytes()).save(storage, word)?;
+        }
+        Ok(())
+    }
+}
+
+// Swaps against the positions in range. The pool's reserves are ignored, price and liquidity come
+// from `CONCENTRATED_POOL` and the ticks from `TICKS`.
+pub struct Concentrated {
+    pub pool: ConcentratedPool,
+    pub ticks: Ticks,
+    pub lp_fee: Uint128,
+}
+
+impl Curve for Concentrated {
+    fn output_amount(
+        &self,
+        storage: &dyn Storage,
+        input: PairSide,
+        amount_in: Uint128,
+        _reserve_in: Uint128,
+        _reserve_out: Uint128,
+    ) -> StdResult<Uint128> {
+        swap(storage, &mut self.pool.clone(), &mut self.ticks.clone(), input, amount_in, self.lp_fee)
+    }
+
+    fn spot_price(
+        &self,
+        input: PairSide,
+        _reserve_in: Uint128,
+        _reserve_out: Uint128,
+    ) -> StdResult<Decimal256> {
+        let price = self.pool.sqrt_price.checked_mul(self.pool.sqrt_price)?;
+        match input {
+            PairSide::TokenErth => Ok(price),
+            PairSide::TokenB => Decimal256::one()
+                .checked_div(price)
+                .map_err(|err| StdError::generic_err(err.to_string())),
+        }
+    }
+
+    fn apply_swap(
+        &mut self,
+        storage: &dyn Storage,
+        input: PairSide,
+        amount_in: Uint128,
+        _reserve_in: Uint128,
+        _reserve_out: Uint128,
+    ) -> StdResult<Uint128> {
+        swap(storage, &mut self.pool, &mut self.ticks, input, amount_in, self.lp_fee)
+    }
+
+    fn apply_feeless_swap(
+        &mut self,
+        storage: &dyn Storage,
+        input: PairSide,
+        amount_in: Uint128,
+        _reserve_in: Uint128,
+        _reserve_out: Uint128,
+    ) -> StdResult<Uint128> {
+        swap(storage, &mut self.pool, &mut self.ticks, input, amount_in, Uint128::zero())
+    }
+
+    fn save(&self, storage: &mut dyn Storage) -> StdResult<()> {
+        self.ticks.save(storage)?;
+        CONCENTRATED_POOL.save(storage, &self.pool)
+    }
+}
+
+// An empty pool starting at `initial_price` token B per ERTH
+pub fn new_pool(initial_price: Decimal256) -> StdResult<ConcentratedPool> {
+    let sqrt_price = initial_price.sqrt();
+
+    Ok(ConcentratedPool {
+        sqrt_price,
+        tick: tick_at_sqrt_price(sqrt_price)?,
+        liquidity: Uint128::zero(),
+        fee_growth_global_erth: Uint256::zero(),
+        fee_growth_global_b: Uint256::zero(),
+        next_position_id: 1,
+    })
+}
+
+pub fn sqrt_price_at_tick(tick: i32) -> StdResult<Decimal256> {
+    if !(MIN_TICK..=MAX_TICK).contains(&tick) {
+        return Err(StdError::generic_err("Tick is outside the supported range"));
+    }
+
+    let sqrt_price = SQRT_TICK_RATIO.checked_pow(tick.unsigned_abs())?;
+    if tick >= 0 {
+        Ok(sqrt_price)
+    } else {
+        Decimal256::one()
+            .checked_div(sqrt_price)
+            .map_err(|err| StdError::generic_err(err.to_string()))
+    }
+}
+
+// Highest tick whose price is at or below `sqrt_price`
+pub fn tick_at_sqrt_price(sqrt_price: Decimal256) -> StdResult<i32> {
+    if sqrt_price < sqrt_price_at_tick(MIN_TICK)? || sqrt_price > sqrt_price_at_tick(MAX_TICK)? {
+        return Err(StdError::generic_err("Price is outside the supported tick range"));
+    }
+
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if sqrt_price_at_tick(mid)? <= sqrt_price {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    Ok(low)
+}
+
+pub fn validate_range(tick_lower: i32, tick_upper: i32, tick_spacing: u32) -> StdResult<()> {
+    if tick_lower >= tick_upper {
+        return Err(StdError::generic_err("Lower tick must be below the upper tick"));
+    }
+    if tick_lower < MIN_TICK || tick_upper > MAX_TICK {
+        return Err(StdError::generic_err("Tick is outside the supported range"));
+    }
+
+    let spacing = tick_spacing as i32;
+    if tick_lower % spacing != 0 || tick_upper % spacing != 0 {
+        return Err(StdError::generic_err("Ticks must be multiples of the tick spacing"));
+    }
+
+    Ok(())
+}
+
+// Hands out the next position id, the position holds no liquidity until `update_position`
+pub fn open_position(pool: &mut ConcentratedPool, tick_lower: i32, tick_upper: i32) -> Position {
+    let id = pool.next_position_id;
+    pool.next_position_id += 1;
+
+    Position {
+        id,
+        tick_lower,
+        tick_upper,
+        liquidity: Uint128::zero(),
+        fee_growth_inside_erth_last: Uint256::zero(),
+        fee_growth_inside_b_last: Uint256::zero(),
+        tokens_owed_erth: Uint128::zero(),
+        tokens_owed_b: Uint128::zero(),
+    }
+}
+
+// Most liquidity the amounts can back over the range at the current price
+pub fn liquidity_for_amounts(
+    pool: &ConcentratedPool,
+    tick_lower: i32,
+    tick_upper: i32,
+    amount_erth: Uint128,
+    amount_b: Uint128,
+) -> StdResult<Uint128> {
+    let lower = sqrt_price_at_tick(tick_lower)?.atomics();
+    let upper = sqrt_price_at_tick(tick_upper)?.atomics();
+    let current = pool.sqrt_price.atomics();
+    let scale = Uint256::from(DECIMAL_SCALE);
+
+    // x * a * b / (b - a) from ERTH above `from`, y / (b - a) from token B below `to`
+    let from_erth = |from: Uint256| {
+        mul_div(Uint256::from(amount_erth) * from, upper, scale * (upper - from), false)
+    };
+    let from_b = |to: Uint256| mul_div(Uint256::from(amount_b), scale, to - lower, false);
+
+    let liquidity = if current <= lower {
+        from_erth(lower)?
+    } else if current >= upper {
+        from_b(upper)?
+    } else {
+        from_erth(current)?.min(from_b(current)?)
+    };
+
+    to_uint128(liquidity)
+}
+
+// Tokens backing `liquidity` over the range at the current price, rounded up when depositing
+pub fn amounts_for_liquidity(
+    pool: &ConcentratedPool,
+    tick_lower: i32,
+    tick_upper: i32,
+    liquidity: Uint128,
+    round_up: bool,
+) -> StdResult<(Uint128, Uint128)> {
+    let lower = sqrt_price_at_tick(tick_lower)?.atomics();
+    let upper = sqrt_price_at_tick(tick_upper)?.atomics();
+    let current = pool.sqrt_price.atomics().clamp(lower, upper);
+
+    let amount_erth = if current < upper {
+        erth_delta(current, upper, liquidity, round_up)?
+    } else {
+        Uint256::zero()
+    };
+    let amount_b = if current > lower {
+        b_delta(lower, current, liquidity, round_up)?
+    } else {
+        Uint256::zero()
+    };
+
+    Ok((to_uint128(amount_erth)?, to_uint128(amount_b)?))
+}
+
+// Adds `liquidity_delta` to the position, first crediting the fees it earned since its last update
+pub fn update_position(
+    storage: &dyn Storage,
+    pool: &mut ConcentratedPool,
+    ticks: &mut Ticks,
+    position: &mut Position,
+    liquidity_delta: i128,
+) -> StdResult<()> {
+    if liquidity_delta != 0 {
+        update_tick(storage, pool, ticks, position.tick_lower, liquidity_delta, false)?;
+        update_tick(storage, pool, ticks, position.tick_upper, liquidity_delta, true)?;
+    }
+
+    let (inside_erth, inside_b) = fee_growth_inside(storage, pool, ticks, position.tick_lower, position.tick_upper)?;
+    position.tokens_owed_erth += fees_earned(
+        inside_erth.wrapping_sub(position.fee_growth_inside_erth_last),
+        position.liquidity,
+    )?;
+    position.tokens_owed_b += fees_earned(
+        inside_b.wrapping_sub(position.fee_growth_inside_b_last),
+        position.liquidity,
+    )?;
+    position.fee_growth_inside_erth_last = inside_erth;
+    position.fee_growth_inside_b_last = inside_b;
+
+    position.liquidity = add_delta(position.liquidity, liquidity_delta)?;
+    if pool.tick >= position.tick_lower && pool.tick < position.tick_upper {
+        pool.liquidity = add_delta(pool.liquidity, liquidity_delta)?;
+    }
+
+    // Ticks no position refers to anymore don't need to be crossed
+    for tick in [position.tick_lower, position.tick_upper] {
+        if ticks.get(storage, tick)?.is_some_and(|info| info.liquidity_gross.is_zero()) {
+            ticks.remove(storage, tick)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Sells `amount_in` of the input token along the initialized ticks and returns the output
+pub fn swap(
+    storage: &dyn Storage,
+    pool: &mut ConcentratedPool,
+    ticks: &mut Ticks,
+    input: PairSide,
+    amount_in: Uint128,
+    lp_fee: Uint128,
+) -> StdResult<Uint128> {
+    // Selling ERTH lowers its price in token B
+    let downwards = input == PairSide::TokenErth;
+    let fee = amount_in * lp_fee / Uint128::from(10000u128);
+    let net_in = Uint256::from(amount_in - fee);
+    let scale = Uint256::from(DECIMAL_SCALE);
+
+    let mut remaining = net_in;
+    let mut fee_left = Uint256::from(fee);
+    let mut output = Uint256::zero();
+
+    while !remaining.is_zero() {
+        if (downwards && pool.tick < MIN_TICK) || (!downwards && pool.tick >= MAX_TICK) {
+            return Err(StdError::generic_err("Not enough liquidity in range for this swap"));
+        }
+
+        let (next_tick, initialized) = ticks.next_within_word(storage, pool.tick, downwards)?;
+        let next_tick = next_tick.clamp(MIN_TICK, MAX_TICK);
+
+        let target = sqrt_price_at_tick(next_tick)?;
+        let (current, target_atomics) = (pool.sqrt_price.atomics(), target.atomics());
+        let liquidity = pool.liquidity;
+
+        // Nothing to trade against before the next tick
+        if liquidity.is_zero() {
+            pool.sqrt_price = target;
+            step_past(storage, pool, ticks, next_tick, initialized, downwards)?;
+            continue;
+        }
+
+        let needed = if downwards {
+            erth_delta(target_atomics, current, liquidity, true)?
+        } else {
+            b_delta(current, target_atomics, liquidity, true)?
+        };
+
+        let reaches_target = remaining >= needed;
+        let used = if reaches_target {
+            output += if downwards {
+                b_delta(target_atomics, current, liquidity, false)?
+            } else {
+                erth_delta(current, target_atomics, liquidity, false)?
+            };
+            pool.sqrt_price = target;
+            needed
+        } else {
+            let l = Uint256::from(liquidity);
+            // Rounded so the price moves at least as far as the input pays for
+            let next_sqrt_price = if downwards {
+                mul_div(l * current, scale, l * scale + remaining * current, true)?
+            } else {
+                current + mul_div(remaining, scale, l, false)?
+            };
+
+            output += if downwards {
+                b_delta(next_sqrt_price, current, liquidity, false)?
+            } else {
+                erth_delta(current, next_sqrt_price, liquidity, false)?
+            };
+            pool.sqrt_price = Decimal256::new(next_sqrt_price);
+            pool.tick = tick_at_sqrt_price(pool.sqrt_price)?;
+            remaining
+        };
+        remaining -= used;
+
+        // The in-range liquidity earns the fee paid on the part of the input it absorbed
+        let step_fee = if remaining.is_zero() {
+            fee_left
+        } else {
+            mul_div(Uint256::from(fee), used, net_in, false)?
+        };
+        fee_left -= step_fee;
+
+        let growth = (step_fee << FEE_GROWTH_SHIFT) / Uint256::from(liquidity);
+        if downwards {
+            pool.fee_growth_global_erth = pool.fee_growth_global_erth.wrapping_add(growth);
+        } else {
+            pool.fee_growth_global_b = pool.fee_growth_global_b.wrapping_add(growth);
+        }
+
+        // Only after the fees, which belong to the liquidity on this side of the tick
+        if reaches_target {
+            step_past(storage, pool, ticks, next_tick, initialized, downwards)?;
+        }
+    }
+
+    to_uint128(output)
+}
+
+fn update_tick(
+    storage: &dyn Storage,
+    pool: &ConcentratedPool,
+    ticks: &mut Ticks,
+    tick: i32,
+    liquidity_delta: i128,
+    upper: bool,
+) -> StdResult<()> {
+    // By convention all fees so far were earned below a newly initialized tick at or below the price
+    let mut info = match ticks.get(storage, tick)? {
+        Some(info) => info,
+        None if tick <= pool.tick => TickInfo {
+            fee_growth_outside_erth: pool.fee_growth_global_erth,
+            fee_growth_outside_b: pool.fee_growth_global_b,
+            ..TickInfo::default()
+        },
+        None => TickInfo::default(),
+    };
+
+    info.liquidity_gross = add_delta(info.liquidity_gross, liquidity_delta)?;
+    info.liquidity_net = if upper {
+        info.liquidity_net.checked_sub(liquidity_delta)
+    } else {
+        info.liquidity_net.checked_add(liquidity_delta)
+    }
+    .ok_or_else(|| StdError::generic_err("Tick liquidity overflow"))?;
+
+    ticks.set(storage, tick, info)
+}
+
+// Moves past a tick the caller has already set the price to, crossing it when it is initialized
+fn step_past(
+    storage: &dyn Storage,
+    pool: &mut ConcentratedPool,
+    ticks: &mut Ticks,
+    tick: i32,
+    initialized: bool,
+    downwards: bool,
+) -> StdResult<()> {
+    if initialized {
+        return cross(storage, pool, ticks, tick, downwards);
+    }
+
+    pool.tick = if downwards { tick - 1 } else { tick };
+    Ok(())
+}
+
+// Moves the price across an initialized tick, which the caller has already set the price to
+fn cross(
+    storage: &dyn Storage,
+    pool: &mut ConcentratedPool,
+    ticks: &mut Ticks,
+    tick: i32,
+    downwards: bool,
+) -> StdResult<()> {
+    let mut info = ticks
+        .get(storage, tick)?
+        .ok_or_else(|| StdError::generic_err("Crossed an uninitialized tick"))?;
+
+    info.fee_growth_outside_erth = pool.fee_growth_global_erth.wrapping_sub(info.fee_growth_outside_erth);
+    info.fee_growth_outside_b = pool.fee_growth_global_b.wrapping_sub(info.fee_growth_outside_b);
+
+    if downwards {
+        let net = info
+            .liquidity_net
+            .checked_neg()
+            .ok_or_else(|| StdError::generic_err("Tick liquidity overflow"))?;
+        pool.liquidity = add_delta(pool.liquidity, net)?;
+        pool.tick = tick - 1;
+    } else {
+        pool.liquidity = add_delta(pool.liquidity, info.liquidity_net)?;
+        pool.tick = tick;
+    }
+
+    ticks.set(storage, tick, info)
+}
+
+pub fn fee_growth_inside(
+    storage: &dyn Storage,
+    pool: &ConcentratedPool,
+    ticks: &Ticks,
+    tick_lower: i32,
+    tick_upper: i32,
+) -> StdResult<(Uint256, Uint256)> {
+    let lower = ticks.get(storage, tick_lower)?.unwrap_or_default();
+    let upper = ticks.get(storage, tick_upper)?.unwrap_or_default();
+
+    let inside = |global: Uint256, lower_outside: Uint256, upper_outside: Uint256| {
+        let below = if pool.tick >= tick_lower { lower_outside } else { global.wrapping_sub(lower_outside) };
+        let above = if pool.tick < tick_upper { upper_outside } else { global.wrapping_sub(upper_outside) };
+        global.wrapping_sub(below).wrapping_sub(above)
+    };
+
+    Ok((
+        inside(pool.fee_growth_global_erth, lower.fee_growth_outside_erth, upper.fee_growth_outside_erth),
+        inside(pool.fee_growth_global_b, lower.fee_growth_outside_b, upper.fee_growth_outside_b),
+    ))
+}
+
+fn fees_earned(growth: Uint256, liquidity: Uint128) -> StdResult<Uint128> {
+    let fees = Uint256::try_from(growth.full_mul(liquidity) >> FEE_GROWTH_SHIFT)
+        .map_err(|_| StdError::generic_err("Fee overflow"))?;
+    to_uint128(fees)
+}
+
+// ERTH spanning sqrt prices `lower` to `upper`: L * (b - a) / (a * b)
+fn erth_delta(lower: Uint256, upper: Uint256, liquidity: Uint128, round_up: bool) -> StdResult<Uint256> {
+    mul_div(
+        Uint256::from(liquidity) * (upper - lower),
+        Uint256::from(DECIMAL_SCALE),
+        lower * upper,
+        round_up,
+    )
+}
+
+// Token B spanning sqrt prices `lower` to `upper`: L * (b - a)
+fn b_delta(lower: Uint256, upper: Uint256, liquidity: Uint128, round_up: bool) -> StdResult<Uint256> {
+    mul_div(Uint256::from(liquidity), upper - lower, Uint256::from(DECIMAL_SCALE), round_up)
+}
+
+fn mul_div(a: Uint256, b: Uint256, denominator: Uint256, round_up: bool) -> StdResult<Uint256> {
+    let product = a.full_mul(b);
+    let denominator = Uint512::from(denominator);
+
+    let mut result = product.checked_div(denominator)?;
+    if round_up && !product.checked_rem(denominator)?.is_zero() {
+        result += Uint512::one();
+    }
+
+    Uint256::try_from(result).map_err(|_| StdError::generic_err("Concentrated liquidity math overflow"))
+}
+
+fn add_delta(value: Uint128, delta: i128) -> StdResult<Uint128> {
+    let magnitude = Uint128::new(delta.unsigned_abs());
+    Ok(if delta >= 0 {
+        value.checked_add(magnitude)?
+    } else {
+        value.checked_sub(magnitude)?
+    })
+}
+
+fn to_uint128(value: Uint256) -> StdResult<Uint128> {
+    Uint128::try_from(value).map_err(|_| StdError::generic_err("Amount exceeds Uint128"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::MockStorage;
+
+    fn deposit(
+        storage: &dyn Storage,
+        pool: &mut ConcentratedPool,
+        ticks: &mut Ticks,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity: u128,
+    ) -> Position {
+        let mut position = open_position(pool, tick_lower, tick_upper);
+        update_position(storage, pool, ticks, &mut position, liquidity as i128).unwrap();
+        position
+    }
+
+    fn stored(storage: &dyn Storage, tick: i32) -> bool {
+        TICKS.add_suffix(&tick.to_be_bytes()).may_load(storage).unwrap().is_some()
+    }
+
+    #[test]
+    fn ticks_round_trip_through_prices() {
+        assert_eq!(sqrt_price_at_tick(0).unwrap(), Decimal256::one());
+
+        for tick in [-MAX_TICK, -123_457, -1, 0, 1, 60, 99_999, MAX_TICK] {
+            let sqrt_price = sqrt_price_at_tick(tick).unwrap();
+            assert_eq!(tick_at_sqrt_price(sqrt_price).unwrap(), tick);
+        }
+
+        let just_below = sqrt_price_at_tick(60).unwrap() - Decimal256::raw(1);
+        assert_eq!(tick_at_sqrt_price(just_below).unwrap(), 59);
+    }
+
+    #[test]
+    fn swaps_within_a_range_pay_lp_fees() {
+        let storage = MockStorage::new();
+        let mut ticks = Ticks::new(60);
+        let mut pool = new_pool(Decimal256::one()).unwrap();
+        let mut position = deposit(&storage, &mut pool, &mut ticks, -600, 600, 1_000_000_000);
+
+        // Deposits at price 1 need about the same amount of each token
+        let (erth, b) = amounts_for_liquidity(&pool, -600, 600, position.liquidity, true).unwrap();
+        assert_eq!(erth, b);
+
+        // Small trades fill close to 1:1 less the 0.3% fee
+        let output = swap(&storage, &mut pool, &mut ticks, PairSide::TokenErth, Uint128::new(10_000), Uint128::new(30)).unwrap();
+        assert!(output > Uint128::new(9_960) && output < Uint128::new(9_970), "{}", output);
+        assert!(pool.sqrt_price < Decimal256::one());
+        assert_eq!(pool.tick, -1);
+
+        update_position(&storage, &mut pool, &mut ticks, &mut position, 0).unwrap();
+        assert!(position.tokens_owed_erth >= Uint128::new(29) && position.tokens_owed_erth <= Uint128::new(30));
+        assert!(position.tokens_owed_b.is_zero());
+
+        // Going past the last initialized tick runs out of liquidity
+        let err = swap(&storage, &mut pool, &mut ticks, PairSide::TokenB, Uint128::new(1_000_000_000), Uint128::zero()).unwrap_err();
+        assert!(err.to_string().contains("Not enough liquidity"), "{}", err);
+    }
+
+    #[test]
+    fn feeless_swaps_skip_the_lp_fee() {
+        let storage = MockStorage::new();
+        let mut ticks = Ticks::new(60);
+        let mut pool = new_pool(Decimal256::one()).unwrap();
+        let mut position = deposit(&storage, &mut pool, &mut ticks, -600, 600, 1_000_000_000);
+        let mut curve = Concentrated { pool, ticks, lp_fee: Uint128::new(30) };
+
+        let amount = Uint128::new(10_000);
+        let with_fee = curve.output_amount(&storage, PairSide::TokenErth, amount, Uint128::zero(), Uint128::zero()).unwrap();
+        let feeless = curve.apply_feeless_swap(&storage, PairSide::TokenErth, amount, Uint128::zero(), Uint128::zero()).unwrap();
+        assert!(feeless > with_fee && feeless > Uint128::new(9_990), "{} {}", feeless, with_fee);
+
+        // Nothing accrued to the range
+        update_position(&storage, &mut curve.pool, &mut curve.ticks, &mut position, 0).unwrap();
+        assert!(position.tokens_owed_erth.is_zero() && position.tokens_owed_b.is_zero());
+    }
+
+    #[test]
+    fn swaps_cross_into_neighbouring_ranges() {
+        let mut storage = MockStorage::new();
+        let mut ticks = Ticks::new(60);
+        let mut pool = new_pool(Decimal256::one()).unwrap();
+        let mut near = deposit(&storage, &mut pool, &mut ticks, -60, 60, 1_000_000_000);
+        let mut below = deposit(&storage, &mut pool, &mut ticks, -600, -60, 5_000_000_000);
+        assert_eq!(pool.liquidity, Uint128::new(1_000_000_000));
+
+        // Only the position below the price is single sided, in ERTH's counterpart
+        let (erth, b) = amounts_for_liquidity(&pool, -600, -60, below.liquidity, true).unwrap();
+        assert!(erth.is_zero() && !b.is_zero());
+
+        let output = swap(&storage, &mut pool, &mut ticks, PairSide::TokenErth, Uint128::new(10_000_000), Uint128::new(30)).unwrap();
+        assert!(pool.tick < -60 && pool.tick >= -600, "{}", pool.tick);
+        assert_eq!(pool.liquidity, Uint128::new(5_000_000_000));
+        assert!(output < Uint128::new(10_000_000));
+
+        // Both ranges earned fees on the part of the swap they absorbed
+        update_position(&storage, &mut pool, &mut ticks, &mut near, 0).unwrap();
+        update_position(&storage, &mut pool, &mut ticks, &mut below, 0).unwrap();
+        let total = near.tokens_owed_erth + below.tokens_owed_erth;
+        assert!(!near.tokens_owed_erth.is_zero() && !below.tokens_owed_erth.is_zero());
+        assert!(total <= Uint128::new(30_000) && total >= Uint128::new(29_990), "{}", total);
+
+        // Crossing back restores the first range's liquidity
+        swap(&storage, &mut pool, &mut ticks, PairSide::TokenB, Uint128::new(10_000_000), Uint128::new(30)).unwrap();
+        assert!(pool.tick >= -60 && pool.tick < 60, "{}", pool.tick);
+        assert_eq!(pool.liquidity, Uint128::new(1_000_000_000));
+
+        // Withdrawing everything clears both ticks of the range
+        ticks.save(&mut storage).unwrap();
+        assert!(stored(&storage, -600));
+        update_position(&storage, &mut pool, &mut ticks, &mut below, -5_000_000_000).unwrap();
+        ticks.save(&mut storage).unwrap();
+        assert!(!stored(&storage, -600));
+        assert!(stored(&storage, -60));
+    }
+
+    #[test]
+    fn swaps_find_saved_ticks_across_empty_bitmap_words() {
+        let mut storage = MockStorage::new();
+        let mut ticks = Ticks::new(60);
+        let mut pool = new_pool(Decimal256::one()).unwrap();
+        deposit(&storage, &mut pool, &mut ticks, -60, 60, 1_000_000_000);
+        deposit(&storage, &mut pool, &mut ticks, -60_000, -30_000, 1_000_000_000_000);
+        ticks.save(&mut storage).unwrap();
+
+        // A fresh overlay only has what was saved, and the far range is several words below
+        let mut ticks = Ticks::new(60);
+        swap(&storage, &mut pool, &mut ticks, PairSide::TokenErth, Uint128::new(100_000_000), Uint128::new(30)).unwrap();
+        assert!(pool.tick < -30_000 && pool.tick >= -60_000, "{}", pool.tick);
+        assert_eq!(pool.liquidity, Uint128::new(1_000_000_000_000));
+
+        // Simulating against the same storage doesn't need the overlay's changes saved
+        let curve = Concentrated { pool: pool.clone(), ticks: Ticks::new(60), lp_fee: Uint128::new(30) };
+        let output = curve.output_amount(&storage, PairSide::TokenB, Uint128::new(1_000), Uint128::zero(), Uint128::zero()).unwrap();
+        assert!(!output.is_zero());
+    }
+}
@@ -1,21 +1,71 @@
+#[cfg(not(feature = "library"))]
+use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    entry_point, to_binary, from_binary, Binary, Deps, DepsMut, Env,
+    to_binary, from_binary, Binary, Deps, DepsMut, Env, Storage, Decimal256,
     MessageInfo, Response, StdError, StdResult, Addr, Uint128, CosmosMsg,
-    WasmMsg, SubMsg, Reply, SubMsgResponse,
+    WasmMsg, SubMsg, Reply, SubMsgResponse, SubMsgResult, QueryRequest, WasmQuery, Event,
+    BankMsg, Coin,
 };
 use secret_toolkit::snip20;
+use secret_toolkit::permit::{validate, Permit, RevokedPermits, TokenPermissions};
+use secret_toolkit::viewing_key::{ViewingKey, ViewingKeyStore};
+use secret_toolkit::utils::{pad_query_result, space_pad};
+use secret_toolkit::crypto::sha_256;
 use crate::msg::{
     ExecuteMsg, InstantiateMsg, QueryMsg, QueryStateResponse, QuerySwapResponse,
     ReceiveMsg, UnclaimedDepositResponse, MigrateMsg, HopDetails,
-    Snip20InstantiateMsg, InitConfig, SendMessage,
+    Snip20InstantiateMsg, InitConfig, SendMessage, LpTokenConfig, FeeDiscountResponse,
+    RegistrationQueryMsg, RegistrationStatusResponse, FactoryQueryMsg, FactoryPoolResponse, FeeConfigResponse, ExecuteAnswer,
+    QueryWithPermit, PoolInfoResponse, SimulateProvideResponse, SimulateWithdrawResponse,
+    VersionResponse, ConcentratedPoolResponse, PositionsResponse, LimitOrderInfo, LimitOrdersResponse,
+    DcaScheduleInfo, DcaSchedulesResponse, PendingBatchResponse, BatchClaimResponse,
+};
+use crate::batch::{BatchOrder, clear_batch};
+use crate::concentrated::{
+    new_pool, validate_range, open_position, liquidity_for_amounts, amounts_for_liquidity,
+    update_position, Ticks, MAX_TICK,
+};
+use crate::curve::{
+    Curve, load_curve, load_curve_config, current_amp, apply_price, MAX_AMP, MAX_AMP_CHANGE,
+    MIN_RAMP_TIME, MIN_WEIGHT_FRACTION,
+};
+use crate::history::{record_user_action, query_user_history};
+use crate::migrations::{migrate_state, MigrateParams, CURRENT_STATE_VERSION};
+use crate::oracle::{
+    update_price_oracle, query_twap, record_reserve_snapshot, query_reserve_snapshots, spot_prices,
+};
+use crate::state::{
+    STATE, State, DEPOSITS, FEE_DISCOUNTS, REGISTERED_USER_DISCOUNT, FEE_CONFIG, FeeConfig,
+    FeeDestination, FeeToken, ACCUMULATED_FEES, PROTOCOL_FEES_ACCRUED, FLUSH_CONFIG,
+    SNAPSHOT_CAPACITY, UserAction, CONSTANT_SHAPE_RESPONSES, CONTRACT_INFO, ContractInfo,
+    STATE_VERSION, PairSide, CURVE_CONFIG, CurveConfig, AmpRamp, CONCENTRATED_POOL,
+    ConcentratedPool, POSITIONS, LIMIT_ORDERS, ORDER_BOOK, NEXT_LIMIT_ORDER_ID, LimitOrder, DCA_SCHEDULES,
+    NEXT_DCA_ID, DcaSchedule, BATCH_WINDOW, PENDING_BATCH, PendingBatch, BatchIntent, BATCH_CLAIMS,
+    BATCH_MIN_INTENT, FORWARDERS, Forwarders,
 };
-use crate::state::{STATE, State, DEPOSITS};
 
 const INSTANTIATE_LP_TOKEN_REPLY_ID: u64 = 0;
+const MAX_POSITIONS_PAGE_SIZE: u32 = 100;
+const MAX_LIMIT_ORDERS_PAGE_SIZE: u32 = 100;
+// Bounds the extra work a swap does for resting orders and a keeper call can ask for
+const MAX_ORDERS_CHECKED_PER_SWAP: usize = 10;
+const MAX_EXECUTE_ORDERS: usize = 50;
+const MAX_DCA_PAGE_SIZE: u32 = 100;
+// Basis points of each DCA execution paid to whoever triggered it
+const DCA_KEEPER_TIP: u128 = 10;
+// Bounds the work of settling a batch, which clears every intent in it again per refunded one
+const MAX_BATCH_INTENTS: usize = 50;
+// Keeps a single address from filling the pending batch on its own
+const MAX_BATCH_INTENTS_PER_OWNER: usize = 5;
 const ERTH_DAO: &str = "secret1hxrvx0v0zvqgmpuzspdg5j8rrxpjgyjql3w9gh";
-const CONTRACT_VERSION: &str = "v0.0.22";
-
-#[entry_point]
+const CONTRACT_NAME: &str = "animal-swap";
+const CONTRACT_VERSION: &str = "v0.1.0";
+const MAX_FEE_DISCOUNT: u128 = 10000;
+const PREFIX_REVOKED_PERMITS: &str = "revoked_permits";
+// Responses and query results are padded to a multiple of this many bytes
+const BLOCK_SIZE: usize = 256;
+
+#[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
     env: Env,
@@ -25,38 +75,198 @@ pub fn instantiate(
     // Validate and convert strings to Addr
     let contract_manager = deps.api.addr_validate(&msg.contract_manager)?;
     let token_erth_contract = deps.api.addr_validate(&msg.token_erth_contract)?;
-    let token_b_contract = deps.api.addr_validate(&msg.token_b_contract)?;
+    // A native token B is identified by its denom wherever the contract compares tokens
+    let (token_b_contract, token_b_hash) = match &msg.token_b_denom {
+        Some(denom) => (Addr::unchecked(denom), String::new()),
+        None => (deps.api.addr_validate(&msg.token_b_contract)?, msg.token_b_hash.clone()),
+    };
+    let fee_side = msg.fee_side.unwrap_or(PairSide::TokenErth);
+
+    // Buybacks and flushes hand the fee side token over with a `Send`, which a bank transfer can't do
+    if msg.token_b_denom.is_some() && fee_side == PairSide::TokenB {
+        return Err(StdError::generic_err("The fee side can't be a native denom"));
+    }
     let registration_contract_addr = deps.api.addr_validate(&msg.registration_contract)?;
     let lp_staking_contract_addr = deps.api.addr_validate(&msg.lp_staking_contract)?;
 
+    seed_viewing_keys(deps.storage, &env, &msg.entropy);
+
+    let lp_token_config = msg.lp_token_config.clone().unwrap_or(LpTokenConfig {
+        name: None,
+        symbol: None,
+        decimals: None,
+        admin: None,
+        config: None,
+    });
+
+    // Concentrated pools track positions instead of minting LP shares
+    if msg.concentrated.is_some() && msg.lp_token_contract.is_some() {
+        return Err(StdError::generic_err("Concentrated pools don't take an LP token"));
+    }
+
+    // Adopt an existing LP token when one is given, otherwise a new one is instantiated below
+    let (lp_token_contract, lp_token_decimals) = match &msg.lp_token_contract {
+        Some(lp_token_contract) => {
+            let lp_token_contract = deps.api.addr_validate(lp_token_contract)?;
+
+            // Confirms the address is a SNIP-20 with the given hash and reads its decimals
+            let token_info = snip20::token_info_query(
+                deps.querier,
+                BLOCK_SIZE,
+                msg.lp_token_hash.clone(),
+                lp_token_contract.to_string(),
+            )?;
+
+            // The pool mints LP tokens on every deposit, so it has to be a minter already
+            let minters = snip20::minters_query(
+                deps.querier,
+                BLOCK_SIZE,
+                msg.lp_token_hash.clone(),
+                lp_token_contract.to_string(),
+            )?;
+            if !minters.minters.contains(&env.contract.address.to_string()) {
+                return Err(StdError::generic_err("This contract is not a minter of the LP token"));
+            }
+
+            (lp_token_contract, token_info.decimals)
+        }
+        // Placeholder until the reply, and for good on concentrated pools
+        None => (Addr::unchecked(""), lp_token_config.decimals.unwrap_or(msg.lp_token_decimals)),
+    };
+
+    // Initialize the state with a placeholder address for the LP token unless one was adopted
+    let state = State {
+        contract_manager: contract_manager.clone(),
+        token_erth_contract: token_erth_contract.clone(),
+        token_erth_hash: msg.token_erth_hash.clone(),
+        token_b_contract: token_b_contract.clone(),
+        token_b_hash,
+        token_b_symbol: msg.token_b_symbol.clone(),
+        registration_contract: registration_contract_addr.clone(),
+        registration_hash: msg.registration_hash.clone(),
+        lp_token_contract,
+        lp_token_hash: msg.lp_token_hash.clone(),
+        lp_token_code_id: msg.lp_token_code_id,
+        lp_staking_contract: lp_staking_contract_addr, // Placeholder
+        lp_staking_hash: msg.lp_staking_hash.clone(),
+        token_erth_reserve: Uint128::zero(),
+        token_b_reserve: Uint128::zero(),
+        total_shares: Uint128::zero(),
+        protocol_fee: msg.protocol_fee,
+        lp_token_decimals,
+        fee_side,
+        token_b_denom: msg.token_b_denom.clone(),
+    };
+
+    // Save the initial state
+    STATE.save(deps.storage, &state)?;
+    STATE_VERSION.save(deps.storage, &CURRENT_STATE_VERSION)?;
+
+    let curve_options = [msg.stable_swap_amp.is_some(), msg.weights.is_some(), msg.concentrated.is_some()];
+    if curve_options.iter().filter(|set| **set).count() > 1 {
+        return Err(StdError::generic_err(
+            "Only one of stable_swap_amp, weights and concentrated may be set",
+        ));
+    }
+
+    if let Some(params) = msg.concentrated.clone() {
+        if params.tick_spacing == 0 || params.tick_spacing > MAX_TICK as u32 {
+            return Err(StdError::generic_err(format!("Tick spacing must be between 1 and {}", MAX_TICK)));
+        }
+        if params.lp_fee >= Uint128::from(10000u128) {
+            return Err(StdError::generic_err("LP fee must be below 10000 basis points"));
+        }
+
+        CONCENTRATED_POOL.save(deps.storage, &new_pool(params.initial_price)?)?;
+        CURVE_CONFIG.save(deps.storage, &CurveConfig::Concentrated {
+            tick_spacing: params.tick_spacing,
+            lp_fee: params.lp_fee,
+        })?;
+    }
+
+    if let Some(weights) = msg.weights.clone() {
+        let total = weights.erth as u128 + weights.b as u128;
+        if weights.erth == 0 || weights.b == 0
+            || (weights.erth.min(weights.b) as u128) * (MIN_WEIGHT_FRACTION as u128) < total
+        {
+            return Err(StdError::generic_err(format!(
+                "Each weight must be at least 1/{} of the total",
+                MIN_WEIGHT_FRACTION
+            )));
+        }
+
+        CURVE_CONFIG.save(deps.storage, &CurveConfig::Weighted { weights })?;
+    }
+
+    if let Some(amp) = msg.stable_swap_amp {
+        if amp == 0 || amp > MAX_AMP {
+            return Err(StdError::generic_err(format!("Amplification must be between 1 and {}", MAX_AMP)));
+        }
+
+        let now = env.block.time.seconds();
+        CURVE_CONFIG.save(deps.storage, &CurveConfig::StableSwap {
+            amp: AmpRamp {
+                initial_amp: amp,
+                future_amp: amp,
+                initial_time: now,
+                future_time: now,
+            },
+        })?;
+    }
+
+    CONTRACT_INFO.save(deps.storage, &ContractInfo {
+        name: CONTRACT_NAME.to_string(),
+        version: CONTRACT_VERSION.to_string(),
+    })?;
+
+    if msg.lp_token_contract.is_some() || msg.concentrated.is_some() {
+        // No submessage, so register as a receiver for the tokens right away
+        return Ok(Response::new()
+            .add_messages(register_receive_msgs(&state, &env.contract.code_hash)?)
+            .add_attribute("action", "instantiate")
+            .add_attribute("lp_token_contract", state.lp_token_contract.to_string()));
+    }
 
-    let lp_token_name = format!("ERTH-{} Animal Swap LP Token", msg.token_b_symbol);
-    let lp_token_symbol = format!("{}LP", msg.token_b_symbol);
+    let lp_token_name = lp_token_config
+        .name
+        .unwrap_or_else(|| format!("ERTH-{} Animal Swap LP Token", msg.token_b_symbol));
+    let lp_token_symbol = lp_token_config
+        .symbol
+        .unwrap_or_else(|| format!("{}LP", msg.token_b_symbol));
+    let lp_token_admin = match lp_token_config.admin {
+        Some(admin) => deps.api.addr_validate(&admin)?.to_string(),
+        None => ERTH_DAO.to_string(),
+    };
 
-    let init_config = InitConfig {
+    let init_config = lp_token_config.config.unwrap_or(InitConfig {
         public_total_supply: Some(true),
         enable_deposit: Some(false),
         enable_redeem: Some(false),
         enable_mint: Some(true),
         enable_burn: Some(true),
         can_modify_denoms: Some(false),
-    };
+    });
+
+    // The pool mints on every deposit and burns on every unbond
+    if init_config.enable_mint != Some(true) || init_config.enable_burn != Some(true) {
+        return Err(StdError::generic_err("LP token config must enable mint and burn"));
+    }
 
     // Construct the SNIP-20 instantiation message
     let lp_token_instantiate_msg = Snip20InstantiateMsg {
         name: lp_token_name.clone(),
         admin: Some(env.contract.address.to_string()), // Use the validated address
         symbol: lp_token_symbol,
-        decimals: 6,
+        decimals: state.lp_token_decimals,
         initial_balances: None,
-        prng_seed: to_binary(&env.block.time.seconds())?,
+        prng_seed: Binary::from(block_entropy(&env)),
         config: Some(init_config),
         supported_denoms: None,
     };
 
     // Instantiate the LP token contract
     let lp_token_msg = WasmMsg::Instantiate {
-        admin: Some(ERTH_DAO.to_string()), 
+        admin: Some(lp_token_admin),
         code_id: msg.lp_token_code_id,
         code_hash: msg.lp_token_hash.clone(),
         msg: to_binary(&lp_token_instantiate_msg)?,
@@ -65,87 +275,198 @@ pub fn instantiate(
     };
 
     // Submessage for LP token instantiation
-    let sub_msg_lp = SubMsg::reply_on_success(CosmosMsg::Wasm(lp_token_msg), INSTANTIATE_LP_TOKEN_REPLY_ID);
-
-
-    // Initialize the state with placeholder addresses for the LP token and staking contracts
-    let state = State {
-        contract_manager: contract_manager.clone(),
-        token_erth_contract: token_erth_contract.clone(),
-        token_erth_hash: msg.token_erth_hash.clone(),
-        token_b_contract: token_b_contract.clone(),
-        token_b_hash: msg.token_b_hash.clone(),
-        token_b_symbol: msg.token_b_symbol,
-        registration_contract: registration_contract_addr.clone(),
-        registration_hash: msg.registration_hash,
-        lp_token_contract: Addr::unchecked(""), // Placeholder
-        lp_token_hash: msg.lp_token_hash.clone(),
-        lp_token_code_id: msg.lp_token_code_id,
-        lp_staking_contract: lp_staking_contract_addr, // Placeholder
-        lp_staking_hash: msg.lp_staking_hash.clone(),
-        token_erth_reserve: Uint128::zero(),
-        token_b_reserve: Uint128::zero(),
-        total_shares: Uint128::zero(),
-        protocol_fee: msg.protocol_fee,
-    };
-
-    // Save the initial state
-    STATE.save(deps.storage, &state)?;
+    let sub_msg_lp = SubMsg::reply_always(CosmosMsg::Wasm(lp_token_msg), INSTANTIATE_LP_TOKEN_REPLY_ID);
 
     Ok(Response::new()
         .add_submessage(sub_msg_lp)
         .add_attribute("action", "instantiate"))
 }
 
-#[entry_point]
+// Registers this contract as a receiver for ERTH, token B unless it is native and the LP token
+// unless the pool is concentrated and has none
+fn register_receive_msgs(state: &State, code_hash: &str) -> StdResult<Vec<CosmosMsg>> {
+    let mut tokens = vec![(&state.token_erth_contract, &state.token_erth_hash)];
+    if !state.lp_token_contract.as_str().is_empty() {
+        tokens.push((&state.lp_token_contract, &state.lp_token_hash));
+    }
+    if state.token_b_denom.is_none() {
+        tokens.push((&state.token_b_contract, &state.token_b_hash));
+    }
+
+    tokens
+        .iter()
+        .map(|(contract, hash)| {
+            Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: contract.to_string(),
+                code_hash: hash.to_string(),
+                msg: to_binary(&snip20::HandleMsg::RegisterReceive {
+                    code_hash: code_hash.to_string(),
+                    padding: None,
+                })?,
+                funds: vec![],
+            }))
+        })
+        .collect()
+}
+
+fn is_native_token(state: &State, token: &Addr) -> bool {
+    state.token_b_denom.as_deref() == Some(token.as_str())
+}
+
+fn token_hash(state: &State, token: &Addr) -> String {
+    if token == &state.token_erth_contract {
+        state.token_erth_hash.clone()
+    } else {
+        state.token_b_hash.clone()
+    }
+}
+
+// Pays out either pool token, through the bank when it is the native token B
+fn transfer_msg(state: &State, token: &Addr, recipient: &Addr, amount: Uint128) -> StdResult<CosmosMsg> {
+    if let Some(denom) = state.token_b_denom.as_ref().filter(|_| is_native_token(state, token)) {
+        return Ok(CosmosMsg::Bank(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount: vec![Coin::new(amount.u128(), denom)],
+        }));
+    }
+
+    Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: token.to_string(),
+        code_hash: token_hash(state, token),
+        msg: to_binary(&snip20::HandleMsg::Transfer {
+            recipient: recipient.to_string(),
+            amount,
+            padding: None,
+            memo: None,
+        })?,
+        funds: vec![],
+    }))
+}
+
+// Amount of the native token B attached to the message, which must be the only coin sent
+fn native_amount(state: &State, info: &MessageInfo) -> StdResult<Uint128> {
+    let denom = state
+        .token_b_denom
+        .as_ref()
+        .ok_or_else(|| StdError::generic_err("This pool has no native token"))?;
+
+    match info.funds.as_slice() {
+        [coin] if &coin.denom == denom && !coin.amount.is_zero() => Ok(coin.amount),
+        _ => Err(StdError::generic_err(format!("Expected a single {} coin", denom))),
+    }
+}
+
+// Validates a token given in a query or message, accepting the native denom for token B
+fn validate_token(deps: Deps, state: &State, token: &str) -> StdResult<Addr> {
+    if state.token_b_denom.as_deref() == Some(token) {
+        Ok(Addr::unchecked(token))
+    } else {
+        deps.api.addr_validate(token)
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
     msg: ExecuteMsg,
 ) -> Result<Response, StdError> {
-    match msg {
-        ExecuteMsg::AddLiquidity { amount_erth, amount_b } =>
-            execute_add_liquidity(deps, env, info, amount_erth, amount_b),
-        ExecuteMsg::UpdateState { key, value } => execute_update_state(deps, env, info, key, value),
-        ExecuteMsg::Receive { sender, from, amount, msg, memo: _ } =>
+    let response = match msg {
+        ExecuteMsg::AddLiquidity { amount_erth, amount_b, padding: _ } =>
+            execute_add_liquidity(deps, env, info, amount_erth, amount_b, false),
+        ExecuteMsg::AddLiquidityNative { amount_erth, padding: _ } =>
+            execute_add_liquidity_native(deps, env, info, amount_erth),
+        ExecuteMsg::SwapNative { min_received, hop, padding: _ } => execute_swap_native(deps, env, info, min_received, hop),
+        ExecuteMsg::UpdateState { key, value, padding: _ } => execute_update_state(deps, env, info, key, value),
+        ExecuteMsg::Receive { sender, from, amount, msg, memo: _, padding: _ } =>
             execute_receive(deps, env, info, sender, from, amount, msg),
-    }
+        ExecuteMsg::SetFeeDiscount { address, discount, padding: _ } =>
+            execute_set_fee_discount(deps, info, address, discount),
+        ExecuteMsg::RampAmp { future_amp, future_time, padding: _ } =>
+            execute_ramp_amp(deps, env, info, future_amp, future_time),
+        ExecuteMsg::StopRampAmp { padding: _ } => execute_stop_ramp_amp(deps, env, info),
+        ExecuteMsg::MintPosition { tick_lower, tick_upper, amount_erth, amount_b, padding: _ } =>
+            execute_mint_position(deps, env, info, tick_lower, tick_upper, amount_erth, amount_b),
+        ExecuteMsg::BurnPosition { position_id, liquidity, padding: _ } =>
+            execute_burn_position(deps, env, info, position_id, liquidity),
+        ExecuteMsg::CollectFees { position_id, padding: _ } => execute_collect_fees(deps, env, info, position_id),
+        ExecuteMsg::ExecuteOrders { ids, padding: _ } => execute_orders(deps, env, ids),
+        ExecuteMsg::CancelLimitOrder { id, padding: _ } => execute_cancel_limit_order(deps, info, id),
+        ExecuteMsg::ExecuteDca { id, padding: _ } => execute_dca(deps, env, info, id),
+        ExecuteMsg::CancelDca { id, padding: _ } => execute_cancel_dca(deps, info, id),
+        ExecuteMsg::SettleBatch { padding: _ } => execute_settle_batch(deps, env),
+        ExecuteMsg::ClaimBatch { padding: _ } => execute_claim_batch(deps, info),
+        ExecuteMsg::CancelBatchSwaps { padding: _ } => execute_cancel_batch_swaps(deps, info),
+        ExecuteMsg::SetFeeConfig { fee_config, padding: _ } => execute_set_fee_config(deps, info, fee_config),
+        ExecuteMsg::ClaimProtocolFees { recipient, padding: _ } => execute_claim_protocol_fees(deps, info, recipient),
+        ExecuteMsg::FlushFees { padding: _ } => execute_flush_fees(deps, env),
+        ExecuteMsg::CreateViewingKey { entropy, padding: _ } => execute_create_viewing_key(deps, env, info, entropy),
+        ExecuteMsg::SetViewingKey { key, padding: _ } => execute_set_viewing_key(deps, info, key),
+        ExecuteMsg::RevokePermit { permit_name, padding: _ } => execute_revoke_permit(deps, info, permit_name),
+    };
+
+    pad_response(response)
+}
+
+// Pads the data and the combined attribute size to a multiple of BLOCK_SIZE. The attributes are
+// padded with an extra `padding` attribute so the keys clients look up stay untouched.
+fn pad_response(response: StdResult<Response>) -> StdResult<Response> {
+    response.map(|mut response| {
+        response.data = response.data.map(|mut data| {
+            space_pad(&mut data.0, BLOCK_SIZE);
+            data
+        });
+
+        let padding_key = "padding";
+        let attributes_len: usize = response
+            .attributes
+            .iter()
+            .map(|attr| attr.key.len() + attr.value.len())
+            .sum::<usize>()
+            + padding_key.len();
+
+        // Attribute values can't be empty, so an exact fit gets a whole extra block
+        let missing = BLOCK_SIZE - attributes_len % BLOCK_SIZE;
+
+        response.add_attribute(padding_key, " ".repeat(missing))
+    })
 }
 
 
+pub fn execute_add_liquidity_native(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount_erth: Uint128,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+    let amount_b = native_amount(&state, &info)?;
+
+    execute_add_liquidity(deps, env, info, amount_erth, amount_b, true)
+}
+
 pub fn execute_add_liquidity(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
     amount_erth: Uint128,
     amount_b: Uint128,
+    b_attached: bool, // Token B came in as funds, only possible when it is native
 ) -> Result<Response, StdError> {
     let mut state = STATE.load(deps.storage)?;
 
-    let (shares, adjusted_amount_erth, adjusted_amount_b) = if state.total_shares.is_zero() {
-        // Initial liquidity: use the provided amounts directly and set the total shares to the sum
-        let shares = amount_erth + amount_b;
-        (shares, amount_erth, amount_b)
-    } else {
-        // Subsequent liquidity
-        let share_erth = amount_erth * state.total_shares / state.token_erth_reserve;
-        let share_b = amount_b * state.total_shares / state.token_b_reserve;
-        let shares = share_erth.min(share_b);
+    if state.token_b_denom.is_some() && !b_attached {
+        return Err(StdError::generic_err("Use AddLiquidityNative for pools with a native token B"));
+    }
+    ensure_share_liquidity(deps.storage)?;
 
-        // Adjust amounts based on the limiting factor
-        let adjusted_amount_erth = (shares * state.token_erth_reserve) / state.total_shares;
-        let adjusted_amount_b = (shares * state.token_b_reserve) / state.total_shares;
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
 
-        (shares, adjusted_amount_erth, adjusted_amount_b)
-    };
+    let (shares, adjusted_amount_erth, adjusted_amount_b, excess_token, excess_amount) =
+        calculate_provide(&state, amount_erth, amount_b);
 
-    // Calculate the excess amount of the token that exceeds the required ratio
-    let (excess_token, excess_amount) = if amount_erth > adjusted_amount_erth {
-        (state.token_erth_contract.clone(), amount_erth - adjusted_amount_erth)
-    } else {
-        (state.token_b_contract.clone(), amount_b - adjusted_amount_b)
-    };
+    let constant_shape = CONSTANT_SHAPE_RESPONSES.may_load(deps.storage)?.unwrap_or(false);
 
     let mut messages = vec![];
 
@@ -157,47 +478,41 @@ pub fn execute_add_liquidity(
         padding: None,
         memo: None,
     };
-    let transfer_b_msg = snip20::HandleMsg::TransferFrom {
-        owner: info.sender.clone().to_string(),
-        recipient: env.contract.address.clone().to_string(),
-        amount: adjusted_amount_b,
-        padding: None,
-        memo: None,
-    };
-
     messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
         contract_addr: state.token_erth_contract.to_string(),
         code_hash: state.token_erth_hash.clone(),
         msg: to_binary(&transfer_erth_msg)?,
         funds: vec![],
     }));
-    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.token_b_contract.to_string(),
-        code_hash: state.token_b_hash.clone(),
-        msg: to_binary(&transfer_b_msg)?,
-        funds: vec![],
-    }));
 
-    // Refund the excess token if any
-    if excess_amount > Uint128::from(2u32) {
-        let refund_msg = snip20::HandleMsg::Transfer {
-            recipient: info.sender.clone().to_string(),
-            amount: excess_amount,
+    // A native token B is already here as funds
+    if !b_attached {
+        let transfer_b_msg = snip20::HandleMsg::TransferFrom {
+            owner: info.sender.clone().to_string(),
+            recipient: env.contract.address.clone().to_string(),
+            amount: adjusted_amount_b,
             padding: None,
             memo: None,
         };
         messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: excess_token.to_string(),
-            code_hash: if excess_token == state.token_erth_contract {
-                state.token_erth_hash.clone()
-            } else {
-                state.token_b_hash.clone()
-            },
-            msg: to_binary(&refund_msg)?,
+            contract_addr: state.token_b_contract.to_string(),
+            code_hash: state.token_b_hash.clone(),
+            msg: to_binary(&transfer_b_msg)?,
             funds: vec![],
         }));
     }
 
+    // Only the adjusted amounts are pulled through allowances, so SNIP-20 excess never leaves the
+    // sender. Attached native funds arrive in full and their excess is sent back.
+    let excess_attached = b_attached && excess_token == state.token_b_contract;
+    let excess_not_pulled = if excess_attached { Uint128::zero() } else { excess_amount };
+    if excess_attached && !excess_amount.is_zero() {
+        messages.push(transfer_msg(&state, &excess_token, &info.sender, excess_amount)?);
+    } else if constant_shape {
+        // Stands in for the refund so the message count doesn't give the deposit ratio away
+        messages.push(transfer_msg(&state, &state.token_erth_contract, &info.sender, Uint128::zero())?);
+    }
+
     // Update reserves
     state.token_erth_reserve += adjusted_amount_erth;
     state.token_b_reserve += adjusted_amount_b;
@@ -219,6 +534,12 @@ pub fn execute_add_liquidity(
     }));
 
     STATE.save(deps.storage, &state)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
+    record_user_action(deps.storage, &info.sender, &env.block, UserAction::AddLiquidity {
+        amount_erth: adjusted_amount_erth,
+        amount_b: adjusted_amount_b,
+        shares,
+    })?;
 
     Ok(Response::new()
         .add_messages(messages)
@@ -226,7 +547,56 @@ pub fn execute_add_liquidity(
         .add_attribute("from", info.sender)
         .add_attribute("shares", shares.to_string())
         .add_attribute("adjusted_amount_erth", adjusted_amount_erth.to_string())
-        .add_attribute("adjusted_amount_b", adjusted_amount_b.to_string()))
+        .add_attribute("adjusted_amount_b", adjusted_amount_b.to_string())
+        .add_attribute("excess_not_pulled", excess_not_pulled.to_string()))
+}
+
+// Shared by `execute_add_liquidity` and `SimulateProvide`. Returns the shares minted, the amounts
+// taken into the reserves, and the token and amount of the deposit left over.
+fn calculate_provide(
+    state: &State,
+    amount_erth: Uint128,
+    amount_b: Uint128,
+) -> (Uint128, Uint128, Uint128, Addr, Uint128) {
+    let (shares, adjusted_amount_erth, adjusted_amount_b) = if state.total_shares.is_zero() {
+        // Initial liquidity: use the provided amounts directly and set the total shares to the sum
+        let shares = amount_erth + amount_b;
+        (shares, amount_erth, amount_b)
+    } else {
+        // Subsequent liquidity. Deposits in proportion to the reserves leave the price unchanged on
+        // every curve, so this holds for weighted pools too
+        let share_erth = amount_erth * state.total_shares / state.token_erth_reserve;
+        let share_b = amount_b * state.total_shares / state.token_b_reserve;
+        let shares = share_erth.min(share_b);
+
+        // Adjust amounts based on the limiting factor
+        let adjusted_amount_erth = (shares * state.token_erth_reserve) / state.total_shares;
+        let adjusted_amount_b = (shares * state.token_b_reserve) / state.total_shares;
+
+        (shares, adjusted_amount_erth, adjusted_amount_b)
+    };
+
+    // Calculate the excess amount of the token that exceeds the required ratio
+    let (excess_token, excess_amount) = if amount_erth > adjusted_amount_erth {
+        (state.token_erth_contract.clone(), amount_erth - adjusted_amount_erth)
+    } else {
+        (state.token_b_contract.clone(), amount_b - adjusted_amount_b)
+    };
+
+    (shares, adjusted_amount_erth, adjusted_amount_b, excess_token, excess_amount)
+}
+
+// Shared by `recieve_unbond_liquidity` and `SimulateWithdraw`
+fn calculate_withdraw(state: &State, lp_token_amount: Uint128) -> StdResult<(Uint128, Uint128)> {
+    if lp_token_amount > state.total_shares || state.total_shares.is_zero() {
+        return Err(StdError::generic_err("LP token amount exceeds total shares"));
+    }
+
+    // Calculate the amount of ERTH and B tokens to return, pro rata regardless of the curve
+    let amount_erth = (lp_token_amount * state.token_erth_reserve) / state.total_shares;
+    let amount_b = (lp_token_amount * state.token_b_reserve) / state.total_shares;
+
+    Ok((amount_erth, amount_b))
 }
 
 
@@ -272,6 +642,55 @@ pub fn execute_update_state(
         "registration_hash" => {
             state.registration_hash = value.clone();
         }
+        "registered_user_discount" => {
+            let discount: Uint128 = value.parse().map_err(|_| StdError::generic_err("Invalid registered_user_discount"))?;
+            if discount > Uint128::from(MAX_FEE_DISCOUNT) {
+                return Err(StdError::generic_err("Discount cannot exceed 10000 basis points"));
+            }
+            REGISTERED_USER_DISCOUNT.save(deps.storage, &discount)?;
+        }
+        "constant_shape_responses" => {
+            let constant_shape: bool = value.parse().map_err(|_| StdError::generic_err("Invalid constant_shape_responses"))?;
+            CONSTANT_SHAPE_RESPONSES.save(deps.storage, &constant_shape)?;
+        }
+        "snapshot_capacity" => {
+            let capacity: u32 = value.parse().map_err(|_| StdError::generic_err("Invalid snapshot_capacity"))?;
+            SNAPSHOT_CAPACITY.save(deps.storage, &capacity)?;
+        }
+        "batch_window" => {
+            let window: u64 = value.parse().map_err(|_| StdError::generic_err("Invalid batch_window"))?;
+            BATCH_WINDOW.save(deps.storage, &window)?;
+        }
+        "router_contract" | "factory_contract" => {
+            let mut forwarders = FORWARDERS.may_load(deps.storage)?.unwrap_or_default();
+            // Empty to stop trusting the contract
+            let contract = if value.is_empty() { None } else { Some(deps.api.addr_validate(&value)?) };
+            if key == "router_contract" {
+                forwarders.router_contract = contract;
+            } else {
+                forwarders.factory_contract = contract;
+            }
+            FORWARDERS.save(deps.storage, &forwarders)?;
+        }
+        "factory_hash" => {
+            let mut forwarders = FORWARDERS.may_load(deps.storage)?.unwrap_or_default();
+            forwarders.factory_hash = value.clone();
+            FORWARDERS.save(deps.storage, &forwarders)?;
+        }
+        "batch_min_intent" => {
+            let min_intent: Uint128 = value.parse().map_err(|_| StdError::generic_err("Invalid batch_min_intent"))?;
+            BATCH_MIN_INTENT.save(deps.storage, &min_intent)?;
+        }
+        "fee_flush_threshold" => {
+            let mut flush_config = FLUSH_CONFIG.may_load(deps.storage)?.unwrap_or_default();
+            flush_config.threshold = value.parse().map_err(|_| StdError::generic_err("Invalid fee_flush_threshold"))?;
+            FLUSH_CONFIG.save(deps.storage, &flush_config)?;
+        }
+        "fee_flush_interval" => {
+            let mut flush_config = FLUSH_CONFIG.may_load(deps.storage)?.unwrap_or_default();
+            flush_config.interval = value.parse().map_err(|_| StdError::generic_err("Invalid fee_flush_interval"))?;
+            FLUSH_CONFIG.save(deps.storage, &flush_config)?;
+        }
         _ => return Err(StdError::generic_err("Invalid state key")),
     }
 
@@ -283,618 +702,3663 @@ pub fn execute_update_state(
         .add_attribute("value", value))
 }
 
+// Chain randomness where the network provides it, otherwise a hash of the block and contract
+fn block_entropy(env: &Env) -> Vec<u8> {
+    match &env.block.random {
+        Some(random) => random.to_vec(),
+        None => sha_256(
+            [
+                env.block.height.to_be_bytes().as_slice(),
+                env.block.time.nanos().to_be_bytes().as_slice(),
+                env.contract.address.as_bytes(),
+            ]
+            .concat()
+            .as_slice(),
+        )
+        .to_vec(),
+    }
+}
+
+// Mixes the caller's secret entropy into the chain randomness. The LP token is seeded from the
+// block entropy alone, so without the caller's secret it could recompute the seed.
+fn seed_viewing_keys(storage: &mut dyn Storage, env: &Env, entropy: &str) {
+    let random = env.block.random.as_ref().map(|random| random.to_vec()).unwrap_or_default();
+    ViewingKey::set_seed(storage, &sha_256(&[random.as_slice(), entropy.as_bytes(), b"viewing_key"].concat()));
+}
 
+// Pools that predate viewing keys have no seed until their first migrate
+fn viewing_key_seed_is_set(storage: &dyn Storage) -> bool {
+    storage.get(&[ViewingKey::STORAGE_KEY, b"::seed"].concat()).is_some()
+}
 
-pub fn execute_receive(
+pub fn execute_create_viewing_key(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    sender: String,
-    from: String,
-    amount: Uint128,
-    msg: Binary,
+    entropy: String,
 ) -> Result<Response, StdError> {
-    let msg: ReceiveMsg = from_binary(&msg)?;
+    let key = ViewingKey::create(
+        deps.storage,
+        &info,
+        &env,
+        info.sender.as_str(),
+        entropy.as_bytes(),
+    );
 
-    let _sender_addr = deps.api.addr_validate(&sender)?;
-    let from_addr = deps.api.addr_validate(&from)?;
+    Ok(Response::new()
+        .set_data(to_binary(&ExecuteAnswer::CreateViewingKey { key })?)
+        .add_attribute("action", "create_viewing_key"))
+}
 
-    match msg {
-        ReceiveMsg::Swap {min_received, hop, user} => receive_swap(deps, env, info, from_addr, amount, min_received, hop, user),
-        ReceiveMsg::UnbondLiquidity {} => recieve_unbond_liquidity(deps, env, info, from_addr, amount),
-        ReceiveMsg::ErthBuybackSwap {} => receive_erth_buyback_swap(deps, info, amount),
-        ReceiveMsg::AnmlBuybackSwap {} => receive_anml_buyback_swap(deps, info, amount),
+pub fn execute_set_viewing_key(
+    deps: DepsMut,
+    info: MessageInfo,
+    key: String,
+) -> Result<Response, StdError> {
+    ViewingKey::set(deps.storage, info.sender.as_str(), &key);
 
-    }
+    Ok(Response::new()
+        .add_attribute("action", "set_viewing_key"))
 }
 
+pub fn execute_revoke_permit(
+    deps: DepsMut,
+    info: MessageInfo,
+    permit_name: String,
+) -> Result<Response, StdError> {
+    RevokedPermits::revoke_permit(
+        deps.storage,
+        PREFIX_REVOKED_PERMITS,
+        info.sender.as_str(),
+        &permit_name,
+    );
 
+    Ok(Response::new()
+        .add_attribute("action", "revoke_permit")
+        .add_attribute("permit_name", permit_name))
+}
 
-fn receive_swap(
+pub fn execute_set_fee_discount(
     deps: DepsMut,
-    _env: Env,
     info: MessageInfo,
-    mut from: Addr,
-    amount: Uint128,
-    min_received: Option<Uint128>,
-    hop: Option<HopDetails>,
-    user: Option<Addr>,
+    address: String,
+    discount: Option<Uint128>,
 ) -> Result<Response, StdError> {
-    // Load state
-    let mut state = STATE.load(deps.storage)?;
-    let input_amount = amount;
-    let input_token = info.sender.clone();
+    let state = STATE.load(deps.storage)?;
 
-    // Calculate the swap details and update reserves directly, including trade volume in ERTH
-    let (protocol_fee_amount, output_amount, output_addr, output_hash, trade_volume_in_erth) =
-        calculate_swap(&mut state, input_amount, &input_token)?;
+    if info.sender != state.contract_manager {
+        return Err(StdError::generic_err("unauthorized"));
+    }
 
-    let mut messages = vec![];
+    let address = deps.api.addr_validate(&address)?;
 
-    // Handle the protocol fee in ERTH
-    let buyback_msg = snip20::HandleMsg::Send {
-        recipient: state.lp_staking_contract.to_string(),
-        recipient_code_hash: Some(state.lp_staking_hash.clone()),
-        amount: protocol_fee_amount,
-        msg: Some(to_binary(&SendMessage::BurnErth {
-            trade_volume: trade_volume_in_erth, 
-            pool_liquidity: state.token_erth_reserve * Uint128::from(2u32),
-            total_shares: state.total_shares.clone(),
-        })?),
-        memo: None,
-        padding: None,
-    };
-
-    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.token_erth_contract.to_string(),
-        code_hash: state.token_erth_hash.clone(),
-        msg: to_binary(&buyback_msg)?,
-        funds: vec![],
-    }));
-
-    // Check if hop details are provided
-    if let Some(hop_details) = hop {
-        // Try to validate the hop contract address
-        let hop_addr = deps.api.addr_validate(&hop_details.contract)?;
-
-        let hop_msg = snip20::HandleMsg::Send {
-            recipient: hop_addr.to_string(),
-            recipient_code_hash: Some(hop_details.hash.clone()),
-            amount: output_amount,
-            msg: Some(to_binary(&SendMessage::Swap {
-                min_received: min_received.clone(),
-                user: from.clone(),
-            })?),
-            memo: None,
-            padding: None,
-        };
-
-        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: output_addr.to_string(),
-            code_hash: output_hash.clone(),
-            msg: to_binary(&hop_msg)?,
-            funds: vec![],
-        }));
-
-    } else {
-        // Check against minimum received amount
-        if let Some(min) = min_received {
-            if output_amount < min {
-                return Err(StdError::generic_err("Output amount is less than the minimum received amount"));
+    // A missing discount removes the override so the address falls back to the default fee
+    match discount {
+        Some(discount) => {
+            if discount > Uint128::from(MAX_FEE_DISCOUNT) {
+                return Err(StdError::generic_err("Discount cannot exceed 10000 basis points"));
             }
+            FEE_DISCOUNTS.insert(deps.storage, &address, &discount)?;
         }
-
-        // Check for user parameter in the case of the second part of the hop
-        if let Some(user_addr) = user {
-            from = user_addr;
+        None => {
+            FEE_DISCOUNTS.remove(deps.storage, &address)?;
         }
-        
-
-        // Create transfer message if `from` is provided
-        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: output_addr.to_string(),
-            code_hash: output_hash,
-            msg: to_binary(&snip20::HandleMsg::Transfer {
-                recipient: from.to_string(),
-                amount: output_amount,
-                padding: None,
-                memo: None,
-            })?,
-            funds: vec![],
-        }));
     }
 
-    // Save the updated state
-    STATE.save(deps.storage, &state)?;
-
     Ok(Response::new()
-        .add_messages(messages)
-        .add_attribute("action", "swap")
-        .add_attribute("from", from.to_string())
-        .add_attribute("input_amount", amount.to_string())
-        .add_attribute("output_amount", output_amount.to_string())
-        .add_attribute("protocol_fee_amount", protocol_fee_amount.to_string())
-        .add_attribute("trade_volume_in_erth", trade_volume_in_erth.to_string()))  // Add trade volume attribute
+        .add_attribute("action", "set_fee_discount")
+        .add_attribute("address", address)
+        .add_attribute("discount", discount.unwrap_or_default().to_string()))
 }
 
+pub fn execute_ramp_amp(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    future_amp: u64,
+    future_time: u64,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
 
+    if info.sender != state.contract_manager {
+        return Err(StdError::generic_err("unauthorized"));
+    }
 
-fn calculate_swap(
-    state: &mut State,  // Mutably borrow the state so we can update reserves
-    input_amount: Uint128,
-    input_token: &Addr,
-) -> Result<(Uint128, Uint128, Addr, String, Uint128), StdError> {
-    // Calculate protocol fee in the input token
-    let mut protocol_fee_amount = input_amount * state.protocol_fee / Uint128::from(10000u128);
-    let amount_after_protocol_fee = input_amount - protocol_fee_amount;
-
-    // Extract all necessary details from the state
-    let (input_reserve, output_reserve, output_addr, output_hash, trade_volume_in_erth) = if input_token == &state.token_erth_contract {
-        (
-            state.token_erth_reserve,
-            state.token_b_reserve,
-            state.token_b_contract.clone(),
-            state.token_b_hash.clone(),
-            input_amount,  // Trade volume is the input amount in ERTH
-        )
-    } else if input_token == &state.token_b_contract {
-        (
-            state.token_b_reserve,
-            state.token_erth_reserve,
-            state.token_erth_contract.clone(),
-            state.token_erth_hash.clone(),
-            // Convert input token volume to ERTH using reserve ratio
-            (input_amount * state.token_erth_reserve) / state.token_b_reserve,
-        )
-    } else {
-        return Err(StdError::generic_err("Invalid input token"));
+    let CurveConfig::StableSwap { amp: ramp } = load_curve_config(deps.storage)? else {
+        return Err(StdError::generic_err("Only StableSwap pools have an amplification"));
     };
 
-    // Calculate the output amount using the constant product formula
-    let output_amount = (amount_after_protocol_fee * output_reserve)
-        / (input_reserve + amount_after_protocol_fee);
+    // A pool that never ramped has `initial_time == future_time` and can ramp right away
+    let now = env.block.time.seconds();
+    let ramped = ramp.initial_time != ramp.future_time;
+    if ramped && now < ramp.initial_time + MIN_RAMP_TIME {
+        return Err(StdError::generic_err("The amplification was ramped too recently"));
+    }
+    if future_time < now + MIN_RAMP_TIME {
+        return Err(StdError::generic_err("Ramp time is too short"));
+    }
+    if future_amp == 0 || future_amp > MAX_AMP {
+        return Err(StdError::generic_err(format!("Amplification must be between 1 and {}", MAX_AMP)));
+    }
 
-    // Check if the liquidity is enough
-    if output_amount > output_reserve {
-        return Err(StdError::generic_err("Insufficient liquidity in reserves"));
+    // Large jumps would let arbitrageurs drain the pool as the curve reshapes
+    let initial_amp = current_amp(&ramp, now);
+    if future_amp > initial_amp * MAX_AMP_CHANGE || future_amp * MAX_AMP_CHANGE < initial_amp {
+        return Err(StdError::generic_err(format!(
+            "Amplification can change by at most {}x per ramp",
+            MAX_AMP_CHANGE
+        )));
     }
 
-    // Update the reserves based on the swap
-    if input_token == &state.token_erth_contract {
-        state.token_erth_reserve += amount_after_protocol_fee; // Add input amount to ERTH reserve
-        state.token_b_reserve -= output_amount; // Subtract output amount from token B reserve
-    } else if input_token == &state.token_b_contract {
-        state.token_b_reserve += amount_after_protocol_fee; // Add to token B reserve after protocol fee is deducted
-        state.token_erth_reserve -= output_amount;          // Subtract from ERTH reserve (as we are sending this amount)
+    CURVE_CONFIG.save(deps.storage, &CurveConfig::StableSwap {
+        amp: AmpRamp {
+            initial_amp,
+            future_amp,
+            initial_time: now,
+            future_time,
+        },
+    })?;
 
-        // Perform feeless swap to convert protocol fee to ERTH
-        let protocol_fee_in_erth = calculate_feeless_swap(&state, protocol_fee_amount, &state.token_b_contract)?;
+    Ok(Response::new()
+        .add_attribute("action", "ramp_amp")
+        .add_attribute("initial_amp", initial_amp.to_string())
+        .add_attribute("future_amp", future_amp.to_string())
+        .add_attribute("future_time", future_time.to_string()))
+}
 
-        //update reserves
-        state.token_b_reserve += protocol_fee_amount;
-        state.token_erth_reserve -= protocol_fee_in_erth;
+pub fn execute_stop_ramp_amp(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
 
-        // The `protocol_fee_amount` now represents the amount in ERTH
-        protocol_fee_amount = protocol_fee_in_erth;
+    if info.sender != state.contract_manager {
+        return Err(StdError::generic_err("unauthorized"));
     }
 
-    // Return the result including the protocol fee (now in ERTH), output amount, and other details
-    Ok((
-        protocol_fee_amount, // Protocol fee in ERTH
-        output_amount,
-        output_addr,
-        output_hash,
-        trade_volume_in_erth,
-    ))
-}
+    let CurveConfig::StableSwap { amp: ramp } = load_curve_config(deps.storage)? else {
+        return Err(StdError::generic_err("Only StableSwap pools have an amplification"));
+    };
+
+    let now = env.block.time.seconds();
+    let amp = current_amp(&ramp, now);
+    CURVE_CONFIG.save(deps.storage, &CurveConfig::StableSwap {
+        amp: AmpRamp {
+            initial_amp: amp,
+            future_amp: amp,
+            initial_time: now,
+            future_time: now,
+        },
+    })?;
 
+    Ok(Response::new()
+        .add_attribute("action", "stop_ramp_amp")
+        .add_attribute("amp", amp.to_string()))
+}
 
+// Concentrated pools hold liquidity in positions, so LP share deposits don't apply to them
+fn ensure_share_liquidity(storage: &dyn Storage) -> StdResult<()> {
+    match load_curve_config(storage)? {
+        CurveConfig::Concentrated { .. } => Err(StdError::generic_err(
+            "Concentrated pools take liquidity through MintPosition",
+        )),
+        _ => Ok(()),
+    }
+}
 
+fn load_concentrated_pool(storage: &dyn Storage) -> StdResult<(ConcentratedPool, u32)> {
+    match load_curve_config(storage)? {
+        CurveConfig::Concentrated { tick_spacing, .. } => Ok((CONCENTRATED_POOL.load(storage)?, tick_spacing)),
+        _ => Err(StdError::generic_err("Only concentrated pools have positions")),
+    }
+}
 
+fn liquidity_delta(liquidity: Uint128) -> StdResult<i128> {
+    i128::try_from(liquidity.u128()).map_err(|_| StdError::generic_err("Liquidity too large"))
+}
 
+// Pays out the position's amounts, skipping zeros since bank sends can't carry them
+fn position_payout_msgs(
+    state: &State,
+    recipient: &Addr,
+    amount_erth: Uint128,
+    amount_b: Uint128,
+) -> StdResult<Vec<CosmosMsg>> {
+    [(&state.token_erth_contract, amount_erth), (&state.token_b_contract, amount_b)]
+        .into_iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(token, amount)| transfer_msg(state, token, recipient, amount))
+        .collect()
+}
 
-fn receive_erth_buyback_swap(
+#[allow(clippy::too_many_arguments)]
+pub fn execute_mint_position(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
-    amount: Uint128,
+    tick_lower: i32,
+    tick_upper: i32,
+    amount_erth: Uint128,
+    amount_b: Uint128,
 ) -> Result<Response, StdError> {
-
     let mut state = STATE.load(deps.storage)?;
-    let input_token = info.sender.clone();
-
-    if input_token != state.token_b_contract {
-        return Err(StdError::generic_err("invalid input token for erth buyback contract"));
+    let (mut pool, tick_spacing) = load_concentrated_pool(deps.storage)?;
+    validate_range(tick_lower, tick_upper, tick_spacing)?;
+
+    // A native token B comes in as funds, which may be left out when the range needs none
+    if let Some(denom) = state.token_b_denom.as_ref() {
+        let attached = match info.funds.as_slice() {
+            [] => Uint128::zero(),
+            [coin] if &coin.denom == denom => coin.amount,
+            _ => return Err(StdError::generic_err(format!("Only {} may be attached", denom))),
+        };
+        if attached != amount_b {
+            return Err(StdError::generic_err(format!("Attach exactly amount_b of {}", denom)));
+        }
     }
 
-    // Calculate the swap details without fees
-    let output_amount = calculate_feeless_swap(&mut state, amount, &input_token)?;
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
 
-    // Update reserves
-    state.token_b_reserve += amount;
-    state.token_erth_reserve -= output_amount;
+    let liquidity = liquidity_for_amounts(&pool, tick_lower, tick_upper, amount_erth, amount_b)?;
+    if liquidity.is_zero() {
+        return Err(StdError::generic_err("Amounts are too small for this range"));
+    }
 
-    // Save state
-    STATE.save(deps.storage, &state)?;
+    // Never more than the given amounts, since the liquidity was rounded down
+    let (used_erth, used_b) = amounts_for_liquidity(&pool, tick_lower, tick_upper, liquidity, true)?;
 
-    // Create a Send message to send the output amount back to the buyback contract for burning
-    let buyback_msg = snip20::HandleMsg::Send {
-        recipient: state.lp_staking_contract.to_string(),
-        recipient_code_hash: Some(state.lp_staking_hash.clone()),
-        amount: output_amount,
-        msg: Some(to_binary(&SendMessage::BurnErth {
-            trade_volume: output_amount,
-            pool_liquidity: state.token_erth_reserve * Uint128::from(2u32),
-            total_shares: state.total_shares.clone(),
-        })?),
-        memo: None,
-        padding: None,
+    let mut ticks = Ticks::new(tick_spacing);
+    let mut position = open_position(&mut pool, tick_lower, tick_upper);
+    update_position(deps.storage, &mut pool, &mut ticks, &mut position, liquidity_delta(liquidity)?)?;
+
+    let mut messages = vec![];
+    let mut pull = |token: &Addr, hash: &str, amount: Uint128| -> StdResult<()> {
+        if !amount.is_zero() {
+            messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: token.to_string(),
+                code_hash: hash.to_string(),
+                msg: to_binary(&snip20::HandleMsg::TransferFrom {
+                    owner: info.sender.to_string(),
+                    recipient: env.contract.address.to_string(),
+                    amount,
+                    padding: None,
+                    memo: None,
+                })?,
+                funds: vec![],
+            }));
+        }
+        Ok(())
     };
+    pull(&state.token_erth_contract, &state.token_erth_hash, used_erth)?;
+    if state.token_b_denom.is_none() {
+        pull(&state.token_b_contract, &state.token_b_hash, used_b)?;
+    } else if amount_b > used_b {
+        messages.push(transfer_msg(&state, &state.token_b_contract, &info.sender, amount_b - used_b)?);
+    }
 
-    // Create the message to execute the Send
-    let send_message = CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.token_erth_contract.to_string(),
-        code_hash: state.token_erth_hash.clone(),
-        msg: to_binary(&buyback_msg)?,
-        funds: vec![],
-    });
+    state.token_erth_reserve += used_erth;
+    state.token_b_reserve += used_b;
+
+    POSITIONS.add_suffix(info.sender.as_bytes()).insert(deps.storage, &position.id, &position)?;
+    ticks.save(deps.storage)?;
+    CONCENTRATED_POOL.save(deps.storage, &pool)?;
+    STATE.save(deps.storage, &state)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
 
     Ok(Response::new()
-        .add_message(send_message)
-        .add_attribute("action", "buyback_swap")
-        .add_attribute("input_amount", amount.to_string())
-        .add_attribute("output_amount", output_amount.to_string()))
+        .add_messages(messages)
+        .add_attribute("action", "mint_position")
+        .add_attribute("position_id", position.id.to_string())
+        .add_attribute("liquidity", liquidity.to_string())
+        .add_attribute("amount_erth", used_erth.to_string())
+        .add_attribute("amount_b", used_b.to_string()))
 }
 
-// function only used in the ANML-ERTH pair for 1/second ERTH->ANML buyback and burn
-fn receive_anml_buyback_swap(
+pub fn execute_burn_position(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
-    amount: Uint128,
+    position_id: u64,
+    liquidity: Uint128,
 ) -> Result<Response, StdError> {
-
     let mut state = STATE.load(deps.storage)?;
-    let input_token = info.sender.clone();
+    let (mut pool, tick_spacing) = load_concentrated_pool(deps.storage)?;
 
-    if input_token != state.token_erth_contract {
-        return Err(StdError::generic_err("invalid input token for anml buyback contract"));
-    }
-
-    // Calculate the swap details without fees
-    let output_amount = calculate_feeless_swap(&mut state, amount, &input_token)?;
+    let positions = POSITIONS.add_suffix(info.sender.as_bytes());
+    let mut position = positions
+        .get(deps.storage, &position_id)
+        .ok_or_else(|| StdError::generic_err("Position not found"))?;
 
-    // Update reserves
-    state.token_erth_reserve += amount;
-    state.token_b_reserve -= output_amount;
-
-    // Save state
-    STATE.save(deps.storage, &state)?;
+    if liquidity.is_zero() || liquidity > position.liquidity {
+        return Err(StdError::generic_err("Liquidity must be between 1 and the position's liquidity"));
+    }
 
-    // Create a Send message to send the output amount back to the buyback contract for burning
-    let buyback_msg = snip20::HandleMsg::Send {
-        recipient: state.lp_staking_contract.to_string(),
-        recipient_code_hash: Some(state.lp_staking_hash.clone()),
-        amount: output_amount,
-        msg: Some(to_binary(&SendMessage::BurnAnml {
-            trade_volume: amount,
-            pool_liquidity: state.token_erth_reserve * Uint128::from(2u32),
-            total_shares: state.total_shares.clone(),
-        })?),
-        memo: None,
-        padding: None,
-    };
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
 
-    // Create the message to execute the Send
-    let send_message = CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.token_b_contract.to_string(),
-        code_hash: state.token_b_hash.clone(),
-        msg: to_binary(&buyback_msg)?,
-        funds: vec![],
-    });
+    let (amount_erth, amount_b) =
+        amounts_for_liquidity(&pool, position.tick_lower, position.tick_upper, liquidity, false)?;
+    let mut ticks = Ticks::new(tick_spacing);
+    update_position(deps.storage, &mut pool, &mut ticks, &mut position, -liquidity_delta(liquidity)?)?;
 
-    Ok(Response::new()
-        .add_message(send_message)
-        .add_attribute("action", "buyback_swap")
-        .add_attribute("input_amount", amount.to_string())
-        .add_attribute("output_amount", output_amount.to_string()))
-}
+    state.token_erth_reserve -= amount_erth;
+    state.token_b_reserve -= amount_b;
 
-fn calculate_feeless_swap(
-    state: &State, 
-    input_amount: Uint128,
-    input_token: &Addr,
-) -> Result<Uint128, StdError> {
-    // Extract the reserves immutably before mutating state
-    let (input_reserve, output_reserve) = if input_token == &state.token_b_contract {
-        (state.token_b_reserve, state.token_erth_reserve)
-    } else if input_token == &state.token_erth_contract {
-        (state.token_erth_reserve, state.token_b_reserve)
+    // Emptied positions are kept until their fees are collected
+    if position.liquidity.is_zero() && position.tokens_owed_erth.is_zero() && position.tokens_owed_b.is_zero() {
+        positions.remove(deps.storage, &position_id)?;
     } else {
-        return Err(StdError::generic_err("Invalid input token for feeless swap"));
-    };
-
-    // Calculate the output amount using the constant product formula
-    let output_amount = (input_amount * output_reserve)
-        / (input_reserve + input_amount);
-
-    // Check if there is enough liquidity in the reserves
-    if output_amount > output_reserve {
-        return Err(StdError::generic_err(
-            "Insufficient liquidity in reserves for feeless swap",
-        ));
+        positions.insert(deps.storage, &position_id, &position)?;
     }
+    ticks.save(deps.storage)?;
+    CONCENTRATED_POOL.save(deps.storage, &pool)?;
+    STATE.save(deps.storage, &state)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
 
-    // Return the calculated output amount (which is in ERTH)
-    Ok(output_amount)
+    Ok(Response::new()
+        .add_messages(position_payout_msgs(&state, &info.sender, amount_erth, amount_b)?)
+        .add_attribute("action", "burn_position")
+        .add_attribute("position_id", position_id.to_string())
+        .add_attribute("liquidity", liquidity.to_string())
+        .add_attribute("amount_erth", amount_erth.to_string())
+        .add_attribute("amount_b", amount_b.to_string()))
 }
 
-
-
-pub fn recieve_unbond_liquidity(
+pub fn execute_collect_fees(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     info: MessageInfo,
-    from: Addr,
-    lp_token_amount: Uint128,
+    position_id: u64,
 ) -> Result<Response, StdError> {
-    // Load the state
     let mut state = STATE.load(deps.storage)?;
+    let (mut pool, tick_spacing) = load_concentrated_pool(deps.storage)?;
 
-    if info.sender != state.lp_token_contract {
-        return Err(StdError::generic_err("Invalid LP token"));
+    let positions = POSITIONS.add_suffix(info.sender.as_bytes());
+    let mut position = positions
+        .get(deps.storage, &position_id)
+        .ok_or_else(|| StdError::generic_err("Position not found"))?;
+
+    // Credit what was earned since the last update, which leaves the ticks as they are
+    if !position.liquidity.is_zero() {
+        update_position(deps.storage, &mut pool, &mut Ticks::new(tick_spacing), &mut position, 0)?;
     }
 
-    // Calculate the amount of ERTH and B tokens to return
-    let amount_erth = (lp_token_amount * state.token_erth_reserve) / state.total_shares;
-    let amount_b = (lp_token_amount * state.token_b_reserve) / state.total_shares;
+    let (amount_erth, amount_b) = (position.tokens_owed_erth, position.tokens_owed_b);
+    position.tokens_owed_erth = Uint128::zero();
+    position.tokens_owed_b = Uint128::zero();
 
-    // Update the state reserves and total shares
     state.token_erth_reserve -= amount_erth;
     state.token_b_reserve -= amount_b;
 
-    // Adjust total shares based on the unbonding amounts
-    state.total_shares -= lp_token_amount;
-
+    if position.liquidity.is_zero() {
+        positions.remove(deps.storage, &position_id)?;
+    } else {
+        positions.insert(deps.storage, &position_id, &position)?;
+    }
+    CONCENTRATED_POOL.save(deps.storage, &pool)?;
     STATE.save(deps.storage, &state)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
 
-    let mut messages = vec![];
-
-    // Create message to burn the LP tokens
-    let burn_lp_msg = snip20::HandleMsg::Burn {
-        amount: lp_token_amount,
-        memo: None,
-        padding: None,
-    };
+    Ok(Response::new()
+        .add_messages(position_payout_msgs(&state, &info.sender, amount_erth, amount_b)?)
+        .add_attribute("action", "collect_fees")
+        .add_attribute("position_id", position_id.to_string())
+        .add_attribute("amount_erth", amount_erth.to_string())
+        .add_attribute("amount_b", amount_b.to_string()))
+}
 
-    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.lp_token_contract.to_string(),
-        code_hash: state.lp_token_hash.clone(),
-        msg: to_binary(&burn_lp_msg)?,
-        funds: vec![],
-    }));
+pub fn execute_set_fee_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    fee_config: FeeConfig,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
 
-    // Transfer the unbonded tokens to the user
-    let transfer_erth_msg = snip20::HandleMsg::Transfer {
-        recipient: from.clone().to_string(),
-        amount: amount_erth,
-        padding: None,
-        memo: None,
+    if info.sender != state.contract_manager {
+        return Err(StdError::generic_err("unauthorized"));
+    }
+
+    match &fee_config.destination {
+        FeeDestination::Staking {} => {
+            // Flushes to the staking contract only ever send the fee side token
+            if fee_config.fee_token != FeeToken::FeeSide {
+                return Err(StdError::generic_err("Staking destination requires the fee side fee token"));
+            }
+        }
+        FeeDestination::Accumulate {} => {}
+        FeeDestination::Split { recipients } => {
+            if recipients.is_empty() {
+                return Err(StdError::generic_err("Split destination requires at least one recipient"));
+            }
+            let mut total_share = Uint128::zero();
+            for recipient in recipients {
+                deps.api.addr_validate(recipient.address.as_str())?;
+                total_share += recipient.share;
+            }
+            if total_share != Uint128::from(10000u128) {
+                return Err(StdError::generic_err("Recipient shares must sum to 10000 basis points"));
+            }
+            // Fees kept in a native token B go out as bank sends, which can't deliver a message
+            let native_fees = state.token_b_denom.is_some() && fee_config.fee_token == FeeToken::Input;
+            if native_fees && recipients.iter().any(|recipient| recipient.msg.is_some()) {
+                return Err(StdError::generic_err("Recipients can't take a msg when fees may be paid in a native denom"));
+            }
+        }
+    }
+
+    FEE_CONFIG.save(deps.storage, &fee_config)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_fee_config"))
+}
+
+pub fn execute_claim_protocol_fees(
+    deps: DepsMut,
+    info: MessageInfo,
+    recipient: Option<String>,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+
+    if info.sender != state.contract_manager {
+        return Err(StdError::generic_err("unauthorized"));
+    }
+
+    let recipient = match recipient {
+        Some(recipient) => deps.api.addr_validate(&recipient)?,
+        None => state.contract_manager.clone(),
     };
-    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.token_erth_contract.to_string(),
-        code_hash: state.token_erth_hash.clone(),
-        msg: to_binary(&transfer_erth_msg)?,
-        funds: vec![],
-    }));
 
-    let transfer_b_msg = snip20::HandleMsg::Transfer {
-        recipient: from.clone().to_string(),
-        amount: amount_b,
-        padding: None,
+    let accumulated = ACCUMULATED_FEES.may_load(deps.storage)?.unwrap_or_default();
+    ACCUMULATED_FEES.save(deps.storage, &Default::default())?;
+
+    let mut messages = vec![];
+
+    if !accumulated.erth.is_zero() {
+        messages.push(transfer_msg(&state, &state.token_erth_contract, &recipient, accumulated.erth)?);
+    }
+
+    if !accumulated.token_b.is_zero() {
+        messages.push(transfer_msg(&state, &state.token_b_contract, &recipient, accumulated.token_b)?);
+    }
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "claim_protocol_fees")
+        .add_attribute("recipient", recipient)
+        .add_attribute("erth_amount", accumulated.erth.to_string())
+        .add_attribute("token_b_amount", accumulated.token_b.to_string()))
+}
+
+// Permissionless: forwards the accrued staking fees once the threshold or interval is reached
+pub fn execute_flush_fees(
+    deps: DepsMut,
+    env: Env,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+    let flush_config = FLUSH_CONFIG.may_load(deps.storage)?.unwrap_or_default();
+    let mut accrued = PROTOCOL_FEES_ACCRUED.may_load(deps.storage)?.unwrap_or_default();
+
+    if accrued.amount.is_zero() {
+        return Err(StdError::generic_err("No protocol fees to flush"));
+    }
+
+    let now = env.block.time.seconds();
+    let threshold_reached = accrued.amount >= flush_config.threshold;
+    let interval_elapsed = now >= accrued.last_flush + flush_config.interval;
+
+    if !threshold_reached && !interval_elapsed {
+        return Err(StdError::generic_err("Flush threshold and interval not yet reached"));
+    }
+
+    // Fees accrue in the fee side token, see `route_protocol_fee`
+    let (fee_token, fee_token_hash, fee_reserve) = fee_side_token(&state);
+
+    let buyback_msg = snip20::HandleMsg::Send {
+        recipient: state.lp_staking_contract.to_string(),
+        recipient_code_hash: Some(state.lp_staking_hash.clone()),
+        amount: accrued.amount,
+        msg: Some(to_binary(&SendMessage::BurnErth {
+            trade_volume: accrued.trade_volume,
+            pool_liquidity: fee_reserve * Uint128::from(2u32),
+            total_shares: state.total_shares,
+        })?),
         memo: None,
+        padding: None,
     };
-    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.token_b_contract.to_string(),
-        code_hash: state.token_b_hash.clone(),
-        msg: to_binary(&transfer_b_msg)?,
+
+    let send_message = CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: fee_token.to_string(),
+        code_hash: fee_token_hash,
+        msg: to_binary(&buyback_msg)?,
         funds: vec![],
-    }));
+    });
 
+    let flushed = accrued.clone();
+    accrued.amount = Uint128::zero();
+    accrued.trade_volume = Uint128::zero();
+    accrued.last_flush = now;
+    PROTOCOL_FEES_ACCRUED.save(deps.storage, &accrued)?;
 
     Ok(Response::new()
-        .add_messages(messages)
-        .add_attribute("action", "unbond_liquidity")
-        .add_attribute("from", from)
-        .add_attribute("erth_token_amount", amount_erth.to_string())
-        .add_attribute("token_b_amount", amount_b.to_string())
-        .add_attribute("lp_token_amount", lp_token_amount.to_string()))
+        .add_message(send_message)
+        .add_attribute("action", "flush_fees")
+        .add_attribute("amount", flushed.amount.to_string())
+        .add_attribute("trade_volume", flushed.trade_volume.to_string()))
 }
 
-#[entry_point]
-pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> StdResult<Response> {
-    match msg.id {
-        INSTANTIATE_LP_TOKEN_REPLY_ID => handle_instantiate_lp_token_reply(deps, env, msg),
-        _ => Err(StdError::generic_err("Unknown reply ID")),
+// Builds the messages that deliver a swap's protocol fee according to the fee config
+// The fee stays in the input token only when that isn't the fee side and conversion is disabled
+fn protocol_fee_token(state: &State, input_token: &Addr, convert_fee: bool) -> Addr {
+    let fee_side = fee_side_token(state).0;
+    if input_token != &fee_side && !convert_fee {
+        input_token.clone()
+    } else {
+        fee_side
     }
 }
 
-fn handle_instantiate_lp_token_reply(
-    deps: DepsMut,
-    env: Env,
-    msg: Reply,
-) -> StdResult<Response> {
-    let mut state = STATE.load(deps.storage)?;
+fn route_protocol_fee(
+    storage: &mut dyn Storage,
+    state: &State,
+    fee_config: &FeeConfig,
+    fee_token: &Addr,
+    fee_amount: Uint128,
+    trade_volume: Uint128, // In the fee side token
+    constant_shape: bool, // Keep zero amount sends so the message count never varies
+) -> StdResult<Vec<CosmosMsg>> {
+    let fee_token_hash = token_hash(state, fee_token);
+    let native_fee = is_native_token(state, fee_token);
 
-    // Extract the SubMsgExecutionResponse from the reply
-    let res: SubMsgResponse = msg.result.unwrap();
+    let mut messages = vec![];
 
-    // Find the event that contains the contract address
-    let contract_address_event = res
-        .events
-        .iter()
-        .find(|event| event.ty == "instantiate");
+    match &fee_config.destination {
+        FeeDestination::Staking {} => {
+            // Accrued here and forwarded in one batch by `FlushFees`
+            let mut accrued = PROTOCOL_FEES_ACCRUED.may_load(storage)?.unwrap_or_default();
+            accrued.amount += fee_amount;
+            accrued.trade_volume += trade_volume;
+            PROTOCOL_FEES_ACCRUED.save(storage, &accrued)?;
+        }
+        FeeDestination::Accumulate {} => {
+            let mut accumulated = ACCUMULATED_FEES.may_load(storage)?.unwrap_or_default();
+            if fee_token == &state.token_erth_contract {
+                accumulated.erth += fee_amount;
+            } else {
+                accumulated.token_b += fee_amount;
+            }
+            ACCUMULATED_FEES.save(storage, &accumulated)?;
+        }
+        FeeDestination::Split { recipients } => {
+            let mut remaining = fee_amount;
+            for (i, recipient) in recipients.iter().enumerate() {
+                // The last recipient takes the rounding dust
+                let amount = if i == recipients.len() - 1 {
+                    remaining
+                } else {
+                    fee_amount * recipient.share / Uint128::from(10000u128)
+                };
+                remaining -= amount;
+
+                // Bank sends can't carry a zero amount, so those are skipped even in constant shape mode
+                if amount.is_zero() && (!constant_shape || native_fee) {
+                    continue;
+                }
+
+                // `SetFeeConfig` refuses recipient messages whenever fees can be native
+                if native_fee {
+                    messages.push(transfer_msg(state, fee_token, &recipient.address, amount)?);
+                    continue;
+                }
+
+                messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: fee_token.to_string(),
+                    code_hash: fee_token_hash.clone(),
+                    msg: to_binary(&snip20::HandleMsg::Send {
+                        recipient: recipient.address.to_string(),
+                        recipient_code_hash: recipient.code_hash.clone(),
+                        amount,
+                        msg: recipient.msg.clone(),
+                        memo: None,
+                        padding: None,
+                    })?,
+                    funds: vec![],
+                }));
+            }
+        }
+    }
 
-    // Ensure we found the instantiate event
-    let contract_address_event = match contract_address_event {
-        Some(event) => event,
-        None => return Err(StdError::generic_err("Failed to find instantiate event")),
+    Ok(messages)
+}
+
+// Returns the protocol fee to charge this trader and the discount (in basis points of the fee) applied
+fn effective_protocol_fee(
+    deps: Deps,
+    state: &State,
+    trader: &Addr,
+) -> StdResult<(Uint128, Uint128)> {
+    let discount = match FEE_DISCOUNTS.get(deps.storage, trader) {
+        // A per-address override always takes precedence
+        Some(discount) => discount,
+        None => {
+            let registered_discount = REGISTERED_USER_DISCOUNT
+                .may_load(deps.storage)?
+                .unwrap_or_default();
+
+            if !registered_discount.is_zero() && is_registered(deps, state, trader) {
+                registered_discount
+            } else {
+                Uint128::zero()
+            }
+        }
     };
 
-    // Find the attribute that contains the contract address
-    let contract_address_attr = contract_address_event
-        .attributes
-        .iter()
-        .find(|attr| attr.key == "contract_address");
+    let fee = state.protocol_fee * (Uint128::from(MAX_FEE_DISCOUNT) - discount)
+        / Uint128::from(MAX_FEE_DISCOUNT);
 
-    // Ensure we found the contract address attribute
-    let contract_address = match contract_address_attr {
-        Some(attr) => &attr.value,
-        None => return Err(StdError::generic_err("Failed to find contract address")),
-    };
+    Ok((fee, discount))
+}
 
-    // Validate the contract address
-    let lp_token_contract_addr = deps.api.addr_validate(contract_address)?;
+// The trader a swap is for, `user` when the router or a factory pool forwards it and the payer otherwise
+fn swap_trader(deps: Deps, from: &Addr, user: Option<&Addr>) -> StdResult<Addr> {
+    let Some(user) = user else {
+        return Ok(from.clone());
+    };
+    let forwarders = FORWARDERS.may_load(deps.storage)?.unwrap_or_default();
 
-    // Update the state with the LP token contract address
-    state.lp_token_contract = lp_token_contract_addr.clone();
-    STATE.save(deps.storage, &state)?;
+    if forwarders.router_contract.as_ref() == Some(from) || is_factory_pool(deps, &forwarders, from) {
+        Ok(user.clone())
+    } else {
+        Ok(from.clone())
+    }
+}
 
-    // Register this contract as a receiver for the LP token
-    let register_lp_msg = CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: lp_token_contract_addr.to_string(),
-        code_hash: state.lp_token_hash.clone(),
-        msg: to_binary(&snip20::HandleMsg::RegisterReceive {
-            code_hash: env.contract.code_hash.clone(),
-            padding: None,  // Optional padding
-        })?,
-        funds: vec![],
+// Like the registration check, a failing factory query counts as not indexed
+fn is_factory_pool(deps: Deps, forwarders: &Forwarders, address: &Addr) -> bool {
+    let Some(factory) = forwarders.factory_contract.as_ref() else {
+        return false;
+    };
+    let pool: StdResult<FactoryPoolResponse> = to_binary(&FactoryQueryMsg::PoolByContract {
+        contract: address.to_string(),
+    })
+    .and_then(|msg| {
+        deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr: factory.to_string(),
+            code_hash: forwarders.factory_hash.clone(),
+            msg,
+        }))
     });
 
-    // Register the contract as a receiver for the ERTH token
-    let register_erth_msg = CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.token_erth_contract.to_string(),
-        code_hash: state.token_erth_hash.clone(),
-        msg: to_binary(&snip20::HandleMsg::RegisterReceive {
-            code_hash: env.contract.code_hash.clone(),
-            padding: None,
-        })?,
-        funds: vec![],
-    });
+    pool.map(|res| &res.pool.contract == address).unwrap_or(false)
+}
 
-    // Register the contract as a receiver for the B token
-    let register_b_msg = CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.token_b_contract.to_string(),
-        code_hash: state.token_b_hash.clone(),
-        msg: to_binary(&snip20::HandleMsg::RegisterReceive {
-            code_hash: env.contract.code_hash.clone(),
-            padding: None,
-        })?,
-        funds: vec![],
+// A failing registration query is treated as unregistered so it can never block a swap
+fn is_registered(deps: Deps, state: &State, address: &Addr) -> bool {
+    let status: StdResult<RegistrationStatusResponse> = to_binary(&RegistrationQueryMsg::QueryRegistrationStatus {
+        address: address.to_string(),
+    })
+    .and_then(|msg| {
+        deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr: state.registration_contract.to_string(),
+            code_hash: state.registration_hash.clone(),
+            msg,
+        }))
     });
 
-
-    Ok(Response::new()
-        .add_message(register_lp_msg) // Add the registration message for the LP token
-        .add_message(register_erth_msg)
-        .add_message(register_b_msg)
-        .add_attribute("action", "instantiate_lp_token")
-        .add_attribute("lp_token_contract", lp_token_contract_addr.to_string()))
+    status.map(|res| res.registration_status).unwrap_or(false)
 }
 
 
 
-#[entry_point]
-pub fn migrate(deps: DepsMut, env: Env, msg: MigrateMsg) -> StdResult<Response> {
+pub fn execute_receive(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    sender: String,
+    from: String,
+    amount: Uint128,
+    msg: Binary,
+) -> Result<Response, StdError> {
+    let msg: ReceiveMsg = from_binary(&msg)?;
+
+    let _sender_addr = deps.api.addr_validate(&sender)?;
+    let from_addr = deps.api.addr_validate(&from)?;
+
     match msg {
-        MigrateMsg::Migrate {} => {
+        ReceiveMsg::Swap {min_received, hop, user, padding: _} => receive_swap(deps, env, info.sender, from_addr, amount, min_received, hop, user),
+        ReceiveMsg::UnbondLiquidity { padding: _ } => recieve_unbond_liquidity(deps, env, info, from_addr, amount),
+        ReceiveMsg::ErthBuybackSwap { padding: _ } => receive_erth_buyback_swap(deps, env, info, amount),
+        ReceiveMsg::AnmlBuybackSwap { padding: _ } => receive_anml_buyback_swap(deps, env, info, amount),
+        ReceiveMsg::PlaceLimitOrder { price, min_out, padding: _ } =>
+            receive_place_limit_order(deps, info, from_addr, amount, price, min_out),
+        ReceiveMsg::CreateDca { amount_per_slice, min_out_per_slice, interval, padding: _ } =>
+            receive_create_dca(deps, env, info, from_addr, amount, amount_per_slice, min_out_per_slice, interval),
+        ReceiveMsg::BatchSwap { min_received, padding: _ } =>
+            receive_batch_swap(deps, env, info, from_addr, amount, min_received),
+    }
+}
 
-            // Load the state
-            let state = STATE.load(deps.storage)?;
+fn receive_place_limit_order(
+    deps: DepsMut,
+    info: MessageInfo,
+    from: Addr,
+    amount: Uint128,
+    price: Decimal256,
+    min_out: Uint128,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
 
-            // Register this contract as a receiver for ERTH
-            let register_erth_msg = CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: state.token_erth_contract.to_string(),
-                code_hash: state.token_erth_hash,
-                msg: to_binary(&snip20::HandleMsg::RegisterReceive {
-                    code_hash: env.contract.code_hash.clone(),
-                    padding: None,  // Optional padding
-                })?,
-                funds: vec![],
-            });
+    if info.sender != state.token_erth_contract && info.sender != state.token_b_contract {
+        return Err(StdError::generic_err("Invalid input token"));
+    }
+    if amount.is_zero() || price.is_zero() {
+        return Err(StdError::generic_err("Orders need a nonzero amount and price"));
+    }
 
-            let register_b_msg = CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: state.token_b_contract.to_string(),
-                code_hash: state.token_b_hash,
-                msg: to_binary(&snip20::HandleMsg::RegisterReceive {
-                    code_hash: env.contract.code_hash.clone(),
-                    padding: None,  // Optional padding
-                })?,
-                funds: vec![],
-            });
+    let id = NEXT_LIMIT_ORDER_ID.may_load(deps.storage)?.unwrap_or(1);
+    NEXT_LIMIT_ORDER_ID.save(deps.storage, &(id + 1))?;
 
-            let register_lp_msg = CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: state.lp_token_contract.to_string(),
-                code_hash: state.lp_token_hash,
-                msg: to_binary(&snip20::HandleMsg::RegisterReceive {
-                    code_hash: env.contract.code_hash.clone(),
-                    padding: None,  // Optional padding
-                })?,
-                funds: vec![],
-            });
+    insert_limit_order(deps.storage, id, &LimitOrder {
+        owner: from,
+        input_token: info.sender,
+        amount,
+        price,
+        min_out,
+    })?;
 
-            Ok(Response::new()
-                .add_message(register_erth_msg)
-                .add_message(register_b_msg)
-                .add_message(register_lp_msg)
-                .add_attribute("action", "migrate"))
-        }
-    }
+    Ok(Response::new()
+        .add_attribute("action", "place_limit_order")
+        .add_attribute("order_id", id.to_string()))
 }
 
+pub fn execute_cancel_limit_order(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: u64,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+    let order = LIMIT_ORDERS
+        .get(deps.storage, &id)
+        .ok_or_else(|| StdError::generic_err("Order not found"))?;
 
-
-#[entry_point]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::QueryState {} => to_binary(&query_state(deps)?),
-        QueryMsg::QueryDeposit { address } => {
-            let address = deps.api.addr_validate(&address)?;
-            to_binary(&query_deposit(deps, address)?)
-        },
+    if info.sender != order.owner {
+        return Err(StdError::generic_err("unauthorized"));
     }
+
+    remove_limit_order(deps.storage, id, &order)?;
+
+    Ok(Response::new()
+        .add_message(transfer_msg(&state, &order.input_token, &order.owner, order.amount)?)
+        .add_attribute("action", "cancel_limit_order")
+        .add_attribute("order_id", id.to_string()))
 }
 
-pub fn query_swap(
-    deps: Deps,
-    input_amount: Uint128,
-    input_token: Addr,
-) -> StdResult<QuerySwapResponse> {
-    // Load state
+// Open to anyone, a keeper only pays gas for the orders that actually fill
+pub fn execute_orders(mut deps: DepsMut, env: Env, ids: Vec<u64>) -> Result<Response, StdError> {
+    if ids.len() > MAX_EXECUTE_ORDERS {
+        return Err(StdError::generic_err(format!("At most {} orders per call", MAX_EXECUTE_ORDERS)));
+    }
+
+    let mut messages = settle_due_batch(&mut deps, &env)?.unwrap_or_default();
     let mut state = STATE.load(deps.storage)?;
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
+    let mut curve = load_curve(deps.storage, env.block.time.seconds())?;
 
-    // Calculate the swap details without creating messages
-    let (protocol_fee_amount, output_amount, _, _, _) =
-        calculate_swap(&mut state, input_amount, &input_token)?;
+    let mut filled = vec![];
+    for id in ids {
+        let Some(order) = LIMIT_ORDERS.get(deps.storage, &id) else {
+            continue;
+        };
 
-    Ok(QuerySwapResponse {
-        protocol_fee_amount,
-        output_amount,
-    })
+        if let Some(fill_messages) = try_fill_order(&mut deps, &env, &mut state, curve.as_mut(), id, &order)? {
+            messages.extend(fill_messages);
+            filled.push(id.to_string());
+        }
+    }
+
+    STATE.save(deps.storage, &state)?;
+    curve.save(deps.storage)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "execute_orders")
+        .add_attribute("filled", filled.join(",")))
 }
 
-fn query_state(deps: Deps) -> StdResult<QueryStateResponse> {
-    let state = STATE.load(deps.storage)?;
-    Ok(QueryStateResponse { state })
+fn insert_limit_order(storage: &mut dyn Storage, id: u64, order: &LimitOrder) -> StdResult<()> {
+    let book = ORDER_BOOK.add_suffix(order.input_token.as_bytes());
+    let mut entries = book.may_load(storage)?.unwrap_or_default();
+    let entry = (order.price, id);
+    entries.insert(entries.partition_point(|other| *other < entry), entry);
+    book.save(storage, &entries)?;
+
+    LIMIT_ORDERS.insert(storage, &id, order)
 }
 
-pub fn query_deposit(deps: Deps, address: Addr) -> StdResult<UnclaimedDepositResponse> {
-    // Query deposit amount
-    let unclaimed_deposit = DEPOSITS
-        .get(deps.storage, &address)
-        .unwrap_or_else(Uint128::zero);
+fn remove_limit_order(storage: &mut dyn Storage, id: u64, order: &LimitOrder) -> StdResult<()> {
+    let book = ORDER_BOOK.add_suffix(order.input_token.as_bytes());
+    let mut entries = book.may_load(storage)?.unwrap_or_default();
+    entries.retain(|(_, other)| *other != id);
+    book.save(storage, &entries)?;
 
-    let unclaimed_deposit_response = UnclaimedDepositResponse {
-        unclaimed_deposit,
+    LIMIT_ORDERS.remove(storage, &id)
+}
+
+// Whether the spot price of `input_token` has reached `price`
+fn order_crossed(state: &State, curve: &dyn Curve, input_token: &Addr, price: Decimal256) -> StdResult<bool> {
+    let (input_side, input_reserve, output_reserve) = if input_token == &state.token_erth_contract {
+        (PairSide::TokenErth, state.token_erth_reserve, state.token_b_reserve)
+    } else {
+        (PairSide::TokenB, state.token_b_reserve, state.token_erth_reserve)
     };
 
-    Ok(unclaimed_deposit_response)
+    Ok(!input_reserve.is_zero()
+        && !output_reserve.is_zero()
+        && curve.spot_price(input_side, input_reserve, output_reserve)? >= price)
+}
+
+// Fills resting orders that sell `token`, lowest limit price first, stopping at the first one the
+// price hasn't reached
+fn fill_crossed_orders(
+    deps: &mut DepsMut,
+    env: &Env,
+    state: &mut State,
+    curve: &mut dyn Curve,
+    token: &Addr,
+) -> StdResult<Vec<CosmosMsg>> {
+    let entries = ORDER_BOOK.add_suffix(token.as_bytes()).may_load(deps.storage)?.unwrap_or_default();
+
+    let mut messages = vec![];
+    for (price, id) in entries.into_iter().take(MAX_ORDERS_CHECKED_PER_SWAP) {
+        if !order_crossed(state, curve, token, price)? {
+            break;
+        }
+
+        let order = LIMIT_ORDERS
+            .get(deps.storage, &id)
+            .ok_or_else(|| StdError::generic_err("Order book out of sync"))?;
+        if let Some(fill_messages) = try_fill_order(deps, env, state, curve, id, &order)? {
+            messages.extend(fill_messages);
+        }
+    }
+
+    Ok(messages)
+}
+
+// Sells the order into the pool once the spot price of its token reaches the limit and the
+// output covers `min_out`. Returns the fee and payout messages, or `None` leaving the order resting.
+fn try_fill_order(
+    deps: &mut DepsMut,
+    env: &Env,
+    state: &mut State,
+    curve: &mut dyn Curve,
+    id: u64,
+    order: &LimitOrder,
+) -> StdResult<Option<Vec<CosmosMsg>>> {
+    if !order_crossed(state, curve, &order.input_token, order.price)? {
+        return Ok(None);
+    }
+
+    let (input_side, input_reserve, output_reserve) = if order.input_token == state.token_erth_contract {
+        (PairSide::TokenErth, state.token_erth_reserve, state.token_b_reserve)
+    } else {
+        (PairSide::TokenB, state.token_b_reserve, state.token_erth_reserve)
+    };
+
+    let (protocol_fee, _) = effective_protocol_fee(deps.as_ref(), state, &order.owner)?;
+    let fee_config = FEE_CONFIG.may_load(deps.storage)?.unwrap_or_default();
+    let convert_fee = fee_config.fee_token == FeeToken::FeeSide;
+
+    // Quote before touching anything, an order the pool can't fill well enough keeps resting
+    let amount_after_fee = order.amount - order.amount * protocol_fee / Uint128::from(10000u128);
+    match curve.output_amount(deps.storage, input_side, amount_after_fee, input_reserve, output_reserve) {
+        Ok(quote) if quote >= order.min_out => {}
+        _ => return Ok(None),
+    }
+
+    let (protocol_fee_amount, output_amount, output_addr, _, trade_volume) =
+        calculate_swap(deps.storage, state, curve, order.amount, &order.input_token, protocol_fee, convert_fee)?;
+
+    let constant_shape = CONSTANT_SHAPE_RESPONSES.may_load(deps.storage)?.unwrap_or(false);
+    let mut messages = route_protocol_fee(
+        deps.storage,
+        state,
+        &fee_config,
+        &protocol_fee_token(state, &order.input_token, convert_fee),
+        protocol_fee_amount,
+        trade_volume,
+        constant_shape,
+    )?;
+    messages.push(transfer_msg(state, &output_addr, &order.owner, output_amount)?);
+
+    record_user_action(deps.storage, &order.owner, &env.block, UserAction::Swap {
+        input_token: order.input_token.clone(),
+        input_amount: order.amount,
+        output_token: output_addr,
+        output_amount,
+        protocol_fee_amount,
+    })?;
+    remove_limit_order(deps.storage, id, order)?;
+
+    Ok(Some(messages))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn receive_create_dca(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    from: Addr,
+    amount: Uint128,
+    amount_per_slice: Uint128,
+    min_out_per_slice: Uint128,
+    interval: u64,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+
+    if info.sender != state.token_erth_contract && info.sender != state.token_b_contract {
+        return Err(StdError::generic_err("Invalid input token"));
+    }
+    if amount.is_zero() || amount_per_slice.is_zero() || interval == 0 {
+        return Err(StdError::generic_err("DCA needs a nonzero amount, slice size and interval"));
+    }
+
+    let id = NEXT_DCA_ID.may_load(deps.storage)?.unwrap_or(1);
+    NEXT_DCA_ID.save(deps.storage, &(id + 1))?;
+
+    DCA_SCHEDULES.insert(deps.storage, &id, &DcaSchedule {
+        owner: from,
+        input_token: info.sender,
+        remaining: amount,
+        amount_per_slice,
+        min_out_per_slice,
+        interval,
+        next_execution: env.block.time.seconds(),
+    })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "create_dca")
+        .add_attribute("dca_id", id.to_string()))
+}
+
+pub fn execute_cancel_dca(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: u64,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+    let schedule = DCA_SCHEDULES
+        .get(deps.storage, &id)
+        .ok_or_else(|| StdError::generic_err("DCA schedule not found"))?;
+
+    if info.sender != schedule.owner {
+        return Err(StdError::generic_err("unauthorized"));
+    }
+
+    DCA_SCHEDULES.remove(deps.storage, &id)?;
+
+    Ok(Response::new()
+        .add_message(transfer_msg(&state, &schedule.input_token, &schedule.owner, schedule.remaining)?)
+        .add_attribute("action", "cancel_dca")
+        .add_attribute("dca_id", id.to_string()))
+}
+
+pub fn execute_dca(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    id: u64,
+) -> Result<Response, StdError> {
+    let mut schedule = DCA_SCHEDULES
+        .get(deps.storage, &id)
+        .ok_or_else(|| StdError::generic_err("DCA schedule not found"))?;
+
+    let mut messages = settle_due_batch(&mut deps, &env)?.unwrap_or_default();
+    let mut state = STATE.load(deps.storage)?;
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
+    let mut curve = load_curve(deps.storage, env.block.time.seconds())?;
+
+    let (slice_messages, amount) = run_dca_slice(&mut deps, &env, &mut state, curve.as_mut(), &info.sender, &mut schedule)?;
+    messages.extend(slice_messages);
+
+    if schedule.remaining.is_zero() {
+        DCA_SCHEDULES.remove(deps.storage, &id)?;
+    } else {
+        DCA_SCHEDULES.insert(deps.storage, &id, &schedule)?;
+    }
+
+    STATE.save(deps.storage, &state)?;
+    curve.save(deps.storage)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "execute_dca")
+        .add_attribute("dca_id", id.to_string())
+        .add_attribute("amount", amount.to_string()))
+}
+
+// Sells one slice, capped at what is left, and moves the schedule on to the next interval after
+// now. Missed intervals are skipped so a late keeper can't sell several slices as one lump. The
+// keeper's tip comes out of the input. Returns the messages and the amount taken from the schedule.
+fn run_dca_slice(
+    deps: &mut DepsMut,
+    env: &Env,
+    state: &mut State,
+    curve: &mut dyn Curve,
+    keeper: &Addr,
+    schedule: &mut DcaSchedule,
+) -> StdResult<(Vec<CosmosMsg>, Uint128)> {
+    let now = env.block.time.seconds();
+    if now < schedule.next_execution {
+        return Err(StdError::generic_err("No DCA slice is due yet"));
+    }
+
+    let elapsed_intervals = 1 + (now - schedule.next_execution) / schedule.interval;
+    let amount = schedule.amount_per_slice.min(schedule.remaining);
+    let tip = amount * Uint128::from(DCA_KEEPER_TIP) / Uint128::from(10000u128);
+    let min_out = schedule.min_out_per_slice.multiply_ratio(amount, schedule.amount_per_slice);
+
+    let (protocol_fee, _) = effective_protocol_fee(deps.as_ref(), state, &schedule.owner)?;
+    let fee_config = FEE_CONFIG.may_load(deps.storage)?.unwrap_or_default();
+    let convert_fee = fee_config.fee_token == FeeToken::FeeSide;
+
+    let (protocol_fee_amount, output_amount, output_addr, _, trade_volume) =
+        calculate_swap(deps.storage, state, curve, amount - tip, &schedule.input_token, protocol_fee, convert_fee)?;
+
+    if output_amount < min_out {
+        return Err(StdError::generic_err("DCA output is less than the minimum per slice"));
+    }
+
+    let constant_shape = CONSTANT_SHAPE_RESPONSES.may_load(deps.storage)?.unwrap_or(false);
+    let mut messages = route_protocol_fee(
+        deps.storage,
+        state,
+        &fee_config,
+        &protocol_fee_token(state, &schedule.input_token, convert_fee),
+        protocol_fee_amount,
+        trade_volume,
+        constant_shape,
+    )?;
+    messages.push(transfer_msg(state, &output_addr, &schedule.owner, output_amount)?);
+    if !tip.is_zero() {
+        messages.push(transfer_msg(state, &schedule.input_token, keeper, tip)?);
+    }
+
+    record_user_action(deps.storage, &schedule.owner, &env.block, UserAction::Swap {
+        input_token: schedule.input_token.clone(),
+        input_amount: amount - tip,
+        output_token: output_addr,
+        output_amount,
+        protocol_fee_amount,
+    })?;
+
+    schedule.remaining -= amount;
+    schedule.next_execution += elapsed_intervals * schedule.interval;
+
+    Ok((messages, amount))
+}
+
+fn receive_batch_swap(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    from: Addr,
+    amount: Uint128,
+    min_received: Option<Uint128>,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+
+    if info.sender != state.token_erth_contract && info.sender != state.token_b_contract {
+        return Err(StdError::generic_err("Invalid input token"));
+    }
+    if amount.is_zero() {
+        return Err(StdError::generic_err("Batch swaps need a nonzero amount"));
+    }
+    let window = BATCH_WINDOW.may_load(deps.storage)?.unwrap_or_default();
+    if window == 0 {
+        return Err(StdError::generic_err("Batch swaps are disabled"));
+    }
+
+    // A batch whose window has closed is settled before this intent opens the next one
+    let messages = settle_due_batch(&mut deps, &env)?.unwrap_or_default();
+
+    let mut batch = PENDING_BATCH.may_load(deps.storage)?.unwrap_or(PendingBatch {
+        start_height: env.block.height,
+        intents: vec![],
+    });
+    if batch.intents.len() >= MAX_BATCH_INTENTS {
+        return Err(StdError::generic_err("The pending batch is full"));
+    }
+    if batch.intents.iter().filter(|intent| intent.owner == from).count() >= MAX_BATCH_INTENTS_PER_OWNER {
+        return Err(StdError::generic_err(format!(
+            "At most {} intents per address in a batch",
+            MAX_BATCH_INTENTS_PER_OWNER
+        )));
+    }
+
+    // Valued in the fee side token at the spot price, as for trade volume
+    let min_intent = BATCH_MIN_INTENT.may_load(deps.storage)?.unwrap_or_default();
+    if !min_intent.is_zero() {
+        let state = STATE.load(deps.storage)?;
+        let side = if info.sender == state.token_erth_contract { PairSide::TokenErth } else { PairSide::TokenB };
+        let value = if side == state.fee_side {
+            amount
+        } else {
+            let (price_erth, price_b) = spot_prices(&state, load_curve(deps.storage, env.block.time.seconds())?.as_ref())?;
+            apply_price(amount, if side == PairSide::TokenErth { price_erth } else { price_b })?
+        };
+        if value < min_intent {
+            return Err(StdError::generic_err(format!(
+                "Batch intents must be worth at least {} of the fee side token",
+                min_intent
+            )));
+        }
+    }
+
+    let (protocol_fee, _) = effective_protocol_fee(deps.as_ref(), &state, &from)?;
+    batch.intents.push(BatchIntent {
+        owner: from,
+        input_token: info.sender,
+        amount,
+        protocol_fee,
+        min_out: min_received.unwrap_or_default(),
+    });
+    PENDING_BATCH.save(deps.storage, &batch)?;
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "batch_swap")
+        .add_attribute("settles_at", (batch.start_height + window).to_string()))
+}
+
+pub fn execute_settle_batch(mut deps: DepsMut, env: Env) -> Result<Response, StdError> {
+    let messages = settle_due_batch(&mut deps, &env)?
+        .ok_or_else(|| StdError::generic_err("No batch is due for settlement"))?;
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "settle_batch"))
+}
+
+pub fn execute_claim_batch(deps: DepsMut, info: MessageInfo) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+    let claim = BATCH_CLAIMS
+        .get(deps.storage, &info.sender)
+        .ok_or_else(|| StdError::generic_err("Nothing to claim"))?;
+    BATCH_CLAIMS.remove(deps.storage, &info.sender)?;
+
+    let mut messages = vec![];
+    for (token, amount) in [(&state.token_erth_contract, claim.amount_erth), (&state.token_b_contract, claim.amount_b)] {
+        if !amount.is_zero() {
+            messages.push(transfer_msg(&state, token, &info.sender, amount)?);
+        }
+    }
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "claim_batch")
+        .add_attribute("amount_erth", claim.amount_erth.to_string())
+        .add_attribute("amount_b", claim.amount_b.to_string()))
+}
+
+// Takes the sender's intents out of the pending batch and sends their deposits back
+pub fn execute_cancel_batch_swaps(deps: DepsMut, info: MessageInfo) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+    let batch = PENDING_BATCH
+        .may_load(deps.storage)?
+        .ok_or_else(|| StdError::generic_err("No batch is pending"))?;
+
+    let (cancelled, intents): (Vec<BatchIntent>, Vec<BatchIntent>) =
+        batch.intents.into_iter().partition(|intent| intent.owner == info.sender);
+    if cancelled.is_empty() {
+        return Err(StdError::generic_err("No intents to cancel"));
+    }
+
+    if intents.is_empty() {
+        PENDING_BATCH.remove(deps.storage);
+    } else {
+        PENDING_BATCH.save(deps.storage, &PendingBatch { start_height: batch.start_height, intents })?;
+    }
+
+    let total = |token: &Addr| -> Uint128 {
+        cancelled.iter().filter(|intent| &intent.input_token == token).map(|intent| intent.amount).sum()
+    };
+    let mut messages = vec![];
+    for token in [&state.token_erth_contract, &state.token_b_contract] {
+        let amount = total(token);
+        if !amount.is_zero() {
+            messages.push(transfer_msg(&state, token, &info.sender, amount)?);
+        }
+    }
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "cancel_batch_swaps")
+        .add_attribute("intents", cancelled.len().to_string()))
+}
+
+// Settles the pending batch if its window has closed, returning the protocol fee messages. Swaps
+// call this first, so the batch always clears before any swap in its settling block.
+fn settle_due_batch(deps: &mut DepsMut, env: &Env) -> StdResult<Option<Vec<CosmosMsg>>> {
+    let Some(batch) = PENDING_BATCH.may_load(deps.storage)? else {
+        return Ok(None);
+    };
+    let window = BATCH_WINDOW.may_load(deps.storage)?.unwrap_or_default();
+    if env.block.height < batch.start_height + window {
+        return Ok(None);
+    }
+
+    let mut state = STATE.load(deps.storage)?;
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
+    let mut curve = load_curve(deps.storage, env.block.time.seconds())?;
+
+    let messages = settle_batch(deps, env, &mut state, curve.as_mut(), &batch)?;
+    PENDING_BATCH.remove(deps.storage);
+
+    STATE.save(deps.storage, &state)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
+
+    Ok(Some(messages))
+}
+
+// Clears the batch into `BATCH_CLAIMS`. A batch that can't be cleared has every intent refunded
+// instead, so it doesn't hold up the batches after it.
+fn settle_batch(
+    deps: &mut DepsMut,
+    env: &Env,
+    state: &mut State,
+    curve: &mut dyn Curve,
+    batch: &PendingBatch,
+) -> StdResult<Vec<CosmosMsg>> {
+    match clear_pending_batch(deps.storage, state, curve, batch) {
+        Ok(settlement) => {
+            let messages = apply_batch_settlement(deps, env, state, batch, settlement)?;
+            curve.save(deps.storage)?;
+            Ok(messages)
+        }
+        Err(_) => {
+            for intent in &batch.intents {
+                credit_batch_claim(deps.storage, state, &intent.owner, &intent.input_token, intent.amount)?;
+            }
+            Ok(vec![])
+        }
+    }
+}
+
+// What clearing a batch pays out, worked out before anything is written
+struct BatchSettlement {
+    fills: Vec<(usize, Addr, Uint128, Uint128)>, // Intent, output token, output and protocol fee
+    refunds: Vec<usize>,
+    reserve_erth: Uint128,
+    reserve_b: Uint128,
+    fee_side_fees: Uint128,
+    other_side_fees: Uint128,
+    trade_volume: Uint128,
+}
+
+// Clears every intent at one price, only moving the curve. Protocol fees come off the inputs as in
+// a regular swap, and fees to be converted join the batch as one more order. Intents that would
+// miss their minimum are refunded and the rest cleared again without them.
+fn clear_pending_batch(
+    storage: &dyn Storage,
+    state: &State,
+    curve: &mut dyn Curve,
+    batch: &PendingBatch,
+) -> StdResult<BatchSettlement> {
+    let fee_config = FEE_CONFIG.may_load(storage)?.unwrap_or_default();
+    let convert_fee = fee_config.fee_token == FeeToken::FeeSide;
+    let other_side = match state.fee_side {
+        PairSide::TokenErth => PairSide::TokenB,
+        PairSide::TokenB => PairSide::TokenErth,
+    };
+    let (price_erth, price_b) = spot_prices(state, curve)?;
+
+    let intents = &batch.intents;
+    let sides: Vec<PairSide> = intents
+        .iter()
+        .map(|intent| {
+            if intent.input_token == state.token_erth_contract {
+                PairSide::TokenErth
+            } else {
+                PairSide::TokenB
+            }
+        })
+        .collect();
+    let fees: Vec<Uint128> = intents
+        .iter()
+        .map(|intent| intent.amount * intent.protocol_fee / Uint128::from(10000u128))
+        .collect();
+    let converts_fee = |i: usize| convert_fee && sides[i] != state.fee_side;
+
+    let mut filled = vec![true; intents.len()];
+    let (indices, clearing) = loop {
+        let indices: Vec<usize> = (0..intents.len()).filter(|&i| filled[i]).collect();
+        let mut orders: Vec<BatchOrder> = indices
+            .iter()
+            .map(|&i| BatchOrder { side: sides[i], amount: intents[i].amount - fees[i] })
+            .collect();
+        orders.push(BatchOrder {
+            side: other_side,
+            amount: indices.iter().filter(|&&i| converts_fee(i)).map(|&i| fees[i]).sum(),
+        });
+
+        let clearing = clear_batch(storage, curve, state.token_erth_reserve, state.token_b_reserve, &orders)?;
+
+        let mut missed = false;
+        for (&i, output) in indices.iter().zip(&clearing.outputs) {
+            if *output < intents[i].min_out {
+                filled[i] = false;
+                missed = true;
+            }
+        }
+        if !missed {
+            break (indices, clearing);
+        }
+    };
+
+    if !clearing.net_in.is_zero() {
+        let (reserve_in, reserve_out) = match clearing.net_side {
+            PairSide::TokenErth => (state.token_erth_reserve, state.token_b_reserve),
+            PairSide::TokenB => (state.token_b_reserve, state.token_erth_reserve),
+        };
+        if curve.apply_swap(storage, clearing.net_side, clearing.net_in, reserve_in, reserve_out)? != clearing.net_out {
+            return Err(StdError::generic_err("Batch clearing diverged from the curve"));
+        }
+    }
+
+    let converted_fees = clearing.outputs[indices.len()];
+    let converted_total: Uint128 = indices.iter().filter(|&&i| converts_fee(i)).map(|&i| fees[i]).sum();
+
+    let (mut erth_in, mut erth_out) = (Uint128::zero(), Uint128::zero());
+    let (mut b_in, mut b_out) = (Uint128::zero(), Uint128::zero());
+    let mut fee_side_fees = converted_fees;
+    let mut other_side_fees = Uint128::zero();
+    let mut trade_volume = Uint128::zero();
+    let mut fills = vec![];
+
+    for (&i, &output) in indices.iter().zip(&clearing.outputs) {
+        let intent = &intents[i];
+        let net = intent.amount - fees[i];
+        let output_token = if sides[i] == PairSide::TokenErth {
+            erth_in += net;
+            b_out += output;
+            state.token_b_contract.clone()
+        } else {
+            b_in += net;
+            erth_out += output;
+            state.token_erth_contract.clone()
+        };
+
+        // Trade volume in the fee side token at the spot price before the batch, as for swaps
+        let protocol_fee_amount = if sides[i] == state.fee_side {
+            fee_side_fees += fees[i];
+            trade_volume += intent.amount;
+            fees[i]
+        } else {
+            let price = if sides[i] == PairSide::TokenErth { price_erth } else { price_b };
+            trade_volume += apply_price(intent.amount, price)?;
+            if convert_fee {
+                converted_fees.multiply_ratio(fees[i], converted_total)
+            } else {
+                other_side_fees += fees[i];
+                fees[i]
+            }
+        };
+
+        fills.push((i, output_token, output, protocol_fee_amount));
+    }
+
+    // The converted fees are paid out of the batch like any other order
+    match other_side {
+        PairSide::TokenErth => erth_in += converted_total,
+        PairSide::TokenB => b_in += converted_total,
+    }
+    match state.fee_side {
+        PairSide::TokenErth => erth_out += converted_fees,
+        PairSide::TokenB => b_out += converted_fees,
+    }
+
+    // Whatever the batch took in and didn't pay out, the net trade and the rounding dust, joins the reserves
+    Ok(BatchSettlement {
+        fills,
+        refunds: (0..intents.len()).filter(|&i| !filled[i]).collect(),
+        reserve_erth: state.token_erth_reserve.checked_add(erth_in)?.checked_sub(erth_out)?,
+        reserve_b: state.token_b_reserve.checked_add(b_in)?.checked_sub(b_out)?,
+        fee_side_fees,
+        other_side_fees,
+        trade_volume,
+    })
+}
+
+// Credits the outputs and refunds of a cleared batch, returning the protocol fee messages
+fn apply_batch_settlement(
+    deps: &mut DepsMut,
+    env: &Env,
+    state: &mut State,
+    batch: &PendingBatch,
+    settlement: BatchSettlement,
+) -> StdResult<Vec<CosmosMsg>> {
+    let fee_config = FEE_CONFIG.may_load(deps.storage)?.unwrap_or_default();
+    let convert_fee = fee_config.fee_token == FeeToken::FeeSide;
+
+    for (i, output_token, output, protocol_fee_amount) in settlement.fills {
+        let intent = &batch.intents[i];
+        credit_batch_claim(deps.storage, state, &intent.owner, &output_token, output)?;
+        record_user_action(deps.storage, &intent.owner, &env.block, UserAction::Swap {
+            input_token: intent.input_token.clone(),
+            input_amount: intent.amount,
+            output_token,
+            output_amount: output,
+            protocol_fee_amount,
+        })?;
+    }
+
+    for i in settlement.refunds {
+        let intent = &batch.intents[i];
+        credit_batch_claim(deps.storage, state, &intent.owner, &intent.input_token, intent.amount)?;
+    }
+
+    state.token_erth_reserve = settlement.reserve_erth;
+    state.token_b_reserve = settlement.reserve_b;
+
+    let constant_shape = CONSTANT_SHAPE_RESPONSES.may_load(deps.storage)?.unwrap_or(false);
+    let mut messages = route_protocol_fee(
+        deps.storage,
+        state,
+        &fee_config,
+        &fee_side_token(state).0,
+        settlement.fee_side_fees,
+        settlement.trade_volume,
+        constant_shape,
+    )?;
+    if !convert_fee {
+        messages.extend(route_protocol_fee(
+            deps.storage,
+            state,
+            &fee_config,
+            &other_side_token(state).0,
+            settlement.other_side_fees,
+            Uint128::zero(),
+            constant_shape,
+        )?);
+    }
+
+    Ok(messages)
+}
+
+fn credit_batch_claim(
+    storage: &mut dyn Storage,
+    state: &State,
+    owner: &Addr,
+    token: &Addr,
+    amount: Uint128,
+) -> StdResult<()> {
+    let mut claim = BATCH_CLAIMS.get(storage, owner).unwrap_or_default();
+    if token == &state.token_erth_contract {
+        claim.amount_erth += amount;
+    } else {
+        claim.amount_b += amount;
+    }
+    BATCH_CLAIMS.insert(storage, owner, &claim)
+}
+
+pub fn execute_swap_native(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    min_received: Option<Uint128>,
+    hop: Option<HopDetails>,
+) -> Result<Response, StdError> {
+    let state = STATE.load(deps.storage)?;
+    let amount = native_amount(&state, &info)?;
+
+    receive_swap(deps, env, state.token_b_contract, info.sender, amount, min_received, hop, None)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn receive_swap(
+    mut deps: DepsMut,
+    env: Env,
+    input_token: Addr,
+    mut from: Addr,
+    amount: Uint128,
+    min_received: Option<Uint128>,
+    hop: Option<HopDetails>,
+    user: Option<Addr>,
+) -> Result<Response, StdError> {
+    // A due batch clears ahead of the block's first swap, so swaps can't hold it back or trade in
+    // front of it
+    let mut messages = settle_due_batch(&mut deps, &env)?.unwrap_or_default();
+
+    // Load state
+    let mut state = STATE.load(deps.storage)?;
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
+    let mut curve = load_curve(deps.storage, env.block.time.seconds())?;
+    let input_amount = amount;
+
+    // `user` names who gets the output. The discount and the history entry only follow it when a
+    // trusted forwarder paid in, anyone else could name a discounted address.
+    let recipient = user.clone().unwrap_or_else(|| from.clone());
+    let trader = swap_trader(deps.as_ref(), &from, user.as_ref())?;
+    let (protocol_fee, fee_discount) = effective_protocol_fee(deps.as_ref(), &state, &trader)?;
+
+    let fee_config = FEE_CONFIG.may_load(deps.storage)?.unwrap_or_default();
+    let convert_fee = fee_config.fee_token == FeeToken::FeeSide;
+    let constant_shape = CONSTANT_SHAPE_RESPONSES.may_load(deps.storage)?.unwrap_or(false);
+
+    // Calculate the swap details and update reserves directly, including trade volume in the fee side token
+    let (protocol_fee_amount, output_amount, output_addr, output_hash, trade_volume) =
+        calculate_swap(deps.storage, &mut state, curve.as_mut(), input_amount, &input_token, protocol_fee, convert_fee)?;
+
+    record_user_action(deps.storage, &trader, &env.block, UserAction::Swap {
+        input_token: input_token.clone(),
+        input_amount,
+        output_token: output_addr.clone(),
+        output_amount,
+        protocol_fee_amount,
+    })?;
+
+    let fee_token = protocol_fee_token(&state, &input_token, convert_fee);
+
+    // Handle the protocol fee according to the configured destination
+    messages.extend(route_protocol_fee(
+        deps.storage,
+        &state,
+        &fee_config,
+        &fee_token,
+        protocol_fee_amount,
+        trade_volume,
+        constant_shape,
+    )?);
+
+    // Check if hop details are provided
+    if let Some(hop_details) = hop {
+        if is_native_token(&state, &output_addr) {
+            return Err(StdError::generic_err("Cannot hop with a native token output"));
+        }
+
+        // Try to validate the hop contract address
+        let hop_addr = deps.api.addr_validate(&hop_details.contract)?;
+
+        let hop_msg = snip20::HandleMsg::Send {
+            recipient: hop_addr.to_string(),
+            recipient_code_hash: Some(hop_details.hash.clone()),
+            amount: output_amount,
+            msg: Some(to_binary(&SendMessage::Swap {
+                min_received,
+                user: recipient.clone(),
+            })?),
+            memo: None,
+            padding: None,
+        };
+
+        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: output_addr.to_string(),
+            code_hash: output_hash.clone(),
+            msg: to_binary(&hop_msg)?,
+            funds: vec![],
+        }));
+
+    } else {
+        // Check against minimum received amount
+        if let Some(min) = min_received {
+            if output_amount < min {
+                return Err(StdError::generic_err("Output amount is less than the minimum received amount"));
+            }
+        }
+
+        // Check for user parameter in the case of the second part of the hop
+        if let Some(user_addr) = user {
+            from = user_addr;
+        }
+        
+
+        // A native output always goes out through the bank. Otherwise, in constant shape mode the
+        // payout is a `Send` like the hop path, and a plain transfer if not.
+        let payout_msg = if is_native_token(&state, &output_addr) || !constant_shape {
+            transfer_msg(&state, &output_addr, &from, output_amount)?
+        } else {
+            let send_msg = snip20::HandleMsg::Send {
+                recipient: from.to_string(),
+                recipient_code_hash: None,
+                amount: output_amount,
+                msg: None,
+                memo: None,
+                padding: None,
+            };
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: output_addr.to_string(),
+                code_hash: output_hash,
+                msg: to_binary(&send_msg)?,
+                funds: vec![],
+            })
+        };
+
+        messages.push(payout_msg);
+    }
+
+    // Orders selling the token just bought out of the pool may have crossed their price
+    messages.extend(fill_crossed_orders(&mut deps, &env, &mut state, curve.as_mut(), &output_addr)?);
+
+    // Save the updated state
+    STATE.save(deps.storage, &state)?;
+    curve.save(deps.storage)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "swap")
+        .add_attribute("from", from.to_string())
+        .add_attribute("input_amount", amount.to_string())
+        .add_attribute("output_amount", output_amount.to_string())
+        .add_attribute("protocol_fee_amount", protocol_fee_amount.to_string())
+        .add_attribute("fee_discount", fee_discount.to_string())
+        .add_attribute("trade_volume", trade_volume.to_string()))
+}
+
+
+
+// Contract, hash and reserve of the pair's fee side token
+fn fee_side_token(state: &State) -> (Addr, String, Uint128) {
+    match state.fee_side {
+        PairSide::TokenErth => (state.token_erth_contract.clone(), state.token_erth_hash.clone(), state.token_erth_reserve),
+        PairSide::TokenB => (state.token_b_contract.clone(), state.token_b_hash.clone(), state.token_b_reserve),
+    }
+}
+
+// Contract, hash and reserve of the token opposite the fee side
+fn other_side_token(state: &State) -> (Addr, String, Uint128) {
+    match state.fee_side {
+        PairSide::TokenErth => (state.token_b_contract.clone(), state.token_b_hash.clone(), state.token_b_reserve),
+        PairSide::TokenB => (state.token_erth_contract.clone(), state.token_erth_hash.clone(), state.token_erth_reserve),
+    }
+}
+
+// Moves `amount_in` of `input_token` into the pool and `amount_out` of the other token out of it
+fn apply_swap_to_reserves(state: &mut State, input_token: &Addr, amount_in: Uint128, amount_out: Uint128) {
+    if input_token == &state.token_erth_contract {
+        state.token_erth_reserve += amount_in;
+        state.token_b_reserve -= amount_out;
+    } else {
+        state.token_b_reserve += amount_in;
+        state.token_erth_reserve -= amount_out;
+    }
+}
+
+fn calculate_swap(
+    storage: &dyn Storage,
+    state: &mut State,  // Mutably borrow the state so we can update reserves
+    curve: &mut dyn Curve,
+    input_amount: Uint128,
+    input_token: &Addr,
+    protocol_fee: Uint128, // Fee in basis points after any trader discount
+    convert_fee: bool,     // Convert a fee paid in the other token to the fee side before returning it
+) -> Result<(Uint128, Uint128, Addr, String, Uint128), StdError> {
+    // Calculate protocol fee in the input token
+    let mut protocol_fee_amount = input_amount * protocol_fee / Uint128::from(10000u128);
+    let amount_after_protocol_fee = input_amount - protocol_fee_amount;
+
+    // Extract all necessary details from the state
+    let (input_side, input_reserve, output_reserve, output_addr, output_hash) = if input_token == &state.token_erth_contract {
+        (
+            PairSide::TokenErth,
+            state.token_erth_reserve,
+            state.token_b_reserve,
+            state.token_b_contract.clone(),
+            state.token_b_hash.clone(),
+        )
+    } else if input_token == &state.token_b_contract {
+        (
+            PairSide::TokenB,
+            state.token_b_reserve,
+            state.token_erth_reserve,
+            state.token_erth_contract.clone(),
+            state.token_erth_hash.clone(),
+        )
+    } else {
+        return Err(StdError::generic_err("Invalid input token"));
+    };
+
+    let input_is_fee_side = input_token == &fee_side_token(state).0;
+
+    // Trade volume is measured in the fee side token, converting the input at the curve's spot price
+    let trade_volume = if input_is_fee_side {
+        input_amount
+    } else {
+        apply_price(input_amount, curve.spot_price(input_side, input_reserve, output_reserve)?)?
+    };
+
+    // Calculate the output amount along the pool's curve
+    let output_amount = curve.apply_swap(storage, input_side, amount_after_protocol_fee, input_reserve, output_reserve)?;
+
+    // Check if the liquidity is enough
+    if output_amount > output_reserve {
+        return Err(StdError::generic_err("Insufficient liquidity in reserves"));
+    }
+
+    // Update the reserves based on the swap
+    apply_swap_to_reserves(state, input_token, amount_after_protocol_fee, output_amount);
+
+    if !input_is_fee_side && convert_fee {
+        // Perform feeless swap to convert the protocol fee to the fee side token
+        let protocol_fee_converted = calculate_feeless_swap(storage, state, curve, protocol_fee_amount, input_token)?;
+        apply_swap_to_reserves(state, input_token, protocol_fee_amount, protocol_fee_converted);
+
+        // The `protocol_fee_amount` now represents the amount in the fee side token
+        protocol_fee_amount = protocol_fee_converted;
+    }
+
+    // Return the result including the protocol fee (in the fee side token unless conversion is disabled), output amount, and other details
+    Ok((
+        protocol_fee_amount,
+        output_amount,
+        output_addr,
+        output_hash,
+        trade_volume,
+    ))
+}
+
+
+
+
+
+
+// Swaps the other token for the fee side token and sends it to the staking contract to burn
+fn receive_erth_buyback_swap(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, StdError> {
+
+    let mut state = STATE.load(deps.storage)?;
+    let input_token = info.sender.clone();
+
+    if input_token != other_side_token(&state).0 {
+        return Err(StdError::generic_err("invalid input token for erth buyback contract"));
+    }
+
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
+
+    // Calculate the swap details without fees
+    let mut curve = load_curve(deps.storage, env.block.time.seconds())?;
+    let output_amount = calculate_feeless_swap(deps.storage, &state, curve.as_mut(), amount, &input_token)?;
+
+    // Update reserves
+    apply_swap_to_reserves(&mut state, &input_token, amount, output_amount);
+
+    // Save state
+    STATE.save(deps.storage, &state)?;
+    curve.save(deps.storage)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
+
+    let (fee_token, fee_token_hash, fee_reserve) = fee_side_token(&state);
+
+    // Create a Send message to send the output amount back to the buyback contract for burning
+    let buyback_msg = snip20::HandleMsg::Send {
+        recipient: state.lp_staking_contract.to_string(),
+        recipient_code_hash: Some(state.lp_staking_hash.clone()),
+        amount: output_amount,
+        msg: Some(to_binary(&SendMessage::BurnErth {
+            trade_volume: output_amount,
+            pool_liquidity: fee_reserve * Uint128::from(2u32),
+            total_shares: state.total_shares,
+        })?),
+        memo: None,
+        padding: None,
+    };
+
+    // Create the message to execute the Send
+    let send_message = CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: fee_token.to_string(),
+        code_hash: fee_token_hash,
+        msg: to_binary(&buyback_msg)?,
+        funds: vec![],
+    });
+
+    Ok(Response::new()
+        .add_message(send_message)
+        .add_attribute("action", "buyback_swap")
+        .add_attribute("input_amount", amount.to_string())
+        .add_attribute("output_amount", output_amount.to_string()))
+}
+
+// Swaps the fee side token for the other token and sends it to be burned, used by the ANML-ERTH
+// pair for the 1/second ERTH->ANML buyback and burn
+fn receive_anml_buyback_swap(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, StdError> {
+
+    let mut state = STATE.load(deps.storage)?;
+    let input_token = info.sender.clone();
+
+    if input_token != fee_side_token(&state).0 {
+        return Err(StdError::generic_err("invalid input token for anml buyback contract"));
+    }
+
+    // The burn instruction rides along a `Send`, which a bank transfer can't carry
+    if state.token_b_denom.is_some() {
+        return Err(StdError::generic_err("Buybacks can't pay out a native denom"));
+    }
+
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
+
+    // Calculate the swap details without fees
+    let mut curve = load_curve(deps.storage, env.block.time.seconds())?;
+    let output_amount = calculate_feeless_swap(deps.storage, &state, curve.as_mut(), amount, &input_token)?;
+
+    // Update reserves
+    apply_swap_to_reserves(&mut state, &input_token, amount, output_amount);
+
+    // Save state
+    STATE.save(deps.storage, &state)?;
+    curve.save(deps.storage)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
+
+    let (output_token, output_token_hash, _) = other_side_token(&state);
+
+    // Create a Send message to send the output amount back to the buyback contract for burning
+    let buyback_msg = snip20::HandleMsg::Send {
+        recipient: state.lp_staking_contract.to_string(),
+        recipient_code_hash: Some(state.lp_staking_hash.clone()),
+        amount: output_amount,
+        msg: Some(to_binary(&SendMessage::BurnAnml {
+            trade_volume: amount,
+            pool_liquidity: fee_side_token(&state).2 * Uint128::from(2u32),
+            total_shares: state.total_shares,
+        })?),
+        memo: None,
+        padding: None,
+    };
+
+    // Create the message to execute the Send
+    let send_message = CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: output_token.to_string(),
+        code_hash: output_token_hash,
+        msg: to_binary(&buyback_msg)?,
+        funds: vec![],
+    });
+
+    Ok(Response::new()
+        .add_message(send_message)
+        .add_attribute("action", "buyback_swap")
+        .add_attribute("input_amount", amount.to_string())
+        .add_attribute("output_amount", output_amount.to_string()))
+}
+
+fn calculate_feeless_swap(
+    storage: &dyn Storage,
+    state: &State,
+    curve: &mut dyn Curve,
+    input_amount: Uint128,
+    input_token: &Addr,
+) -> Result<Uint128, StdError> {
+    // Extract the reserves immutably before mutating state
+    let (input_side, input_reserve, output_reserve) = if input_token == &state.token_b_contract {
+        (PairSide::TokenB, state.token_b_reserve, state.token_erth_reserve)
+    } else if input_token == &state.token_erth_contract {
+        (PairSide::TokenErth, state.token_erth_reserve, state.token_b_reserve)
+    } else {
+        return Err(StdError::generic_err("Invalid input token for feeless swap"));
+    };
+
+    // Calculate the output amount along the pool's curve
+    let output_amount = curve.apply_feeless_swap(storage, input_side, input_amount, input_reserve, output_reserve)?;
+
+    // Check if there is enough liquidity in the reserves
+    if output_amount > output_reserve {
+        return Err(StdError::generic_err(
+            "Insufficient liquidity in reserves for feeless swap",
+        ));
+    }
+
+    // Return the calculated output amount in the other token
+    Ok(output_amount)
+}
+
+
+
+pub fn recieve_unbond_liquidity(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    from: Addr,
+    lp_token_amount: Uint128,
+) -> Result<Response, StdError> {
+    // Load the state
+    let mut state = STATE.load(deps.storage)?;
+
+    if info.sender != state.lp_token_contract {
+        return Err(StdError::generic_err("Invalid LP token"));
+    }
+
+    update_price_oracle(deps.storage, &state, env.block.time.seconds())?;
+
+    let (amount_erth, amount_b) = calculate_withdraw(&state, lp_token_amount)?;
+
+    // Update the state reserves and total shares
+    state.token_erth_reserve -= amount_erth;
+    state.token_b_reserve -= amount_b;
+
+    // Adjust total shares based on the unbonding amounts
+    state.total_shares -= lp_token_amount;
+
+    STATE.save(deps.storage, &state)?;
+    record_reserve_snapshot(deps.storage, &state, &env.block)?;
+    record_user_action(deps.storage, &from, &env.block, UserAction::UnbondLiquidity {
+        lp_token_amount,
+        amount_erth,
+        amount_b,
+    })?;
+
+    let mut messages = vec![];
+
+    // Create message to burn the LP tokens
+    let burn_lp_msg = snip20::HandleMsg::Burn {
+        amount: lp_token_amount,
+        memo: None,
+        padding: None,
+    };
+
+    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: state.lp_token_contract.to_string(),
+        code_hash: state.lp_token_hash.clone(),
+        msg: to_binary(&burn_lp_msg)?,
+        funds: vec![],
+    }));
+
+    // Transfer the unbonded tokens to the user
+    let transfer_erth_msg = snip20::HandleMsg::Transfer {
+        recipient: from.clone().to_string(),
+        amount: amount_erth,
+        padding: None,
+        memo: None,
+    };
+    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: state.token_erth_contract.to_string(),
+        code_hash: state.token_erth_hash.clone(),
+        msg: to_binary(&transfer_erth_msg)?,
+        funds: vec![],
+    }));
+
+    // Bank sends can't carry a zero amount
+    if !(is_native_token(&state, &state.token_b_contract) && amount_b.is_zero()) {
+        messages.push(transfer_msg(&state, &state.token_b_contract, &from, amount_b)?);
+    }
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attribute("action", "unbond_liquidity")
+        .add_attribute("from", from)
+        .add_attribute("erth_token_amount", amount_erth.to_string())
+        .add_attribute("token_b_amount", amount_b.to_string())
+        .add_attribute("lp_token_amount", lp_token_amount.to_string()))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> StdResult<Response> {
+    match msg.id {
+        INSTANTIATE_LP_TOKEN_REPLY_ID => handle_instantiate_lp_token_reply(deps, env, msg),
+        _ => Err(StdError::generic_err("Unknown reply ID")),
+    }
+}
+
+fn handle_instantiate_lp_token_reply(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+) -> StdResult<Response> {
+    let mut state = STATE.load(deps.storage)?;
+
+    // The LP token can only be set once
+    if !state.lp_token_contract.as_str().is_empty() {
+        return Err(StdError::generic_err("LP token contract is already set"));
+    }
+
+    let res: SubMsgResponse = match msg.result {
+        SubMsgResult::Ok(res) => res,
+        SubMsgResult::Err(err) => {
+            return Err(StdError::generic_err(format!("LP token instantiation failed: {}", err)))
+        }
+    };
+
+    // Prefer the address from the instantiate response data, falling back to the events
+    let contract_address = match res.data.as_ref().map(|data| parse_instantiate_address(data)) {
+        Some(Ok(address)) => address,
+        _ => instantiate_address_from_events(&res.events, state.lp_token_code_id)?,
+    };
+
+    // Validate the contract address
+    let lp_token_contract_addr = deps.api.addr_validate(&contract_address)?;
+
+    // Update the state with the LP token contract address
+    state.lp_token_contract = lp_token_contract_addr.clone();
+    STATE.save(deps.storage, &state)?;
+
+    Ok(Response::new()
+        .add_messages(register_receive_msgs(&state, &env.contract.code_hash)?)
+        .add_attribute("action", "instantiate_lp_token")
+        .add_attribute("lp_token_contract", lp_token_contract_addr.to_string()))
+}
+
+
+
+// Parses "v1.2.3" style versions into their numeric parts for ordering
+fn parse_version(version: &str) -> StdResult<Vec<u64>> {
+    version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| {
+            part.parse::<u64>()
+                .map_err(|_| StdError::generic_err(format!("Invalid contract version: {}", version)))
+        })
+        .collect()
+}
+
+// Reads a protobuf varint, returning the value and the number of bytes it took
+fn read_varint(data: &[u8]) -> StdResult<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(StdError::generic_err("Invalid varint in instantiate response"))
+}
+
+// Decodes the address (field 1) of a protobuf `MsgInstantiateContractResponse`
+pub fn parse_instantiate_address(data: &[u8]) -> StdResult<String> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let (key, read) = read_varint(&data[pos..])?;
+        pos += read;
+
+        match key & 0x07 {
+            // Varint fields are skipped
+            0 => {
+                let (_, read) = read_varint(&data[pos..])?;
+                pos += read;
+            }
+            // Length delimited fields
+            2 => {
+                let (len, read) = read_varint(&data[pos..])?;
+                pos += read;
+                let end = pos
+                    .checked_add(len as usize)
+                    .filter(|end| *end <= data.len())
+                    .ok_or_else(|| StdError::generic_err("Truncated instantiate response"))?;
+
+                if key >> 3 == 1 {
+                    return String::from_utf8(data[pos..end].to_vec())
+                        .map_err(|_| StdError::generic_err("Invalid address in instantiate response"));
+                }
+                pos = end;
+            }
+            _ => return Err(StdError::generic_err("Unsupported field in instantiate response")),
+        }
+    }
+
+    Err(StdError::generic_err("Instantiate response has no address"))
+}
+
+// Events of contracts the new one instantiates itself come along, so match the expected code id
+pub fn instantiate_address_from_events(events: &[Event], code_id: u64) -> StdResult<String> {
+    // Find the event that contains the contract address
+    let code_id = code_id.to_string();
+    let contract_address_event = events
+        .iter()
+        .find(|event| {
+            event.ty == "instantiate"
+                && event.attributes.iter().any(|attr| attr.key == "code_id" && attr.value == code_id)
+        })
+        .ok_or_else(|| StdError::generic_err("Failed to find instantiate event"))?;
+
+    // Find the attribute that contains the contract address
+    contract_address_event
+        .attributes
+        .iter()
+        .find(|attr| attr.key == "contract_address")
+        .map(|attr| attr.value.clone())
+        .ok_or_else(|| StdError::generic_err("Failed to find contract address"))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(deps: DepsMut, env: Env, msg: MigrateMsg) -> StdResult<Response> {
+    match msg {
+        MigrateMsg::Migrate { lp_token_decimals, fee_side, entropy } => {
+            let params = MigrateParams { lp_token_decimals, fee_side };
+
+            // Refuse to migrate from another contract or to an older version
+            let from_version = match CONTRACT_INFO.may_load(deps.storage)? {
+                Some(info) => {
+                    if info.name != CONTRACT_NAME {
+                        return Err(StdError::generic_err(format!(
+                            "Cannot migrate from contract {}", info.name
+                        )));
+                    }
+                    if parse_version(&info.version)? > parse_version(CONTRACT_VERSION)? {
+                        return Err(StdError::generic_err(format!(
+                            "Cannot downgrade from {} to {}", info.version, CONTRACT_VERSION
+                        )));
+                    }
+                    info.version
+                }
+                None => "unknown".to_string(),
+            };
+
+            CONTRACT_INFO.save(deps.storage, &ContractInfo {
+                name: CONTRACT_NAME.to_string(),
+                version: CONTRACT_VERSION.to_string(),
+            })?;
+
+            // Bring the stored state up to the current layout before anything loads it
+            let from_state_version = migrate_state(deps.storage, &params)?;
+
+            if !viewing_key_seed_is_set(deps.storage) {
+                let entropy = entropy
+                    .ok_or_else(|| StdError::generic_err("Entropy is required to seed viewing keys"))?;
+                seed_viewing_keys(deps.storage, &env, &entropy);
+            }
+
+            // Load the state
+            let state = STATE.load(deps.storage)?;
+
+            // Re-register this contract as a receiver for all three tokens
+            Ok(Response::new()
+                .add_messages(register_receive_msgs(&state, &env.contract.code_hash)?)
+                .add_attribute("action", "migrate")
+                .add_attribute("from_version", from_version)
+                .add_attribute("to_version", CONTRACT_VERSION)
+                .add_attribute("from_state_version", from_state_version.to_string())
+                .add_attribute("to_state_version", CURRENT_STATE_VERSION.to_string()))
+        }
+    }
+}
+
+
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    let response = match msg {
+        QueryMsg::QueryState {} => to_binary(&query_state(deps)?),
+        QueryMsg::QueryDeposit { address, key } => {
+            let address = authenticate_viewing_key(deps, &address, &key)?;
+            to_binary(&query_deposit(deps, address)?)
+        },
+        QueryMsg::QueryFeeDiscount { address, key } => {
+            let address = authenticate_viewing_key(deps, &address, &key)?;
+            to_binary(&query_fee_discount(deps, address)?)
+        },
+        QueryMsg::QueryFeeConfig {} => to_binary(&query_fee_config(deps)?),
+        QueryMsg::Twap { window_seconds } => {
+            let state = STATE.load(deps.storage)?;
+            to_binary(&query_twap(deps.storage, &state, env.block.time.seconds(), window_seconds)?)
+        },
+        QueryMsg::ReserveSnapshots { page, page_size } =>
+            to_binary(&query_reserve_snapshots(deps.storage, page, page_size)?),
+        QueryMsg::PoolInfo {} => to_binary(&query_pool_info(deps, env.block.time.seconds())?),
+        QueryMsg::SimulateProvide { amount_erth, amount_b } =>
+            to_binary(&query_simulate_provide(deps, amount_erth, amount_b)?),
+        QueryMsg::SimulateWithdraw { lp_amount } => to_binary(&query_simulate_withdraw(deps, lp_amount)?),
+        QueryMsg::SimulateSwap { input_token, amount } => {
+            let state = STATE.load(deps.storage)?;
+            let input_token = validate_token(deps, &state, &input_token)?;
+            to_binary(&query_swap(deps, env.block.time.seconds(), amount, input_token)?)
+        },
+        QueryMsg::Version {} => to_binary(&query_version(deps)?),
+        QueryMsg::UserHistory { address, key, page, page_size } => {
+            let address = authenticate_viewing_key(deps, &address, &key)?;
+            to_binary(&query_user_history(deps.storage, &address, page, page_size)?)
+        },
+        QueryMsg::ConcentratedPool {} => to_binary(&query_concentrated_pool(deps)?),
+        QueryMsg::LimitOrders { page, page_size } => to_binary(&query_limit_orders(deps, page, page_size)?),
+        QueryMsg::DcaSchedules { page, page_size } => to_binary(&query_dca_schedules(deps, page, page_size)?),
+        QueryMsg::PendingBatch {} => to_binary(&query_pending_batch(deps)?),
+        QueryMsg::BatchClaim { address, key } => {
+            let address = authenticate_viewing_key(deps, &address, &key)?;
+            to_binary(&query_batch_claim(deps, address)?)
+        },
+        QueryMsg::Positions { address, key, page, page_size } => {
+            let address = authenticate_viewing_key(deps, &address, &key)?;
+            to_binary(&query_positions(deps, &address, page, page_size)?)
+        },
+        QueryMsg::WithPermit { permit, query } => permit_queries(deps, env, permit, query),
+    };
+
+    pad_query_result(response, BLOCK_SIZE)
+}
+
+// Returns the validated address only if the viewing key matches the one it set
+fn authenticate_viewing_key(deps: Deps, address: &str, key: &str) -> StdResult<Addr> {
+    let address = deps.api.addr_validate(address)?;
+    ViewingKey::check(deps.storage, address.as_str(), key)?;
+    Ok(address)
+}
+
+// The owner permission grants everything the signer could see
+fn check_permit_permission(permit: &Permit, permission: TokenPermissions) -> StdResult<()> {
+    if permit.check_permission(&permission) || permit.check_permission(&TokenPermissions::Owner) {
+        Ok(())
+    } else {
+        Err(StdError::generic_err(format!(
+            "Permit does not grant the {:?} permission",
+            permission
+        )))
+    }
+}
+
+fn permit_queries(
+    deps: Deps,
+    env: Env,
+    permit: Permit,
+    query: QueryWithPermit,
+) -> StdResult<Binary> {
+    // Validate the permit signature and that it was issued for this contract
+    let account = validate(
+        deps,
+        PREFIX_REVOKED_PERMITS,
+        &permit,
+        env.contract.address.to_string(),
+        None,
+    )?;
+    let account = deps.api.addr_validate(&account)?;
+
+    match query {
+        QueryWithPermit::QueryDeposit {} => {
+            check_permit_permission(&permit, TokenPermissions::Balance)?;
+            to_binary(&query_deposit(deps, account)?)
+        }
+        QueryWithPermit::QueryFeeDiscount {} => {
+            check_permit_permission(&permit, TokenPermissions::Balance)?;
+            to_binary(&query_fee_discount(deps, account)?)
+        }
+        QueryWithPermit::UserHistory { page, page_size } => {
+            check_permit_permission(&permit, TokenPermissions::History)?;
+            to_binary(&query_user_history(deps.storage, &account, page, page_size)?)
+        }
+        QueryWithPermit::Positions { page, page_size } => {
+            check_permit_permission(&permit, TokenPermissions::Balance)?;
+            to_binary(&query_positions(deps, &account, page, page_size)?)
+        }
+        QueryWithPermit::BatchClaim {} => {
+            check_permit_permission(&permit, TokenPermissions::Balance)?;
+            to_binary(&query_batch_claim(deps, account)?)
+        }
+    }
+}
+
+pub fn query_swap(
+    deps: Deps,
+    now: u64,
+    input_amount: Uint128,
+    input_token: Addr,
+) -> StdResult<QuerySwapResponse> {
+    // Load state
+    let mut state = STATE.load(deps.storage)?;
+    let protocol_fee = state.protocol_fee;
+    let fee_config = FEE_CONFIG.may_load(deps.storage)?.unwrap_or_default();
+    let mut curve = load_curve(deps.storage, now)?;
+
+    // Calculate the swap details without creating messages
+    let (protocol_fee_amount, output_amount, _, _, _) = calculate_swap(
+        deps.storage,
+        &mut state,
+        curve.as_mut(),
+        input_amount,
+        &input_token,
+        protocol_fee,
+        fee_config.fee_token == FeeToken::FeeSide,
+    )?;
+
+    Ok(QuerySwapResponse {
+        protocol_fee_amount,
+        output_amount,
+    })
+}
+
+pub fn query_pool_info(deps: Deps, now: u64) -> StdResult<PoolInfoResponse> {
+    let state = STATE.load(deps.storage)?;
+    let (price_erth_in_b, price_b_in_erth) = spot_prices(&state, load_curve(deps.storage, now)?.as_ref())?;
+    let curve = load_curve_config(deps.storage)?;
+    let amp = match &curve {
+        CurveConfig::StableSwap { amp } => Some(current_amp(amp, now)),
+        CurveConfig::ConstantProduct {} | CurveConfig::Weighted { .. } | CurveConfig::Concentrated { .. } => None,
+    };
+
+    let (erth_per_share, b_per_share) = if state.total_shares.is_zero() {
+        (Decimal256::zero(), Decimal256::zero())
+    } else {
+        (
+            Decimal256::from_ratio(state.token_erth_reserve, state.total_shares),
+            Decimal256::from_ratio(state.token_b_reserve, state.total_shares),
+        )
+    };
+
+    Ok(PoolInfoResponse {
+        token_erth_contract: state.token_erth_contract,
+        token_b_contract: state.token_b_contract,
+        token_b_denom: state.token_b_denom,
+        token_b_symbol: state.token_b_symbol,
+        token_erth_reserve: state.token_erth_reserve,
+        token_b_reserve: state.token_b_reserve,
+        price_erth_in_b,
+        price_b_in_erth,
+        total_shares: state.total_shares,
+        lp_token_contract: state.lp_token_contract,
+        lp_token_decimals: state.lp_token_decimals,
+        protocol_fee: state.protocol_fee,
+        registered_user_discount: REGISTERED_USER_DISCOUNT.may_load(deps.storage)?.unwrap_or_default(),
+        fee_config: FEE_CONFIG.may_load(deps.storage)?.unwrap_or_default(),
+        fee_side: state.fee_side,
+        curve,
+        amp,
+        erth_per_share,
+        b_per_share,
+        version: CONTRACT_VERSION.to_string(),
+    })
+}
+
+pub fn query_limit_orders(deps: Deps, page: u32, page_size: u32) -> StdResult<LimitOrdersResponse> {
+    let page_size = page_size.min(MAX_LIMIT_ORDERS_PAGE_SIZE);
+    let orders = LIMIT_ORDERS
+        .paging(deps.storage, page, page_size)?
+        .into_iter()
+        .map(|(id, order)| LimitOrderInfo {
+            id,
+            input_token: order.input_token,
+            amount: order.amount,
+            price: order.price,
+            min_out: order.min_out,
+        })
+        .collect();
+
+    Ok(LimitOrdersResponse {
+        orders,
+        total: LIMIT_ORDERS.get_len(deps.storage)?,
+    })
+}
+
+pub fn query_dca_schedules(deps: Deps, page: u32, page_size: u32) -> StdResult<DcaSchedulesResponse> {
+    let page_size = page_size.min(MAX_DCA_PAGE_SIZE);
+    let schedules = DCA_SCHEDULES
+        .paging(deps.storage, page, page_size)?
+        .into_iter()
+        .map(|(id, schedule)| DcaScheduleInfo {
+            id,
+            input_token: schedule.input_token,
+            remaining: schedule.remaining,
+            amount_per_slice: schedule.amount_per_slice,
+            min_out_per_slice: schedule.min_out_per_slice,
+            interval: schedule.interval,
+            next_execution: schedule.next_execution,
+        })
+        .collect();
+
+    Ok(DcaSchedulesResponse {
+        schedules,
+        total: DCA_SCHEDULES.get_len(deps.storage)?,
+    })
+}
+
+pub fn query_pending_batch(deps: Deps) -> StdResult<PendingBatchResponse> {
+    let window = BATCH_WINDOW.may_load(deps.storage)?.unwrap_or_default();
+
+    Ok(match PENDING_BATCH.may_load(deps.storage)? {
+        Some(batch) => PendingBatchResponse {
+            settles_at: Some(batch.start_height + window),
+            intents: batch.intents.len() as u32,
+        },
+        None => PendingBatchResponse { settles_at: None, intents: 0 },
+    })
+}
+
+pub fn query_batch_claim(deps: Deps, address: Addr) -> StdResult<BatchClaimResponse> {
+    let claim = BATCH_CLAIMS.get(deps.storage, &address).unwrap_or_default();
+
+    Ok(BatchClaimResponse {
+        amount_erth: claim.amount_erth,
+        amount_b: claim.amount_b,
+    })
+}
+
+pub fn query_concentrated_pool(deps: Deps) -> StdResult<ConcentratedPoolResponse> {
+    let (pool, tick_spacing) = load_concentrated_pool(deps.storage)?;
+    let lp_fee = match load_curve_config(deps.storage)? {
+        CurveConfig::Concentrated { lp_fee, .. } => lp_fee,
+        _ => Uint128::zero(),
+    };
+
+    Ok(ConcentratedPoolResponse {
+        sqrt_price: pool.sqrt_price,
+        price: pool.sqrt_price.checked_mul(pool.sqrt_price)?,
+        tick: pool.tick,
+        liquidity: pool.liquidity,
+        tick_spacing,
+        lp_fee,
+    })
+}
+
+pub fn query_positions(deps: Deps, owner: &Addr, page: u32, page_size: u32) -> StdResult<PositionsResponse> {
+    let (mut pool, tick_spacing) = load_concentrated_pool(deps.storage)?;
+    let mut ticks = Ticks::new(tick_spacing);
+    let positions = POSITIONS.add_suffix(owner.as_bytes());
+
+    let page_size = page_size.min(MAX_POSITIONS_PAGE_SIZE);
+    let positions_page = positions
+        .paging(deps.storage, page, page_size)?
+        .into_iter()
+        .map(|(_, mut position)| {
+            // Bring the owed fees up to date without storing anything
+            if !position.liquidity.is_zero() {
+                update_position(deps.storage, &mut pool, &mut ticks, &mut position, 0)?;
+            }
+            Ok(position)
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(PositionsResponse {
+        positions: positions_page,
+        total: positions.get_len(deps.storage)?,
+    })
+}
+
+pub fn query_simulate_provide(
+    deps: Deps,
+    amount_erth: Uint128,
+    amount_b: Uint128,
+) -> StdResult<SimulateProvideResponse> {
+    let state = STATE.load(deps.storage)?;
+    ensure_share_liquidity(deps.storage)?;
+
+    let (shares, adjusted_amount_erth, adjusted_amount_b, excess_token, excess_amount) =
+        calculate_provide(&state, amount_erth, amount_b);
+
+    let total_shares_after = state.total_shares + shares;
+    let pool_share_after = if total_shares_after.is_zero() {
+        Decimal256::zero()
+    } else {
+        Decimal256::from_ratio(shares, total_shares_after)
+    };
+
+    Ok(SimulateProvideResponse {
+        shares,
+        adjusted_amount_erth,
+        adjusted_amount_b,
+        excess_token,
+        excess_amount,
+        pool_share_after,
+    })
+}
+
+pub fn query_simulate_withdraw(deps: Deps, lp_amount: Uint128) -> StdResult<SimulateWithdrawResponse> {
+    let state = STATE.load(deps.storage)?;
+
+    let (amount_erth, amount_b) = calculate_withdraw(&state, lp_amount)?;
+
+    Ok(SimulateWithdrawResponse {
+        amount_erth,
+        amount_b,
+    })
+}
+
+pub fn query_version(deps: Deps) -> StdResult<VersionResponse> {
+    let contract_info = CONTRACT_INFO
+        .may_load(deps.storage)?
+        .ok_or_else(|| StdError::generic_err("Contract info not set, migrate to record it"))?;
+
+    Ok(VersionResponse { contract_info })
+}
+
+fn query_state(deps: Deps) -> StdResult<QueryStateResponse> {
+    let state = STATE.load(deps.storage)?;
+    Ok(QueryStateResponse { state })
+}
+
+pub fn query_deposit(deps: Deps, address: Addr) -> StdResult<UnclaimedDepositResponse> {
+    // Query deposit amount
+    let unclaimed_deposit = DEPOSITS
+        .get(deps.storage, &address)
+        .unwrap_or_else(Uint128::zero);
+
+    let unclaimed_deposit_response = UnclaimedDepositResponse {
+        unclaimed_deposit,
+    };
+
+    Ok(unclaimed_deposit_response)
+}
+
+pub fn query_fee_discount(deps: Deps, address: Addr) -> StdResult<FeeDiscountResponse> {
+    let state = STATE.load(deps.storage)?;
+    let (protocol_fee, discount) = effective_protocol_fee(deps, &state, &address)?;
+
+    Ok(FeeDiscountResponse {
+        discount,
+        protocol_fee,
+    })
+}
+
+pub fn query_fee_config(deps: Deps) -> StdResult<FeeConfigResponse> {
+    Ok(FeeConfigResponse {
+        fee_config: FEE_CONFIG.may_load(deps.storage)?.unwrap_or_default(),
+        accumulated_fees: ACCUMULATED_FEES.may_load(deps.storage)?.unwrap_or_default(),
+        protocol_fees_accrued: PROTOCOL_FEES_ACCRUED.may_load(deps.storage)?.unwrap_or_default(),
+        flush_config: FLUSH_CONFIG.may_load(deps.storage)?.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::ConstantProduct;
+    use crate::msg::ConcentratedParams;
+    use crate::state::{reserve_snapshots, AccumulatedFees, FeeRecipient, FlushConfig, ProtocolFeesAccrued};
+    use cosmwasm_std::testing::{
+        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR,
+    };
+    use cosmwasm_std::{coins, Attribute, ContractResult, OwnedDeps, ReplyOn, SystemResult};
+
+    fn mock_state() -> State {
+        State {
+            contract_manager: Addr::unchecked("manager"),
+            token_erth_contract: Addr::unchecked("erth"),
+            token_erth_hash: "erth_hash".to_string(),
+            token_b_contract: Addr::unchecked("anml"),
+            token_b_hash: "anml_hash".to_string(),
+            token_b_symbol: "ANML".to_string(),
+            registration_contract: Addr::unchecked("registration"),
+            registration_hash: "registration_hash".to_string(),
+            lp_token_contract: Addr::unchecked(""),
+            lp_token_hash: "lp_token_hash".to_string(),
+            lp_token_code_id: 1,
+            lp_staking_contract: Addr::unchecked("lp_staking"),
+            lp_staking_hash: "lp_staking_hash".to_string(),
+            token_erth_reserve: Uint128::zero(),
+            token_b_reserve: Uint128::zero(),
+            total_shares: Uint128::zero(),
+            protocol_fee: Uint128::new(50),
+            lp_token_decimals: 6,
+            fee_side: PairSide::TokenErth,
+            token_b_denom: None,
+        }
+    }
+
+    fn mock_instantiate_msg() -> InstantiateMsg {
+        InstantiateMsg {
+            contract_manager: "manager".to_string(),
+            token_erth_contract: "erth".to_string(),
+            token_erth_hash: "erth_hash".to_string(),
+            token_b_contract: "anml".to_string(),
+            token_b_hash: "anml_hash".to_string(),
+            token_b_denom: None,
+            registration_contract: "registration".to_string(),
+            registration_hash: "registration_hash".to_string(),
+            token_b_symbol: "ANML".to_string(),
+            lp_token_decimals: 6,
+            lp_token_hash: "lp_token_hash".to_string(),
+            lp_token_code_id: 1,
+            lp_token_contract: None,
+            lp_staking_contract: "lp_staking".to_string(),
+            lp_staking_hash: "lp_staking_hash".to_string(),
+            protocol_fee: Uint128::new(50),
+            lp_token_config: None,
+            fee_side: None,
+            stable_swap_amp: None,
+            weights: None,
+            concentrated: None,
+            entropy: "entropy".to_string(),
+        }
+    }
+
+    // Answers as an existing LP token with 8 decimals and these minters
+    fn existing_lp_token(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, minters: &[&str]) {
+        let minters = format!(r#"{{"minters":{{"minters":{:?}}}}}"#, minters);
+        deps.querier.update_wasm(move |query| {
+            let response = match query {
+                WasmQuery::Smart { msg, .. } if String::from_utf8_lossy(msg).contains("minters") => minters.clone(),
+                _ => r#"{"token_info":{"name":"Old LP","symbol":"OLDLP","decimals":8}}"#.to_string(),
+            };
+            SystemResult::Ok(ContractResult::Ok(Binary::from(response.into_bytes())))
+        });
+    }
+
+    // Protobuf `MsgInstantiateContractResponse { address, data: "" }`
+    fn instantiate_response_data(address: &str) -> Binary {
+        let mut data = vec![0x0a, address.len() as u8];
+        data.extend_from_slice(address.as_bytes());
+        data.extend_from_slice(&[0x12, 0x00]);
+        Binary::from(data)
+    }
+
+    fn instantiate_event(address: &str) -> Event {
+        Event::new("instantiate")
+            .add_attribute("contract_address", address)
+            .add_attribute("code_id", "1")
+    }
+
+    fn lp_token_reply(result: SubMsgResult) -> Reply {
+        Reply {
+            id: INSTANTIATE_LP_TOKEN_REPLY_ID,
+            result,
+        }
+    }
+
+    #[test]
+    fn adopting_an_lp_token_requires_the_pool_to_mint_it() {
+        let mut msg = mock_instantiate_msg();
+        msg.lp_token_contract = Some("old_lp".to_string());
+
+        let mut deps = mock_dependencies();
+        existing_lp_token(&mut deps, &["someone_else"]);
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg.clone()).unwrap_err();
+        assert_eq!(err, StdError::generic_err("This contract is not a minter of the LP token"));
+
+        // Adopted as is, with its own decimals and no LP token instantiated
+        let mut deps = mock_dependencies();
+        existing_lp_token(&mut deps, &["someone_else", MOCK_CONTRACT_ADDR]);
+        let res = instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        assert!(res.messages.iter().all(|sub| sub.reply_on == ReplyOn::Never));
+        assert_eq!(res.messages.len(), 3);
+        let state = STATE.load(&deps.storage).unwrap();
+        assert_eq!((state.lp_token_contract, state.lp_token_decimals), (Addr::unchecked("old_lp"), 8));
+    }
+
+    #[test]
+    fn concentrated_pools_skip_the_lp_token() {
+        let mut msg = mock_instantiate_msg();
+        msg.concentrated = Some(ConcentratedParams {
+            tick_spacing: 60,
+            lp_fee: Uint128::new(30),
+            initial_price: Decimal256::one(),
+        });
+
+        // Registered with ERTH and token B right away, with no LP token to instantiate or reply from
+        let mut deps = mock_dependencies();
+        let res = instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg.clone()).unwrap();
+        assert!(res.messages.iter().all(|sub| sub.reply_on == ReplyOn::Never));
+        let registered: Vec<_> = res.messages.iter().map(|sub| match &sub.msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, .. }) => contract_addr.as_str(),
+            _ => panic!("expected a receive registration"),
+        }).collect();
+        assert_eq!(registered, vec!["erth", "anml"]);
+        assert_eq!(STATE.load(&deps.storage).unwrap().lp_token_contract, Addr::unchecked(""));
+
+        msg.lp_token_contract = Some("old_lp".to_string());
+        let err = instantiate(mock_dependencies().as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Concentrated pools don't take an LP token"));
+    }
+
+    #[test]
+    fn lp_token_config_must_keep_mint_and_burn() {
+        let config = |enable_mint: Option<bool>, enable_burn: Option<bool>| LpTokenConfig {
+            name: Some("Custom LP".to_string()),
+            symbol: None,
+            decimals: Some(18),
+            admin: None,
+            config: Some(InitConfig {
+                public_total_supply: Some(false),
+                enable_deposit: None,
+                enable_redeem: None,
+                enable_mint,
+                enable_burn,
+                can_modify_denoms: None,
+            }),
+        };
+        let mut msg = mock_instantiate_msg();
+
+        for (mint, burn) in [(None, Some(true)), (Some(true), Some(false))] {
+            msg.lp_token_config = Some(config(mint, burn));
+            let err = instantiate(mock_dependencies().as_mut(), mock_env(), mock_info("creator", &[]), msg.clone()).unwrap_err();
+            assert_eq!(err, StdError::generic_err("LP token config must enable mint and burn"));
+        }
+
+        // The rest of the config is passed through to the LP token
+        msg.lp_token_config = Some(config(Some(true), Some(true)));
+        let mut deps = mock_dependencies();
+        let res = instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let CosmosMsg::Wasm(WasmMsg::Instantiate { msg: lp_msg, .. }) = &res.messages[0].msg else {
+            panic!("expected the LP token instantiation");
+        };
+        let lp_msg: Snip20InstantiateMsg = from_binary(lp_msg).unwrap();
+        assert_eq!((lp_msg.name.as_str(), lp_msg.decimals), ("Custom LP", 18));
+        assert_eq!(lp_msg.config.unwrap().public_total_supply, Some(false));
+        assert_eq!(STATE.load(&deps.storage).unwrap().lp_token_decimals, 18);
+    }
+
+    #[test]
+    fn lp_token_reply_reads_address_from_data() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &mock_state()).unwrap();
+
+        let msg = lp_token_reply(SubMsgResult::Ok(SubMsgResponse {
+            events: vec![instantiate_event("event_lp_token")],
+            data: Some(instantiate_response_data("data_lp_token")),
+        }));
+        let res = reply(deps.as_mut(), mock_env(), msg).unwrap();
+
+        assert_eq!(res.messages.len(), 3);
+        assert!(res.attributes.contains(&Attribute::new("lp_token_contract", "data_lp_token")));
+        assert_eq!(STATE.load(&deps.storage).unwrap().lp_token_contract, Addr::unchecked("data_lp_token"));
+    }
+
+    #[test]
+    fn lp_token_reply_falls_back_to_events() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &mock_state()).unwrap();
+
+        let msg = lp_token_reply(SubMsgResult::Ok(SubMsgResponse {
+            events: vec![instantiate_event("event_lp_token")],
+            data: None,
+        }));
+        reply(deps.as_mut(), mock_env(), msg).unwrap();
+
+        assert_eq!(STATE.load(&deps.storage).unwrap().lp_token_contract, Addr::unchecked("event_lp_token"));
+    }
+
+    #[test]
+    fn lp_token_reply_falls_back_to_events_on_malformed_data() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &mock_state()).unwrap();
+
+        let msg = lp_token_reply(SubMsgResult::Ok(SubMsgResponse {
+            events: vec![instantiate_event("event_lp_token")],
+            data: Some(Binary::from(vec![0x0a, 0x20, 0x61])),
+        }));
+        reply(deps.as_mut(), mock_env(), msg).unwrap();
+
+        assert_eq!(STATE.load(&deps.storage).unwrap().lp_token_contract, Addr::unchecked("event_lp_token"));
+    }
+
+    #[test]
+    fn lp_token_reply_without_address_fails() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &mock_state()).unwrap();
+
+        let msg = lp_token_reply(SubMsgResult::Ok(SubMsgResponse {
+            events: vec![],
+            data: None,
+        }));
+        let err = reply(deps.as_mut(), mock_env(), msg).unwrap_err();
+
+        assert_eq!(err, StdError::generic_err("Failed to find instantiate event"));
+    }
+
+    #[test]
+    fn lp_token_reply_error_result_fails() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &mock_state()).unwrap();
+
+        let msg = lp_token_reply(SubMsgResult::Err("out of gas".to_string()));
+        let err = reply(deps.as_mut(), mock_env(), msg).unwrap_err();
+
+        assert_eq!(err, StdError::generic_err("LP token instantiation failed: out of gas"));
+    }
+
+    #[test]
+    fn second_lp_token_reply_is_rejected() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.lp_token_contract = Addr::unchecked("first_lp_token");
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        let msg = lp_token_reply(SubMsgResult::Ok(SubMsgResponse {
+            events: vec![],
+            data: Some(instantiate_response_data("second_lp_token")),
+        }));
+        let err = reply(deps.as_mut(), mock_env(), msg).unwrap_err();
+
+        assert_eq!(err, StdError::generic_err("LP token contract is already set"));
+        assert_eq!(STATE.load(&deps.storage).unwrap().lp_token_contract, Addr::unchecked("first_lp_token"));
+    }
+
+    #[test]
+    fn swap_fees_and_volume_follow_the_fee_side() {
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(2_000_000);
+        state.fee_side = PairSide::TokenB;
+
+        // Token A in: the fee is converted to token B and volume is measured in token B
+        let mut converted = state.clone();
+        let (fee, output, output_addr, _, volume) =
+            calculate_swap(&MockStorage::new(), &mut converted, &mut ConstantProduct, Uint128::new(10_000), &state.token_erth_contract, Uint128::new(100), true).unwrap();
+        assert_eq!(output_addr, state.token_b_contract);
+        assert_eq!(output, Uint128::new(19_605));
+        assert_eq!(fee, Uint128::new(196));
+        assert_eq!(volume, Uint128::new(20_000));
+        assert_eq!(converted.token_erth_reserve, Uint128::new(1_010_000));
+        assert_eq!(converted.token_b_reserve, Uint128::new(2_000_000 - 19_605 - 196));
+
+        // Token B in: already the fee side, nothing to convert
+        let mut direct = state.clone();
+        let (fee, _, _, _, volume) =
+            calculate_swap(&MockStorage::new(), &mut direct, &mut ConstantProduct, Uint128::new(10_000), &state.token_b_contract, Uint128::new(100), true).unwrap();
+        assert_eq!(fee, Uint128::new(100));
+        assert_eq!(volume, Uint128::new(10_000));
+        assert_eq!(direct.token_b_reserve, Uint128::new(2_009_900));
+    }
+
+    #[test]
+    fn twap_averages_the_price_over_the_window() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(2_000_000);
+
+        // The first update only starts the clock, then 2 B per ERTH holds for 100 seconds
+        update_price_oracle(deps.as_mut().storage, &state, 1_000).unwrap();
+        update_price_oracle(deps.as_mut().storage, &state, 1_100).unwrap();
+        state.token_b_reserve = Uint128::new(4_000_000);
+
+        // And 4 B per ERTH for the 100 seconds after
+        let twap = query_twap(&deps.storage, &state, 1_200, 200).unwrap();
+        assert_eq!(twap.price_erth_in_b, Decimal256::from_ratio(3u8, 1u8));
+        assert_eq!(twap.price_b_in_erth, Decimal256::from_ratio(3u8, 8u8));
+        assert_eq!(twap.window_seconds, 200);
+
+        // The window starts at the latest observation at or before its requested start
+        let twap = query_twap(&deps.storage, &state, 1_200, 100).unwrap();
+        assert_eq!(twap.price_erth_in_b, Decimal256::from_ratio(4u8, 1u8));
+        assert_eq!(twap.window_seconds, 100);
+        assert_eq!(query_twap(&deps.storage, &state, 1_200, 150).unwrap().window_seconds, 200);
+
+        let err = query_twap(&deps.storage, &state, 1_200, 500).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Not enough price history for the requested window"));
+        let err = query_twap(&deps.storage, &state, 1_200, 0).unwrap_err();
+        assert_eq!(err, StdError::generic_err("window_seconds must be greater than zero"));
+    }
+
+    #[test]
+    fn lowering_the_snapshot_capacity_trims_a_few_per_call() {
+        let mut deps = mock_dependencies();
+        let state = mock_state();
+        let mut block = mock_env().block;
+        let mut record = |deps: &mut DepsMut| {
+            block.height += 1;
+            record_reserve_snapshot(deps.storage, &state, &block).unwrap();
+        };
+
+        for _ in 0..25 {
+            record(&mut deps.as_mut());
+        }
+        let snapshots = query_reserve_snapshots(&deps.storage, 0, 100).unwrap();
+        assert_eq!(snapshots.total, 25);
+
+        // Only the newest two stay visible while the rest is dropped ten at a time
+        SNAPSHOT_CAPACITY.save(deps.as_mut().storage, &2).unwrap();
+        record(&mut deps.as_mut());
+        assert_eq!(reserve_snapshots().get_len(&deps.storage).unwrap(), 15);
+        let snapshots = query_reserve_snapshots(&deps.storage, 0, 100).unwrap();
+        assert_eq!(snapshots.total, 2);
+        assert_eq!(snapshots.snapshots[1].height, mock_env().block.height + 26);
+
+        // Disabling hides them at once and empties the buffer over the next calls
+        SNAPSHOT_CAPACITY.save(deps.as_mut().storage, &0).unwrap();
+        assert_eq!(query_reserve_snapshots(&deps.storage, 0, 100).unwrap().total, 0);
+        record(&mut deps.as_mut());
+        record(&mut deps.as_mut());
+        assert_eq!(reserve_snapshots().get_len(&deps.storage).unwrap(), 0);
+    }
+
+    #[test]
+    fn fee_discount_belongs_to_the_payer_not_the_named_user() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        FEE_DISCOUNTS.insert(deps.as_mut().storage, &Addr::unchecked("market_maker"), &Uint128::new(10_000)).unwrap();
+
+        let swap = |deps: DepsMut, from: &str, user: Option<&str>| {
+            let msg = ExecuteMsg::Receive {
+                sender: from.to_string(),
+                from: from.to_string(),
+                amount: Uint128::new(10_000),
+                msg: to_binary(&ReceiveMsg::Swap {
+                    min_received: None,
+                    hop: None,
+                    user: user.map(Addr::unchecked),
+                    padding: None,
+                })
+                .unwrap(),
+                memo: None,
+                padding: None,
+            };
+            let res = execute(deps, mock_env(), mock_info("erth", &[]), msg).unwrap();
+            let attribute = |key: &str| res.attributes.iter().find(|attr| attr.key == key).unwrap().value.clone();
+            (attribute("fee_discount"), attribute("protocol_fee_amount"))
+        };
+
+        assert_eq!(swap(deps.as_mut(), "market_maker", None), ("10000".to_string(), "0".to_string()));
+        assert_eq!(swap(deps.as_mut(), "attacker", Some("market_maker")), ("0".to_string(), "50".to_string()));
+
+        // Naming someone as the recipient doesn't write into their history either
+        let history = |address: &str| query_user_history(&deps.storage, &Addr::unchecked(address), 0, 10).unwrap().total;
+        assert_eq!((history("market_maker"), history("attacker")), (1, 1));
+    }
+
+    #[test]
+    fn hops_and_routed_swaps_get_the_traders_discount() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        FEE_DISCOUNTS.insert(deps.as_mut().storage, &Addr::unchecked("trader"), &Uint128::new(10_000)).unwrap();
+        FORWARDERS.save(deps.as_mut().storage, &Forwarders {
+            router_contract: Some(Addr::unchecked("router")),
+            factory_contract: Some(Addr::unchecked("factory")),
+            factory_hash: "factory_hash".to_string(),
+        }).unwrap();
+
+        // The factory only knows `pool_a`
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, msg, .. }
+                if contract_addr == "factory" && String::from_utf8_lossy(msg).contains("\"pool_a\"") =>
+            {
+                let pool = r#"{"pool":{"token_b_contract":"token_a","token_b_hash":"h","token_b_symbol":"A","contract":"pool_a","hash":"h"}}"#;
+                SystemResult::Ok(ContractResult::Ok(Binary::from(pool.as_bytes())))
+            }
+            _ => SystemResult::Ok(ContractResult::Err("Not an indexed pool".to_string())),
+        });
+
+        let swap = |deps: DepsMut, from: &str, hop: Option<HopDetails>| {
+            let msg = ExecuteMsg::Receive {
+                sender: from.to_string(),
+                from: from.to_string(),
+                amount: Uint128::new(10_000),
+                msg: to_binary(&ReceiveMsg::Swap {
+                    min_received: None,
+                    hop,
+                    user: Some(Addr::unchecked("trader")),
+                    padding: None,
+                })
+                .unwrap(),
+                memo: None,
+                padding: None,
+            };
+            let res = execute(deps, mock_env(), mock_info("erth", &[]), msg).unwrap();
+            let attribute = |key: &str| res.attributes.iter().find(|attr| attr.key == key).unwrap().value.clone();
+            (attribute("fee_discount"), attribute("protocol_fee_amount"))
+        };
+
+        // The router sends the first leg, which hops on, and the first pool sends the second
+        let hop = HopDetails { contract: "pool_b".to_string(), hash: "pool_b_hash".to_string() };
+        assert_eq!(swap(deps.as_mut(), "router", Some(hop)), ("10000".to_string(), "0".to_string()));
+        assert_eq!(swap(deps.as_mut(), "pool_a", None), ("10000".to_string(), "0".to_string()));
+
+        // Any other contract naming the trader pays its own fee
+        assert_eq!(swap(deps.as_mut(), "pool_x", None), ("0".to_string(), "50".to_string()));
+
+        // Both legs are in the trader's history, not the forwarders'
+        let history = |address: &str| query_user_history(&deps.storage, &Addr::unchecked(address), 0, 10).unwrap().total;
+        assert_eq!((history("trader"), history("router"), history("pool_a"), history("pool_x")), (2, 0, 0, 1));
+    }
+
+    #[test]
+    fn pool_info_reports_prices_share_value_and_the_current_amp() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(4_000_000);
+        state.total_shares = Uint128::new(2_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        let info = query_pool_info(deps.as_ref(), 1_000).unwrap();
+        assert_eq!(info.curve, CurveConfig::ConstantProduct {});
+        assert_eq!(info.amp, None);
+        assert_eq!((info.price_erth_in_b, info.price_b_in_erth), (Decimal256::percent(400), Decimal256::percent(25)));
+        assert_eq!((info.erth_per_share, info.b_per_share), (Decimal256::percent(50), Decimal256::percent(200)));
+        assert_eq!(info.fee_config, FeeConfig::default());
+        assert_eq!(info.version, CONTRACT_VERSION);
+
+        // Halfway through a ramp from 100 to 200
+        let ramp = AmpRamp { initial_amp: 100, future_amp: 200, initial_time: 1_000, future_time: 2_000 };
+        CURVE_CONFIG.save(deps.as_mut().storage, &CurveConfig::StableSwap { amp: ramp }).unwrap();
+        assert_eq!(query_pool_info(deps.as_ref(), 1_500).unwrap().amp, Some(150));
+
+        // An empty pool has no price and no share value rather than failing
+        state.token_erth_reserve = Uint128::zero();
+        state.token_b_reserve = Uint128::zero();
+        state.total_shares = Uint128::zero();
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        let info = query_pool_info(deps.as_ref(), 1_500).unwrap();
+        assert_eq!((info.price_erth_in_b, info.erth_per_share), (Decimal256::zero(), Decimal256::zero()));
+    }
+
+    #[test]
+    fn a_fresh_stable_pool_can_ramp_once_right_away() {
+        let mut deps = mock_dependencies();
+        let mut msg = mock_instantiate_msg();
+        msg.stable_swap_amp = Some(100);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+
+        let now = mock_env().block.time.seconds();
+        let ramp = |future_amp: u64| ExecuteMsg::RampAmp { future_amp, future_time: now + MIN_RAMP_TIME, padding: None };
+        execute(deps.as_mut(), mock_env(), mock_info("manager", &[]), ramp(200)).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("manager", &[]), ramp(300)).unwrap_err();
+        assert_eq!(err, StdError::generic_err("The amplification was ramped too recently"));
+    }
+
+    #[test]
+    fn permits_are_limited_to_their_permissions_until_revoked() {
+        use secret_toolkit::permit::{PermitParams, PermitSignature, PubKey};
+
+        // Signed fixture from secret-toolkit, granting only history on the token below
+        let token = "secret1rf03820fp8gngzg2w02vd30ns78qkc8rg8dxaq";
+        let signer = "secret1399pyvvk3hvwgxwt3udkslsc5jl3rqv4yshfrl";
+        let permit = Permit {
+            params: PermitParams {
+                allowed_tokens: vec![token.to_string()],
+                permit_name: format!("memo_{}", token),
+                chain_id: "pulsar-2".to_string(),
+                permissions: vec![TokenPermissions::History],
+            },
+            signature: PermitSignature {
+                pub_key: PubKey {
+                    r#type: "tendermint/PubKeySecp256k1".to_string(),
+                    value: Binary::from_base64("A5M49l32ZrV+SDsPnoRv8fH7ivNC4gEX9prvd4RwvRaL").unwrap(),
+                },
+                signature: Binary::from_base64(
+                    "hw/Mo3ZZYu1pEiDdymElFkuCuJzg9soDHw+4DxK7cL9rafiyykh7VynS+guotRAKXhfYMwCiyWmiznc6R+UlsQ==",
+                )
+                .unwrap(),
+            },
+        };
+
+        let mut deps = mock_dependencies();
+        let mut env = mock_env();
+        env.contract.address = Addr::unchecked(token);
+        let run = |deps: Deps, permit_query: QueryWithPermit| {
+            query(deps, env.clone(), QueryMsg::WithPermit { permit: permit.clone(), query: permit_query })
+        };
+
+        let history = run(deps.as_ref(), QueryWithPermit::UserHistory { page: 0, page_size: 10 }).unwrap();
+        assert_eq!(from_binary::<crate::msg::UserHistoryResponse>(&history).unwrap().total, 0);
+        let err = run(deps.as_ref(), QueryWithPermit::QueryFeeDiscount {}).unwrap_err();
+        assert!(err.to_string().contains("does not grant the Balance permission"));
+
+        // Someone else revoking the name doesn't touch the signer's permit
+        let revoke = |name: String| ExecuteMsg::RevokePermit { permit_name: name, padding: None };
+        execute(deps.as_mut(), env.clone(), mock_info("other", &[]), revoke(permit.params.permit_name.clone())).unwrap();
+        run(deps.as_ref(), QueryWithPermit::UserHistory { page: 0, page_size: 10 }).unwrap();
+
+        execute(deps.as_mut(), env.clone(), mock_info(signer, &[]), revoke(permit.params.permit_name.clone())).unwrap();
+        let err = run(deps.as_ref(), QueryWithPermit::UserHistory { page: 0, page_size: 10 }).unwrap_err();
+        assert!(err.to_string().contains("was revoked"));
+    }
+
+    #[test]
+    fn migrate_refuses_older_code_and_other_contracts() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &mock_state()).unwrap();
+        STATE_VERSION.save(deps.as_mut().storage, &CURRENT_STATE_VERSION).unwrap();
+        let msg = MigrateMsg::Migrate { lp_token_decimals: None, fee_side: None, entropy: Some("entropy".to_string()) };
+        let deployed = |name: &str, version: &str| ContractInfo { name: name.to_string(), version: version.to_string() };
+
+        CONTRACT_INFO.save(deps.as_mut().storage, &deployed(CONTRACT_NAME, "v0.10.0")).unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), msg.clone()).unwrap_err();
+        assert_eq!(err, StdError::generic_err(format!("Cannot downgrade from v0.10.0 to {}", CONTRACT_VERSION)));
+
+        CONTRACT_INFO.save(deps.as_mut().storage, &deployed("other-pool", "v0.0.1")).unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), msg.clone()).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Cannot migrate from contract other-pool"));
+
+        // The last release before versions were bumped migrates forward
+        CONTRACT_INFO.save(deps.as_mut().storage, &deployed(CONTRACT_NAME, "v0.0.22")).unwrap();
+        let res = migrate(deps.as_mut(), mock_env(), msg).unwrap();
+        assert!(res.attributes.contains(&Attribute::new("from_version", "v0.0.22")));
+        assert_eq!(CONTRACT_INFO.load(&deps.storage).unwrap().version, CONTRACT_VERSION);
+    }
+
+    #[test]
+    fn migrate_seeds_viewing_keys_for_older_pools() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &mock_state()).unwrap();
+        STATE_VERSION.save(deps.as_mut().storage, &CURRENT_STATE_VERSION).unwrap();
+        assert!(!viewing_key_seed_is_set(&deps.storage));
+
+        let migrate_msg = |entropy: Option<&str>| MigrateMsg::Migrate {
+            lp_token_decimals: None,
+            fee_side: None,
+            entropy: entropy.map(str::to_string),
+        };
+        let err = migrate(deps.as_mut(), mock_env(), migrate_msg(None)).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Entropy is required to seed viewing keys"));
+
+        // The seed depends on the caller's secret, not only on randomness the LP token also sees
+        let mut other = mock_dependencies();
+        STATE.save(other.as_mut().storage, &mock_state()).unwrap();
+        STATE_VERSION.save(other.as_mut().storage, &CURRENT_STATE_VERSION).unwrap();
+        migrate(other.as_mut(), mock_env(), migrate_msg(Some("other"))).unwrap();
+
+        let msg = migrate_msg(Some("entropy"));
+        migrate(deps.as_mut(), mock_env(), msg.clone()).unwrap();
+        let seed = deps.storage.get(&[ViewingKey::STORAGE_KEY, b"::seed"].concat()).unwrap();
+        assert_ne!(other.storage.get(&[ViewingKey::STORAGE_KEY, b"::seed"].concat()).unwrap(), seed);
+
+        // A second migrate keeps the seed keys were already derived from
+        migrate(deps.as_mut(), mock_env(), msg).unwrap();
+        assert_eq!(deps.storage.get(&[ViewingKey::STORAGE_KEY, b"::seed"].concat()).unwrap(), seed);
+    }
+
+    #[test]
+    fn protocol_fees_follow_the_configured_destination() {
+        let mut deps = mock_dependencies();
+        let state = mock_state();
+        let (erth, anml) = (state.token_erth_contract.clone(), state.token_b_contract.clone());
+        let mut route = |fee_config: &FeeConfig, token: &Addr, amount: u128| {
+            route_protocol_fee(deps.as_mut().storage, &state, fee_config, token, Uint128::new(amount), Uint128::new(1_000), false)
+                .unwrap()
+        };
+
+        // Staking accrues until a flush, together with the trade volume
+        assert!(route(&FeeConfig::default(), &erth, 100).is_empty());
+        assert!(route(&FeeConfig::default(), &erth, 20).is_empty());
+
+        // Accumulate holds each token for the manager to claim
+        let accumulate = FeeConfig { destination: FeeDestination::Accumulate {}, fee_token: FeeToken::Input };
+        assert!(route(&accumulate, &erth, 40).is_empty());
+        assert!(route(&accumulate, &anml, 7).is_empty());
+
+        // Split sends the shares right away, the last recipient taking the rounding dust
+        let recipient = |address: &str, share: u128| FeeRecipient {
+            address: Addr::unchecked(address),
+            code_hash: Some(format!("{}_hash", address)),
+            share: Uint128::new(share),
+            msg: None,
+        };
+        let split = FeeConfig {
+            destination: FeeDestination::Split { recipients: vec![recipient("dao", 3_000), recipient("burner", 7_000)] },
+            fee_token: FeeToken::FeeSide,
+        };
+        let messages = route(&split, &erth, 101);
+        let send = |address: &str, amount: u128| CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "erth".to_string(),
+            code_hash: "erth_hash".to_string(),
+            msg: to_binary(&snip20::HandleMsg::Send {
+                recipient: address.to_string(),
+                recipient_code_hash: Some(format!("{}_hash", address)),
+                amount: Uint128::new(amount),
+                msg: None,
+                memo: None,
+                padding: None,
+            })
+            .unwrap(),
+            funds: vec![],
+        });
+        assert_eq!(messages, vec![send("dao", 30), send("burner", 71)]);
+
+        let accrued = PROTOCOL_FEES_ACCRUED.load(&deps.storage).unwrap();
+        assert_eq!((accrued.amount, accrued.trade_volume), (Uint128::new(120), Uint128::new(2_000)));
+        let accumulated = ACCUMULATED_FEES.load(&deps.storage).unwrap();
+        assert_eq!((accumulated.erth, accumulated.token_b), (Uint128::new(40), Uint128::new(7)));
+    }
+
+    #[test]
+    fn only_the_manager_claims_accumulated_fees() {
+        let mut deps = mock_dependencies();
+        let state = mock_state();
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        ACCUMULATED_FEES
+            .save(deps.as_mut().storage, &AccumulatedFees { erth: Uint128::new(40), token_b: Uint128::new(7) })
+            .unwrap();
+
+        let claim = ExecuteMsg::ClaimProtocolFees { recipient: Some("treasury".to_string()), padding: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), claim.clone()).unwrap_err();
+        assert_eq!(err, StdError::generic_err("unauthorized"));
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info("manager", &[]), claim.clone()).unwrap();
+        let treasury = Addr::unchecked("treasury");
+        assert_eq!(res.messages.len(), 2);
+        assert_eq!(res.messages[0].msg, transfer_msg(&state, &state.token_erth_contract, &treasury, Uint128::new(40)).unwrap());
+        assert_eq!(res.messages[1].msg, transfer_msg(&state, &state.token_b_contract, &treasury, Uint128::new(7)).unwrap());
+
+        // Nothing is left for a second claim
+        let res = execute(deps.as_mut(), mock_env(), mock_info("manager", &[]), claim).unwrap();
+        assert!(res.messages.is_empty());
+    }
+
+    #[test]
+    fn fees_flush_once_the_threshold_or_interval_is_reached() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &mock_state()).unwrap();
+        FLUSH_CONFIG.save(deps.as_mut().storage, &FlushConfig { threshold: Uint128::new(100), interval: 3600 }).unwrap();
+        let now = mock_env().block.time.seconds();
+        let flush = |deps: DepsMut, env: Env| execute(deps, env, mock_info("anyone", &[]), ExecuteMsg::FlushFees { padding: None });
+
+        let err = flush(deps.as_mut(), mock_env()).unwrap_err();
+        assert_eq!(err, StdError::generic_err("No protocol fees to flush"));
+
+        let accrued = |amount: u128| ProtocolFeesAccrued {
+            amount: Uint128::new(amount),
+            trade_volume: Uint128::new(amount * 100),
+            last_flush: now - 10,
+        };
+        PROTOCOL_FEES_ACCRUED.save(deps.as_mut().storage, &accrued(50)).unwrap();
+        let err = flush(deps.as_mut(), mock_env()).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Flush threshold and interval not yet reached"));
+
+        // Below the threshold, but the interval since the last flush has passed
+        let mut later = mock_env();
+        later.block.time = later.block.time.plus_seconds(3590);
+        let res = flush(deps.as_mut(), later.clone()).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.attributes[1], Attribute::new("amount", "50"));
+        assert_eq!(res.attributes[2], Attribute::new("trade_volume", "5000"));
+        let flushed = PROTOCOL_FEES_ACCRUED.load(&deps.storage).unwrap();
+        assert_eq!(flushed, ProtocolFeesAccrued { last_flush: later.block.time.seconds(), ..Default::default() });
+
+        // Reaching the threshold doesn't wait for the interval
+        PROTOCOL_FEES_ACCRUED.save(deps.as_mut().storage, &accrued(100)).unwrap();
+        flush(deps.as_mut(), mock_env()).unwrap();
+    }
+
+    fn native_pool_state() -> State {
+        let mut state = mock_state();
+        state.token_b_contract = Addr::unchecked("uscrt");
+        state.token_b_hash = String::new();
+        state.token_b_denom = Some("uscrt".to_string());
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        state
+    }
+
+    // A single test, the static snapshot stores cache their length across tests sharing a process
+    #[test]
+    fn native_token_b_comes_in_as_funds_and_leaves_through_the_bank() {
+        let mut deps = mock_dependencies();
+        STATE.save(deps.as_mut().storage, &native_pool_state()).unwrap();
+
+        let msg = ExecuteMsg::SwapNative { min_received: None, hop: None, padding: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &coins(10_000, "uatom")), msg.clone()).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Expected a single uscrt coin"));
+
+        execute(deps.as_mut(), mock_env(), mock_info("user", &coins(10_000, "uscrt")), msg).unwrap();
+        let state = STATE.load(&deps.storage).unwrap();
+        assert_eq!(state.token_b_reserve, Uint128::new(1_010_000));
+
+        let msg = ExecuteMsg::Receive {
+            sender: "user".to_string(),
+            from: "user".to_string(),
+            amount: Uint128::new(10_000),
+            msg: to_binary(&ReceiveMsg::Swap { min_received: None, hop: None, user: None, padding: None }).unwrap(),
+            memo: None,
+            padding: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("erth", &[]), msg).unwrap();
+
+        let output = STATE.load(&deps.storage).unwrap().token_b_reserve;
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.messages[0].msg, CosmosMsg::Bank(BankMsg::Send {
+            to_address: "user".to_string(),
+            amount: coins((state.token_b_reserve - output).u128(), "uscrt"),
+        }));
+    }
+
+    fn place_order(deps: DepsMut, token: &str, amount: u128, price: Decimal256, min_out: u128) -> Response {
+        let msg = ExecuteMsg::Receive {
+            sender: "user".to_string(),
+            from: "user".to_string(),
+            amount: Uint128::new(amount),
+            msg: to_binary(&ReceiveMsg::PlaceLimitOrder { price, min_out: Uint128::new(min_out), padding: None }).unwrap(),
+            memo: None,
+            padding: None,
+        };
+        execute(deps, mock_env(), mock_info(token, &[]), msg).unwrap()
+    }
+
+    #[test]
+    fn provide_excess_is_only_sent_back_when_it_was_attached() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        state.total_shares = Uint128::new(2_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        // SNIP-20 excess is simply never pulled, so nothing goes back out
+        let msg = ExecuteMsg::AddLiquidity { amount_erth: Uint128::new(15_000), amount_b: Uint128::new(10_000), padding: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg).unwrap();
+        assert_eq!(res.messages.len(), 3);
+        assert!(res.attributes.contains(&Attribute::new("excess_not_pulled", "5000")));
+        let simulated = query_simulate_provide(deps.as_ref(), Uint128::new(15_000), Uint128::new(10_000)).unwrap();
+        assert_eq!((simulated.excess_token, simulated.excess_amount), (Addr::unchecked("erth"), Uint128::new(5_000)));
+
+        // Native funds arrive in full, so their excess is refunded
+        let mut state = native_pool_state();
+        state.total_shares = Uint128::new(2_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        let msg = ExecuteMsg::AddLiquidityNative { amount_erth: Uint128::new(10_000), padding: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("user", &coins(12_000, "uscrt")), msg).unwrap();
+        assert!(res.attributes.contains(&Attribute::new("excess_not_pulled", "0")));
+        assert!(res.messages.iter().any(|sub| sub.msg == CosmosMsg::Bank(BankMsg::Send {
+            to_address: "user".to_string(),
+            amount: coins(2_000, "uscrt"),
+        })));
+    }
+
+    #[test]
+    fn constant_shape_provide_sends_a_zero_transfer_in_place_of_a_refund() {
+        let mut deps = mock_dependencies();
+        let mut state = native_pool_state();
+        state.total_shares = Uint128::new(2_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        CONSTANT_SHAPE_RESPONSES.save(deps.as_mut().storage, &true).unwrap();
+
+        let provide = |deps: DepsMut, attached: u128| {
+            let msg = ExecuteMsg::AddLiquidityNative { amount_erth: Uint128::new(10_000), padding: None };
+            execute(deps, mock_env(), mock_info("user", &coins(attached, "uscrt")), msg).unwrap().messages
+        };
+
+        let exact = provide(deps.as_mut(), 10_000);
+        assert_eq!(exact.len(), provide(deps.as_mut(), 12_000).len());
+        assert!(exact.iter().any(|sub| sub.msg == CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: "erth".to_string(),
+            code_hash: "erth_hash".to_string(),
+            msg: to_binary(&snip20::HandleMsg::Transfer {
+                recipient: "user".to_string(),
+                amount: Uint128::zero(),
+                memo: None,
+                padding: None,
+            })
+            .unwrap(),
+            funds: vec![],
+        })));
+    }
+
+    #[test]
+    fn limit_orders_rest_until_the_price_crosses() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        // Sell 10k ANML once it is worth 1.2 ERTH
+        let price = Decimal256::from_ratio(12u8, 10u8);
+        place_order(deps.as_mut(), "anml", 10_000, price, 11_000);
+        let order = LIMIT_ORDERS.get(&deps.storage, &1).unwrap();
+        assert_eq!(order.owner, Addr::unchecked("user"));
+
+        let env = mock_env();
+        let fill = |deps: &mut DepsMut, state: &mut State, id: u64, order: &LimitOrder| {
+            try_fill_order(deps, &env, state, &mut ConstantProduct, id, order).unwrap()
+        };
+        assert!(fill(&mut deps.as_mut(), &mut state, 1, &order).is_none());
+
+        // At 1.5 ERTH per ANML the order sells into the pool and pays out ERTH
+        state.token_erth_reserve = Uint128::new(1_500_000);
+        let messages = fill(&mut deps.as_mut(), &mut state, 1, &order).unwrap();
+        let output = Uint128::new(1_500_000) - state.token_erth_reserve
+            - PROTOCOL_FEES_ACCRUED.may_load(&deps.storage).unwrap().unwrap_or_default().amount;
+        assert_eq!(
+            messages.last().unwrap(),
+            &transfer_msg(&state, &state.token_erth_contract, &order.owner, output).unwrap()
+        );
+        assert_eq!(state.token_b_reserve, Uint128::new(1_010_000));
+        assert!(LIMIT_ORDERS.get(&deps.storage, &1).is_none());
+
+        // Crossed but unable to meet its minimum, the order keeps resting until cancelled
+        place_order(deps.as_mut(), "anml", 10_000, price, 1_000_000);
+        let order = LIMIT_ORDERS.get(&deps.storage, &2).unwrap();
+        assert!(fill(&mut deps.as_mut(), &mut state, 2, &order).is_none());
+
+        let cancel = ExecuteMsg::CancelLimitOrder { id: 2, padding: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), cancel.clone()).unwrap_err();
+        assert_eq!(err, StdError::generic_err("unauthorized"));
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), cancel).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(&state, &state.token_b_contract, &order.owner, Uint128::new(10_000)).unwrap()
+        );
+        assert!(LIMIT_ORDERS.get(&deps.storage, &2).is_none());
+    }
+
+    #[test]
+    fn swaps_walk_the_order_book_up_to_the_first_uncrossed_price() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_500_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        let price = |tenths: u8| Decimal256::from_ratio(tenths, 10u8);
+        place_order(deps.as_mut(), "anml", 10_000, price(16), 0);
+        place_order(deps.as_mut(), "anml", 10_000, price(11), 0);
+        place_order(deps.as_mut(), "anml", 10_000, price(12), 0);
+        place_order(deps.as_mut(), "erth", 10_000, price(1), 0);
+
+        let anml_book = ORDER_BOOK.add_suffix(state.token_b_contract.as_bytes());
+        assert_eq!(
+            anml_book.load(&deps.storage).unwrap(),
+            vec![(price(11), 2), (price(12), 3), (price(16), 1)]
+        );
+
+        // The two orders below the spot price of 1.5 fill, the one above it and the other side's stay
+        let token = state.token_b_contract.clone();
+        let messages = fill_crossed_orders(&mut deps.as_mut(), &mock_env(), &mut state, &mut ConstantProduct, &token).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(state.token_b_reserve, Uint128::new(1_020_000));
+        assert_eq!(anml_book.load(&deps.storage).unwrap(), vec![(price(16), 1)]);
+        assert!(LIMIT_ORDERS.get(&deps.storage, &1).is_some() && LIMIT_ORDERS.get(&deps.storage, &4).is_some());
+    }
+
+    #[test]
+    fn dca_runs_one_due_slice_at_a_time_and_tips_the_keeper() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        let msg = ExecuteMsg::Receive {
+            sender: "user".to_string(),
+            from: "user".to_string(),
+            amount: Uint128::new(25_000),
+            msg: to_binary(&ReceiveMsg::CreateDca {
+                amount_per_slice: Uint128::new(10_000),
+                min_out_per_slice: Uint128::new(9_000),
+                interval: 3600,
+                padding: None,
+            })
+            .unwrap(),
+            memo: None,
+            padding: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("erth", &[]), msg).unwrap();
+        let mut schedule = DCA_SCHEDULES.get(&deps.storage, &1).unwrap();
+
+        // The first slice is due at once, 0.1% of it goes to the keeper
+        let keeper = Addr::unchecked("keeper");
+        let mut env = mock_env();
+        let (messages, amount) =
+            run_dca_slice(&mut deps.as_mut(), &env, &mut state, &mut ConstantProduct, &keeper, &mut schedule).unwrap();
+        assert_eq!(amount, Uint128::new(10_000));
+        assert_eq!(
+            messages.last().unwrap(),
+            &transfer_msg(&state, &state.token_erth_contract, &keeper, Uint128::new(10)).unwrap()
+        );
+        assert_eq!(schedule.remaining, Uint128::new(15_000));
+        assert_eq!(schedule.next_execution, env.block.time.seconds() + 3600);
+
+        let err = run_dca_slice(&mut deps.as_mut(), &env, &mut state, &mut ConstantProduct, &keeper, &mut schedule)
+            .unwrap_err();
+        assert_eq!(err, StdError::generic_err("No DCA slice is due yet"));
+
+        // Missed slices are skipped, a late call sells one slice and waits for the next interval
+        let mut late = schedule.clone();
+        env.block.time = env.block.time.plus_seconds(3 * 3600 + 60);
+        let (_, amount) =
+            run_dca_slice(&mut deps.as_mut(), &env, &mut state.clone(), &mut ConstantProduct, &keeper, &mut late).unwrap();
+        assert_eq!(amount, Uint128::new(10_000));
+        assert_eq!(late.remaining, Uint128::new(5_000));
+        assert_eq!(late.next_execution, env.block.time.seconds() - 60 + 3600);
+
+        // The last slice is capped at what is left
+        env.block.time = env.block.time.plus_seconds(3600);
+        let (_, amount) =
+            run_dca_slice(&mut deps.as_mut(), &env, &mut state.clone(), &mut ConstantProduct, &keeper, &mut late).unwrap();
+        assert_eq!(amount, Uint128::new(5_000));
+        assert!(late.remaining.is_zero());
+
+        // Only the owner can cancel, getting back what is left
+        DCA_SCHEDULES.insert(deps.as_mut().storage, &1, &schedule).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), ExecuteMsg::CancelDca { id: 1, padding: None })
+            .unwrap_err();
+        assert_eq!(err, StdError::generic_err("unauthorized"));
+        let res = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), ExecuteMsg::CancelDca { id: 1, padding: None })
+            .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(&state, &state.token_erth_contract, &Addr::unchecked("user"), Uint128::new(15_000)).unwrap()
+        );
+        assert!(DCA_SCHEDULES.get(&deps.storage, &1).is_none());
+    }
+
+    fn batch_swap(deps: DepsMut, token: &str, from: &str, amount: u128, min_received: Option<u128>) -> StdResult<Response> {
+        let msg = ExecuteMsg::Receive {
+            sender: from.to_string(),
+            from: from.to_string(),
+            amount: Uint128::new(amount),
+            msg: to_binary(&ReceiveMsg::BatchSwap { min_received: min_received.map(Uint128::new), padding: None }).unwrap(),
+            memo: None,
+            padding: None,
+        };
+        execute(deps, mock_env(), mock_info(token, &[]), msg)
+    }
+
+    #[test]
+    fn batch_intents_clear_together_into_claims() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        let err = batch_swap(deps.as_mut(), "erth", "alice", 30_000, None).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Batch swaps are disabled"));
+        BATCH_WINDOW.save(deps.as_mut().storage, &2).unwrap();
+
+        batch_swap(deps.as_mut(), "erth", "alice", 30_000, None).unwrap();
+        batch_swap(deps.as_mut(), "anml", "bob", 20_000, None).unwrap();
+        // Asks for more than the batch can pay, so is refunded
+        batch_swap(deps.as_mut(), "anml", "carol", 10_000, Some(20_000)).unwrap();
+
+        let pending = query_pending_batch(deps.as_ref()).unwrap();
+        assert_eq!(pending.settles_at, Some(mock_env().block.height + 2));
+        assert_eq!(pending.intents, 3);
+        let err = execute(deps.as_mut(), mock_env(), mock_info("keeper", &[]), ExecuteMsg::SettleBatch { padding: None })
+            .unwrap_err();
+        assert_eq!(err, StdError::generic_err("No batch is due for settlement"));
+
+        let batch = PENDING_BATCH.load(&deps.storage).unwrap();
+        settle_batch(&mut deps.as_mut(), &mock_env(), &mut state, &mut ConstantProduct, &batch).unwrap();
+
+        // Alice sold ERTH into a batch with more ERTH than ANML, Bob got the other side of it
+        let claim = |name: &str| query_batch_claim(deps.as_ref(), Addr::unchecked(name)).unwrap();
+        let (alice, bob, carol) = (claim("alice"), claim("bob"), claim("carol"));
+        assert!(alice.amount_erth.is_zero() && alice.amount_b > Uint128::new(29_500) && alice.amount_b < Uint128::new(29_850));
+        assert!(bob.amount_b.is_zero() && bob.amount_erth > Uint128::new(19_900));
+        assert_eq!(carol, BatchClaimResponse { amount_erth: Uint128::zero(), amount_b: Uint128::new(10_000) });
+
+        // Everything that came in is either in the reserves, claimable or protocol fees
+        let fees = PROTOCOL_FEES_ACCRUED.load(&deps.storage).unwrap().amount;
+        assert_eq!(state.token_erth_reserve + bob.amount_erth + fees, Uint128::new(1_030_000));
+        assert_eq!(state.token_b_reserve + alice.amount_b, Uint128::new(1_020_000));
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info("carol", &[]), ExecuteMsg::ClaimBatch { padding: None })
+            .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(&state, &state.token_b_contract, &Addr::unchecked("carol"), Uint128::new(10_000)).unwrap()
+        );
+        let err = execute(deps.as_mut(), mock_env(), mock_info("carol", &[]), ExecuteMsg::ClaimBatch { padding: None })
+            .unwrap_err();
+        assert_eq!(err, StdError::generic_err("Nothing to claim"));
+    }
+
+    #[test]
+    fn batch_intents_are_cancelled_or_refunded_and_settle_ahead_of_swaps() {
+        let mut deps = mock_dependencies();
+        let mut state = mock_state();
+        state.token_erth_reserve = Uint128::new(1_000_000);
+        state.token_b_reserve = Uint128::new(1_000_000);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        BATCH_WINDOW.save(deps.as_mut().storage, &2).unwrap();
+        BATCH_MIN_INTENT.save(deps.as_mut().storage, &Uint128::new(1_000)).unwrap();
+
+        // Dust is turned away, and one address can only take a few of the batch's places
+        let err = batch_swap(deps.as_mut(), "anml", "alice", 500, None).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Batch intents must be worth at least 1000 of the fee side token"));
+        for _ in 0..MAX_BATCH_INTENTS_PER_OWNER {
+            batch_swap(deps.as_mut(), "erth", "alice", 1_000, None).unwrap();
+        }
+        let err = batch_swap(deps.as_mut(), "erth", "alice", 1_000, None).unwrap_err();
+        assert_eq!(err, StdError::generic_err("At most 5 intents per address in a batch"));
+        batch_swap(deps.as_mut(), "anml", "bob", 2_000, None).unwrap();
+
+        // Alice takes all of hers back at once
+        let cancel = ExecuteMsg::CancelBatchSwaps { padding: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), cancel.clone()).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(&state, &state.token_erth_contract, &Addr::unchecked("alice"), Uint128::new(5_000)).unwrap()
+        );
+        assert_eq!(PENDING_BATCH.load(&deps.storage).unwrap().intents.len(), 1);
+        let err = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), cancel).unwrap_err();
+        assert_eq!(err, StdError::generic_err("No intents to cancel"));
+
+        // With every range withdrawn from a concentrated pool the batch can't be cleared, so it is
+        // refunded instead of reverting
+        CONCENTRATED_POOL.save(deps.as_mut().storage, &new_pool(Decimal256::one()).unwrap()).unwrap();
+        CURVE_CONFIG
+            .save(deps.as_mut().storage, &CurveConfig::Concentrated { tick_spacing: 60, lp_fee: Uint128::new(30) })
+            .unwrap();
+        let mut env = mock_env();
+        env.block.height += 2;
+        let settle = ExecuteMsg::SettleBatch { padding: None };
+        execute(deps.as_mut(), env.clone(), mock_info("keeper", &[]), settle.clone()).unwrap();
+        assert!(PENDING_BATCH.may_load(&deps.storage).unwrap().is_none());
+        assert_eq!(STATE.load(&deps.storage).unwrap().token_b_reserve, Uint128::new(1_000_000));
+        let err = execute(deps.as_mut(), env.clone(), mock_info("keeper", &[]), settle).unwrap_err();
+        assert_eq!(err, StdError::generic_err("No batch is due for settlement"));
+
+        // Bob is refunded once
+        let refund = query_batch_claim(deps.as_ref(), Addr::unchecked("bob")).unwrap();
+        assert_eq!(refund, BatchClaimResponse { amount_erth: Uint128::zero(), amount_b: Uint128::new(2_000) });
+        let claim = ExecuteMsg::ClaimBatch { padding: None };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("bob", &[]), claim.clone()).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(transfer_msg(&state, &state.token_b_contract, &Addr::unchecked("bob"), Uint128::new(2_000)).unwrap())]
+        );
+        let err = execute(deps.as_mut(), env.clone(), mock_info("bob", &[]), claim).unwrap_err();
+        assert_eq!(err, StdError::generic_err("Nothing to claim"));
+
+        // Back on constant product, a due batch settles ahead of the first direct swap of the block,
+        // so swaps can't keep it from settling
+        CURVE_CONFIG.remove(deps.as_mut().storage);
+        batch_swap(deps.as_mut(), "anml", "bob", 2_000, None).unwrap();
+        let swap = ExecuteMsg::Receive {
+            sender: "carol".to_string(),
+            from: "carol".to_string(),
+            amount: Uint128::new(1_000),
+            msg: to_binary(&ReceiveMsg::Swap { min_received: None, hop: None, user: None, padding: None }).unwrap(),
+            memo: None,
+            padding: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("erth", &[]), swap.clone()).unwrap();
+        assert!(PENDING_BATCH.may_load(&deps.storage).unwrap().is_some());
+        execute(deps.as_mut(), env, mock_info("erth", &[]), swap).unwrap();
+        assert!(PENDING_BATCH.may_load(&deps.storage).unwrap().is_none());
+        let filled = query_batch_claim(deps.as_ref(), Addr::unchecked("bob")).unwrap();
+        assert!(filled.amount_b.is_zero() && filled.amount_erth > Uint128::new(1_900), "{:?}", filled);
+    }
 }
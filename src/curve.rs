@@ -0,0 +1,423 @@
+use cosmwasm_std::{Decimal256, StdError, StdResult, Storage, Uint128, Uint256};
+
+use crate::concentrated::{Concentrated, Ticks};
+use crate::state::{AmpRamp, CurveConfig, PairSide, PoolWeights, CONCENTRATED_POOL, CURVE_CONFIG};
+
+// Newton's method iterations before giving up on convergence
+const MAX_ITERATIONS: u32 = 255;
+
+// Limits on amplification and how fast it may be ramped, as in Curve
+pub const MAX_AMP: u64 = 1_000_000;
+pub const MAX_AMP_CHANGE: u64 = 10;
+pub const MIN_RAMP_TIME: u64 = 86400;
+
+// Weighted pools keep each side above 1/50 of the total weight and, as in Balancer, take at most
+// half the input reserve per swap so the power series stays accurate
+pub const MIN_WEIGHT_FRACTION: u64 = 50;
+const MAX_IN_RATIO_DENOMINATOR: u128 = 2;
+
+// Stop summing the power series once terms drop below 1e-10
+const POW_PRECISION: Decimal256 = Decimal256::raw(100_000_000);
+
+// `input` is the side of the pair being swapped in, which only weighted pools care about
+pub trait Curve {
+    // Amount of the other token paid out for `amount_in`, given the reserves before the swap
+    fn output_amount(
+        &self,
+        storage: &dyn Storage,
+        input: PairSide,
+        amount_in: Uint128,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Uint128>;
+
+    // Marginal price of one unit of the input token in the output token, reserves must be nonzero
+    fn spot_price(
+        &self,
+        input: PairSide,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Decimal256>;
+
+    // Like `output_amount`, but for curves with state of their own also moves it as the swap would
+    fn apply_swap(
+        &mut self,
+        storage: &dyn Storage,
+        input: PairSide,
+        amount_in: Uint128,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Uint128> {
+        self.output_amount(storage, input, amount_in, reserve_in, reserve_out)
+    }
+
+    // `apply_swap` for the pool's own buybacks and fee conversions, which pay no LP fee. Only
+    // curves that charge the fee themselves need to override it.
+    fn apply_feeless_swap(
+        &mut self,
+        storage: &dyn Storage,
+        input: PairSide,
+        amount_in: Uint128,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Uint128> {
+        self.apply_swap(storage, input, amount_in, reserve_in, reserve_out)
+    }
+
+    // Persists whatever `apply_swap` changed
+    fn save(&self, _storage: &mut dyn Storage) -> StdResult<()> {
+        Ok(())
+    }
+}
+
+pub struct ConstantProduct;
+
+impl Curve for ConstantProduct {
+    fn output_amount(
+        &self,
+        _storage: &dyn Storage,
+        _input: PairSide,
+        amount_in: Uint128,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Uint128> {
+        Ok((amount_in * reserve_out) / (reserve_in + amount_in))
+    }
+
+    fn spot_price(
+        &self,
+        _input: PairSide,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Decimal256> {
+        Ok(Decimal256::from_ratio(reserve_out, reserve_in))
+    }
+}
+
+// Two token StableSwap, A * n^n * sum(x) + D = A * D * n^n + D^(n+1) / (n^n * prod(x)) with n = 2
+pub struct StableSwap {
+    pub amp: u64,
+}
+
+impl StableSwap {
+    // Invariant D for the given balances
+    fn invariant(&self, x: Uint256, y: Uint256) -> StdResult<Uint256> {
+        let sum = x + y;
+        if sum.is_zero() {
+            return Ok(Uint256::zero());
+        }
+
+        let ann = Uint256::from(self.amp * 4);
+        let mut d = sum;
+        for _ in 0..MAX_ITERATIONS {
+            let d_p = d * d / (x * Uint256::from(2u8)) * d / (y * Uint256::from(2u8));
+            let d_prev = d;
+            d = (ann * sum + d_p * Uint256::from(2u8)) * d
+                / ((ann - Uint256::one()) * d + d_p * Uint256::from(3u8));
+
+            if d.abs_diff(d_prev) <= Uint256::one() {
+                return Ok(d);
+            }
+        }
+
+        Err(StdError::generic_err("StableSwap invariant did not converge"))
+    }
+
+    // Balance of the other token that keeps D constant when this one is `x`
+    fn other_balance(&self, x: Uint256, d: Uint256) -> StdResult<Uint256> {
+        let ann = Uint256::from(self.amp * 4);
+        let c = d * d / (x * Uint256::from(2u8)) * d / (ann * Uint256::from(2u8));
+        let b = x + d / ann;
+
+        let mut y = d;
+        for _ in 0..MAX_ITERATIONS {
+            let y_prev = y;
+            y = (y * y + c) / (y * Uint256::from(2u8) + b - d);
+
+            if y.abs_diff(y_prev) <= Uint256::one() {
+                return Ok(y);
+            }
+        }
+
+        Err(StdError::generic_err("StableSwap balance did not converge"))
+    }
+}
+
+impl Curve for StableSwap {
+    fn output_amount(
+        &self,
+        _storage: &dyn Storage,
+        _input: PairSide,
+        amount_in: Uint128,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Uint128> {
+        if reserve_in.is_zero() || reserve_out.is_zero() {
+            return Err(StdError::generic_err("Insufficient liquidity in reserves"));
+        }
+
+        let x = Uint256::from(reserve_in);
+        let y = Uint256::from(reserve_out);
+        let d = self.invariant(x, y)?;
+        let new_y = self.other_balance(x + Uint256::from(amount_in), d)?;
+
+        // One unit is kept back so rounding in the iterations always favors the pool
+        let output = y.saturating_sub(new_y).saturating_sub(Uint256::one());
+        Ok(Uint128::try_from(output)?)
+    }
+
+    // -dy/dx of the invariant, (16A x^2 y^2 + D^3 y) / (16A x^2 y^2 + D^3 x), divided through by
+    // D^2 so it fits in 256 bits
+    fn spot_price(
+        &self,
+        _input: PairSide,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Decimal256> {
+        let x = Uint256::from(reserve_in);
+        let y = Uint256::from(reserve_out);
+        let d = self.invariant(x, y)?;
+
+        let t = x * y / d;
+        let amp_term = Uint256::from(self.amp * 16) * t * t;
+
+        Ok(Decimal256::from_ratio(amp_term + d * y, amp_term + d * x))
+    }
+}
+
+// Balancer-style weighted constant product, prod(balance ^ weight) = k
+pub struct Weighted {
+    pub weights: PoolWeights,
+}
+
+impl Weighted {
+    // Weight of the input side divided by the weight of the output side
+    fn weight_ratio(&self, input: PairSide) -> Decimal256 {
+        match input {
+            PairSide::TokenErth => Decimal256::from_ratio(self.weights.erth, self.weights.b),
+            PairSide::TokenB => Decimal256::from_ratio(self.weights.b, self.weights.erth),
+        }
+    }
+}
+
+impl Curve for Weighted {
+    // out = reserve_out * (1 - (reserve_in / (reserve_in + amount_in)) ^ (weight_in / weight_out))
+    fn output_amount(
+        &self,
+        _storage: &dyn Storage,
+        input: PairSide,
+        amount_in: Uint128,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Uint128> {
+        if reserve_in.is_zero() || reserve_out.is_zero() {
+            return Err(StdError::generic_err("Insufficient liquidity in reserves"));
+        }
+        if amount_in.u128() > reserve_in.u128() / MAX_IN_RATIO_DENOMINATOR {
+            return Err(StdError::generic_err("Swap exceeds half of the input reserve"));
+        }
+
+        let base = Decimal256::from_ratio(reserve_in, reserve_in + amount_in);
+        let remaining = pow(base, self.weight_ratio(input))?;
+        let paid_out = Decimal256::one().saturating_sub(remaining);
+
+        apply_price(reserve_out, paid_out)
+    }
+
+    // (reserve_out / weight_out) / (reserve_in / weight_in)
+    fn spot_price(
+        &self,
+        input: PairSide,
+        reserve_in: Uint128,
+        reserve_out: Uint128,
+    ) -> StdResult<Decimal256> {
+        Ok(Decimal256::from_ratio(reserve_out, reserve_in) * self.weight_ratio(input))
+    }
+}
+
+// `amount` times `price`, rounded down
+pub fn apply_price(amount: Uint128, price: Decimal256) -> StdResult<Uint128> {
+    let value = Uint256::from(amount) * price.atomics() / Decimal256::one().atomics();
+    Ok(Uint128::try_from(value)?)
+}
+
+// base ^ exp, the whole part of `exp` by repeated multiplication and the fraction with the
+// binomial series from Balancer's `bpowApprox`, which converges for a base in (0, 2)
+fn pow(base: Decimal256, exp: Decimal256) -> StdResult<Decimal256> {
+    let whole = exp.floor();
+    let whole_exp = Uint128::try_from(whole.atomics() / Decimal256::one().atomics())?;
+    let whole_pow = base.checked_pow(u32::try_from(whole_exp.u128()).map_err(|_| {
+        StdError::generic_err("Weight ratio is too large")
+    })?)?;
+
+    let fraction = exp - whole;
+    if fraction.is_zero() {
+        return Ok(whole_pow);
+    }
+
+    Ok(whole_pow * pow_fraction(base, fraction))
+}
+
+fn pow_fraction(base: Decimal256, exp: Decimal256) -> Decimal256 {
+    let one = Decimal256::one();
+    let (x, x_negative) = if base >= one { (base - one, false) } else { (one - base, true) };
+
+    let mut term = one;
+    let mut sum = one;
+    let mut negative = false;
+
+    for i in 1..=MAX_ITERATIONS {
+        let k = Decimal256::from_ratio(i, 1u8);
+        let previous = k - one;
+        let (c, c_negative) = if exp >= previous { (exp - previous, false) } else { (previous - exp, true) };
+
+        term = term * c * x / k;
+        if term.is_zero() {
+            break;
+        }
+
+        if x_negative {
+            negative = !negative;
+        }
+        if c_negative {
+            negative = !negative;
+        }
+
+        sum = if negative { sum.saturating_sub(term) } else { sum + term };
+
+        if term < POW_PRECISION {
+            break;
+        }
+    }
+
+    sum
+}
+
+// Amplification at `now`, linear between the ramp's end points
+pub fn current_amp(ramp: &AmpRamp, now: u64) -> u64 {
+    if now >= ramp.future_time {
+        return ramp.future_amp;
+    }
+    if now <= ramp.initial_time {
+        return ramp.initial_amp;
+    }
+
+    let elapsed = u128::from(now - ramp.initial_time);
+    let duration = u128::from(ramp.future_time - ramp.initial_time);
+    let (initial, future) = (u128::from(ramp.initial_amp), u128::from(ramp.future_amp));
+
+    if future > initial {
+        (initial + (future - initial) * elapsed / duration) as u64
+    } else {
+        (initial - (initial - future) * elapsed / duration) as u64
+    }
+}
+
+pub fn load_curve_config(storage: &dyn Storage) -> StdResult<CurveConfig> {
+    Ok(CURVE_CONFIG
+        .may_load(storage)?
+        .unwrap_or(CurveConfig::ConstantProduct {}))
+}
+
+// The pool's curve as of `now`
+pub fn load_curve(storage: &dyn Storage, now: u64) -> StdResult<Box<dyn Curve>> {
+    Ok(match load_curve_config(storage)? {
+        CurveConfig::ConstantProduct {} => Box::new(ConstantProduct),
+        CurveConfig::StableSwap { amp } => Box::new(StableSwap {
+            amp: current_amp(&amp, now),
+        }),
+        CurveConfig::Weighted { weights } => Box::new(Weighted { weights }),
+        CurveConfig::Concentrated { tick_spacing, lp_fee } => Box::new(Concentrated {
+            pool: CONCENTRATED_POOL.load(storage)?,
+            ticks: Ticks::new(tick_spacing),
+            lp_fee,
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::MockStorage;
+
+    #[test]
+    fn constant_product_matches_xy_k() {
+        let output = ConstantProduct
+            .output_amount(&MockStorage::new(), PairSide::TokenErth, Uint128::new(1_000), Uint128::new(1_000_000), Uint128::new(2_000_000))
+            .unwrap();
+
+        assert_eq!(output, Uint128::new(1_998));
+    }
+
+    #[test]
+    fn stable_swap_has_less_slippage_on_balanced_pools() {
+        let (amount, reserve) = (Uint128::new(100_000), Uint128::new(1_000_000));
+
+        let constant_product = ConstantProduct.output_amount(&MockStorage::new(), PairSide::TokenErth, amount, reserve, reserve).unwrap();
+        let stable = StableSwap { amp: 100 }.output_amount(&MockStorage::new(), PairSide::TokenErth, amount, reserve, reserve).unwrap();
+
+        assert_eq!(constant_product, Uint128::new(90_909));
+        assert!(stable > Uint128::new(99_900) && stable < amount);
+    }
+
+    #[test]
+    fn stable_swap_with_low_amp_approaches_constant_product() {
+        let (amount, reserve) = (Uint128::new(100_000), Uint128::new(1_000_000));
+
+        let low = StableSwap { amp: 1 }.output_amount(&MockStorage::new(), PairSide::TokenErth, amount, reserve, reserve).unwrap();
+        let high = StableSwap { amp: 1_000 }.output_amount(&MockStorage::new(), PairSide::TokenErth, amount, reserve, reserve).unwrap();
+
+        assert!(low < high);
+        assert!(low > ConstantProduct.output_amount(&MockStorage::new(), PairSide::TokenErth, amount, reserve, reserve).unwrap());
+    }
+
+    #[test]
+    fn amp_ramps_linearly() {
+        let ramp = AmpRamp {
+            initial_amp: 100,
+            future_amp: 200,
+            initial_time: 1_000,
+            future_time: 2_000,
+        };
+
+        assert_eq!(current_amp(&ramp, 500), 100);
+        assert_eq!(current_amp(&ramp, 1_500), 150);
+        assert_eq!(current_amp(&ramp, 3_000), 200);
+
+        let down = AmpRamp { initial_amp: 200, future_amp: 100, ..ramp };
+        assert_eq!(current_amp(&down, 1_250), 175);
+    }
+
+    #[test]
+    fn even_weights_track_constant_product() {
+        let (amount, reserve) = (Uint128::new(100_000), Uint128::new(1_000_000));
+        let weighted = Weighted { weights: PoolWeights { erth: 50, b: 50 } };
+
+        let output = weighted.output_amount(&MockStorage::new(), PairSide::TokenErth, amount, reserve, reserve).unwrap();
+        let constant_product = ConstantProduct.output_amount(&MockStorage::new(), PairSide::TokenErth, amount, reserve, reserve).unwrap();
+
+        assert!(output.abs_diff(constant_product) <= Uint128::new(2));
+    }
+
+    #[test]
+    fn weights_scale_spot_price_and_cap_input() {
+        let weighted = Weighted { weights: PoolWeights { erth: 80, b: 20 } };
+        let (erth_reserve, b_reserve) = (Uint128::new(4_000_000), Uint128::new(1_000_000));
+
+        // An 80/20 pool holding 4:1 prices the two tokens equally
+        let price = weighted.spot_price(PairSide::TokenErth, erth_reserve, b_reserve).unwrap();
+        assert_eq!(price, Decimal256::one());
+
+        // Small trades fill close to the spot price in either direction
+        let small = Uint128::new(1_000);
+        let out_b = weighted.output_amount(&MockStorage::new(), PairSide::TokenErth, small, erth_reserve, b_reserve).unwrap();
+        let out_erth = weighted.output_amount(&MockStorage::new(), PairSide::TokenB, small, b_reserve, erth_reserve).unwrap();
+        assert!(out_b > Uint128::new(990) && out_b < small);
+        assert!(out_erth > Uint128::new(990) && out_erth < small);
+
+        let err = weighted
+            .output_amount(&MockStorage::new(), PairSide::TokenB, Uint128::new(600_000), b_reserve, erth_reserve)
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds"), "{}", err);
+    }
+}
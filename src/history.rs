@@ -0,0 +1,42 @@
+use cosmwasm_std::{Addr, BlockInfo, StdResult, Storage};
+
+use crate::msg::UserHistoryResponse;
+use crate::state::{UserAction, UserHistoryEntry, USER_HISTORY};
+
+const MAX_HISTORY_PAGE_SIZE: u32 = 100;
+
+pub fn record_user_action(
+    storage: &mut dyn Storage,
+    user: &Addr,
+    block: &BlockInfo,
+    action: UserAction,
+) -> StdResult<()> {
+    USER_HISTORY.add_suffix(user.as_bytes()).push(storage, &UserHistoryEntry {
+        height: block.height,
+        time: block.time.seconds(),
+        action,
+    })
+}
+
+// Newest entries first
+pub fn query_user_history(
+    storage: &dyn Storage,
+    user: &Addr,
+    page: u32,
+    page_size: u32,
+) -> StdResult<UserHistoryResponse> {
+    let page_size = page_size.min(MAX_HISTORY_PAGE_SIZE);
+    let store = USER_HISTORY.add_suffix(user.as_bytes());
+
+    let history = store
+        .iter(storage)?
+        .rev()
+        .skip((page as usize) * (page_size as usize))
+        .take(page_size as usize)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(UserHistoryResponse {
+        history,
+        total: store.get_len(storage)?,
+    })
+}
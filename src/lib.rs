@@ -1,4 +1,10 @@
+pub mod batch;
 pub mod contract;
+pub mod concentrated;
+pub mod curve;
+pub mod history;
+pub mod migrations;
 pub mod msg;
+pub mod oracle;
 pub mod state;
 
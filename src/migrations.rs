@@ -0,0 +1,320 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cosmwasm_std::{Addr, StdError, StdResult, Storage, Uint128};
+
+use secret_toolkit_storage::Item;
+
+use crate::state::{PairSide, StateV4, STATE, STATE_VERSION};
+
+pub const CURRENT_STATE_VERSION: u16 = 4;
+
+// Original layout, deployed before `STATE_VERSION` existed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct StateV1 {
+    pub contract_manager: Addr,
+    pub token_erth_contract: Addr,
+    pub token_erth_hash: String,
+    pub token_b_contract: Addr,
+    pub token_b_hash: String,
+    pub token_b_symbol: String,
+    pub registration_contract: Addr,
+    pub registration_hash: String,
+    pub lp_token_contract: Addr,
+    pub lp_token_hash: String,
+    pub lp_token_code_id: u64,
+    pub lp_staking_contract: Addr,
+    pub lp_staking_hash: String,
+    pub token_erth_reserve: Uint128,
+    pub token_b_reserve: Uint128,
+    pub total_shares: Uint128,
+    pub protocol_fee: Uint128,
+}
+
+// Same key as `STATE`, bincode isn't self describing so old bytes only load with the old layout
+static STATE_V1: Item<StateV1> = Item::new(b"state");
+
+// Adds `lp_token_decimals`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct StateV2 {
+    pub contract_manager: Addr,
+    pub token_erth_contract: Addr,
+    pub token_erth_hash: String,
+    pub token_b_contract: Addr,
+    pub token_b_hash: String,
+    pub token_b_symbol: String,
+    pub registration_contract: Addr,
+    pub registration_hash: String,
+    pub lp_token_contract: Addr,
+    pub lp_token_hash: String,
+    pub lp_token_code_id: u64,
+    pub lp_staking_contract: Addr,
+    pub lp_staking_hash: String,
+    pub token_erth_reserve: Uint128,
+    pub token_b_reserve: Uint128,
+    pub total_shares: Uint128,
+    pub protocol_fee: Uint128,
+    pub lp_token_decimals: u8,
+}
+
+static STATE_V2: Item<StateV2> = Item::new(b"state");
+
+// Adds `fee_side`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct StateV3 {
+    pub contract_manager: Addr,
+    pub token_erth_contract: Addr,
+    pub token_erth_hash: String,
+    pub token_b_contract: Addr,
+    pub token_b_hash: String,
+    pub token_b_symbol: String,
+    pub registration_contract: Addr,
+    pub registration_hash: String,
+    pub lp_token_contract: Addr,
+    pub lp_token_hash: String,
+    pub lp_token_code_id: u64,
+    pub lp_staking_contract: Addr,
+    pub lp_staking_hash: String,
+    pub token_erth_reserve: Uint128,
+    pub token_b_reserve: Uint128,
+    pub total_shares: Uint128,
+    pub protocol_fee: Uint128,
+    pub lp_token_decimals: u8,
+    pub fee_side: PairSide,
+}
+
+static STATE_V3: Item<StateV3> = Item::new(b"state");
+
+// New fields that a migration may set, falling back to what older deployments effectively used
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct MigrateParams {
+    pub lp_token_decimals: Option<u8>,
+    pub fee_side: Option<PairSide>,
+}
+
+fn upgrade_v1_to_v2(old: StateV1, params: &MigrateParams) -> StateV2 {
+    StateV2 {
+        contract_manager: old.contract_manager,
+        token_erth_contract: old.token_erth_contract,
+        token_erth_hash: old.token_erth_hash,
+        token_b_contract: old.token_b_contract,
+        token_b_hash: old.token_b_hash,
+        token_b_symbol: old.token_b_symbol,
+        registration_contract: old.registration_contract,
+        registration_hash: old.registration_hash,
+        lp_token_contract: old.lp_token_contract,
+        lp_token_hash: old.lp_token_hash,
+        lp_token_code_id: old.lp_token_code_id,
+        lp_staking_contract: old.lp_staking_contract,
+        lp_staking_hash: old.lp_staking_hash,
+        token_erth_reserve: old.token_erth_reserve,
+        token_b_reserve: old.token_b_reserve,
+        total_shares: old.total_shares,
+        protocol_fee: old.protocol_fee,
+        // Every V1 deployment minted its LP token with 6 decimals
+        lp_token_decimals: params.lp_token_decimals.unwrap_or(6),
+    }
+}
+
+fn upgrade_v2_to_v3(old: StateV2, params: &MigrateParams) -> StateV3 {
+    StateV3 {
+        contract_manager: old.contract_manager,
+        token_erth_contract: old.token_erth_contract,
+        token_erth_hash: old.token_erth_hash,
+        token_b_contract: old.token_b_contract,
+        token_b_hash: old.token_b_hash,
+        token_b_symbol: old.token_b_symbol,
+        registration_contract: old.registration_contract,
+        registration_hash: old.registration_hash,
+        lp_token_contract: old.lp_token_contract,
+        lp_token_hash: old.lp_token_hash,
+        lp_token_code_id: old.lp_token_code_id,
+        lp_staking_contract: old.lp_staking_contract,
+        lp_staking_hash: old.lp_staking_hash,
+        token_erth_reserve: old.token_erth_reserve,
+        token_b_reserve: old.token_b_reserve,
+        total_shares: old.total_shares,
+        protocol_fee: old.protocol_fee,
+        lp_token_decimals: old.lp_token_decimals,
+        // V2 deployments are all ERTH pairs with ERTH as the quote token
+        fee_side: params.fee_side.unwrap_or(PairSide::TokenErth),
+    }
+}
+
+fn upgrade_v3_to_v4(old: StateV3) -> StateV4 {
+    StateV4 {
+        contract_manager: old.contract_manager,
+        token_erth_contract: old.token_erth_contract,
+        token_erth_hash: old.token_erth_hash,
+        token_b_contract: old.token_b_contract,
+        token_b_hash: old.token_b_hash,
+        token_b_symbol: old.token_b_symbol,
+        registration_contract: old.registration_contract,
+        registration_hash: old.registration_hash,
+        lp_token_contract: old.lp_token_contract,
+        lp_token_hash: old.lp_token_hash,
+        lp_token_code_id: old.lp_token_code_id,
+        lp_staking_contract: old.lp_staking_contract,
+        lp_staking_hash: old.lp_staking_hash,
+        token_erth_reserve: old.token_erth_reserve,
+        token_b_reserve: old.token_b_reserve,
+        total_shares: old.total_shares,
+        protocol_fee: old.protocol_fee,
+        lp_token_decimals: old.lp_token_decimals,
+        fee_side: old.fee_side,
+        // Existing pools hold SNIP-20s on both sides, the reserves can't change denom in place
+        token_b_denom: None,
+    }
+}
+
+// Upgrades the stored state one version at a time up to the current layout, returning the version it started from
+pub fn migrate_state(storage: &mut dyn Storage, params: &MigrateParams) -> StdResult<u16> {
+    let from_version = STATE_VERSION.may_load(storage)?.unwrap_or(1);
+
+    if from_version > CURRENT_STATE_VERSION {
+        return Err(StdError::generic_err(format!(
+            "Cannot migrate state from version {} to older version {}",
+            from_version, CURRENT_STATE_VERSION
+        )));
+    }
+
+    if from_version < 2 {
+        let state = STATE_V1.load(storage)?;
+        STATE_V2.save(storage, &upgrade_v1_to_v2(state, params))?;
+    }
+
+    if from_version < 3 {
+        let state = STATE_V2.load(storage)?;
+        STATE_V3.save(storage, &upgrade_v2_to_v3(state, params))?;
+    }
+
+    if from_version < 4 {
+        let state = STATE_V3.load(storage)?;
+        STATE.save(storage, &upgrade_v3_to_v4(state))?;
+    }
+
+    STATE_VERSION.save(storage, &CURRENT_STATE_VERSION)?;
+
+    Ok(from_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::MockStorage;
+    use secret_toolkit::serialization::{Bincode2, Serde};
+
+    fn legacy_state() -> StateV1 {
+        StateV1 {
+            contract_manager: Addr::unchecked("manager"),
+            token_erth_contract: Addr::unchecked("erth"),
+            token_erth_hash: "erth_hash".to_string(),
+            token_b_contract: Addr::unchecked("anml"),
+            token_b_hash: "anml_hash".to_string(),
+            token_b_symbol: "ANML".to_string(),
+            registration_contract: Addr::unchecked("registration"),
+            registration_hash: "registration_hash".to_string(),
+            lp_token_contract: Addr::unchecked("lp_token"),
+            lp_token_hash: "lp_token_hash".to_string(),
+            lp_token_code_id: 7,
+            lp_staking_contract: Addr::unchecked("lp_staking"),
+            lp_staking_hash: "lp_staking_hash".to_string(),
+            token_erth_reserve: Uint128::new(1_000_000),
+            token_b_reserve: Uint128::new(2_000_000),
+            total_shares: Uint128::new(3_000_000),
+            protocol_fee: Uint128::new(50),
+        }
+    }
+
+    // Writes the bytes exactly as a V1 contract would have stored them
+    fn store_legacy_bytes(storage: &mut MockStorage, state: &StateV1) {
+        storage.set(b"state", &Bincode2::serialize(state).unwrap());
+    }
+
+    #[test]
+    fn legacy_bytes_do_not_load_as_current_state() {
+        let mut storage = MockStorage::new();
+        store_legacy_bytes(&mut storage, &legacy_state());
+
+        assert!(STATE.load(&storage).is_err());
+    }
+
+    #[test]
+    fn migrates_v1_state_with_defaults() {
+        let mut storage = MockStorage::new();
+        let legacy = legacy_state();
+        store_legacy_bytes(&mut storage, &legacy);
+
+        let from_version = migrate_state(&mut storage, &MigrateParams::default()).unwrap();
+        assert_eq!(from_version, 1);
+        assert_eq!(STATE_VERSION.load(&storage).unwrap(), CURRENT_STATE_VERSION);
+
+        let state = STATE.load(&storage).unwrap();
+        assert_eq!(state.token_erth_reserve, legacy.token_erth_reserve);
+        assert_eq!(state.token_b_reserve, legacy.token_b_reserve);
+        assert_eq!(state.total_shares, legacy.total_shares);
+        assert_eq!(state.lp_token_contract, legacy.lp_token_contract);
+        assert_eq!(state.lp_token_decimals, 6);
+        assert_eq!(state.fee_side, PairSide::TokenErth);
+        assert_eq!(state.token_b_denom, None);
+    }
+
+    #[test]
+    fn migrates_v1_state_with_params() {
+        let mut storage = MockStorage::new();
+        store_legacy_bytes(&mut storage, &legacy_state());
+
+        let params = MigrateParams {
+            lp_token_decimals: Some(18),
+            fee_side: Some(PairSide::TokenB),
+        };
+        migrate_state(&mut storage, &params).unwrap();
+
+        let state = STATE.load(&storage).unwrap();
+        assert_eq!(state.lp_token_decimals, 18);
+        assert_eq!(state.fee_side, PairSide::TokenB);
+    }
+
+    #[test]
+    fn migrates_v2_state_keeping_its_fields() {
+        let mut storage = MockStorage::new();
+        let legacy = upgrade_v1_to_v2(legacy_state(), &MigrateParams {
+            lp_token_decimals: Some(8),
+            fee_side: None,
+        });
+        STATE_V2.save(&mut storage, &legacy).unwrap();
+        STATE_VERSION.save(&mut storage, &2).unwrap();
+
+        let from_version = migrate_state(&mut storage, &MigrateParams::default()).unwrap();
+        assert_eq!(from_version, 2);
+
+        let state = STATE.load(&storage).unwrap();
+        assert_eq!(state.lp_token_decimals, 8);
+        assert_eq!(state.token_b_reserve, legacy.token_b_reserve);
+        assert_eq!(state.fee_side, PairSide::TokenErth);
+    }
+
+    #[test]
+    fn migrating_current_state_is_a_no_op() {
+        let mut storage = MockStorage::new();
+        store_legacy_bytes(&mut storage, &legacy_state());
+        migrate_state(&mut storage, &MigrateParams::default()).unwrap();
+        let before = STATE.load(&storage).unwrap();
+
+        let from_version = migrate_state(&mut storage, &MigrateParams {
+            lp_token_decimals: Some(18),
+            fee_side: Some(PairSide::TokenB),
+        }).unwrap();
+
+        assert_eq!(from_version, CURRENT_STATE_VERSION);
+        assert_eq!(STATE.load(&storage).unwrap(), before);
+    }
+
+    #[test]
+    fn refuses_newer_state_versions() {
+        let mut storage = MockStorage::new();
+        STATE_VERSION.save(&mut storage, &(CURRENT_STATE_VERSION + 1)).unwrap();
+
+        assert!(migrate_state(&mut storage, &MigrateParams::default()).is_err());
+    }
+}
@@ -1,26 +1,63 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{Binary, Uint128, Addr};
+use cosmwasm_std::{Binary, Uint128, Addr, Decimal256};
 
-use crate::state::State;
+use secret_toolkit::permit::Permit;
+
+use crate::state::{
+    State, FeeConfig, AccumulatedFees, ProtocolFeesAccrued, FlushConfig, ReserveSnapshot,
+    UserHistoryEntry, ContractInfo, PairSide, CurveConfig, PoolWeights, Position,
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct InstantiateMsg {
     pub contract_manager: String,
     pub token_erth_contract: String,
     pub token_erth_hash: String,
-    pub token_b_contract: String,
+    pub token_b_contract: String, // Ignored when `token_b_denom` is set
     pub token_b_hash: String,
+    // Native bank denom to use as token B instead of a SNIP-20
+    pub token_b_denom: Option<String>,
     pub registration_contract: String,
     pub registration_hash: String,
     pub token_b_symbol: String,
     pub lp_token_decimals: u8,
     pub lp_token_hash: String,
     pub lp_token_code_id: u64,
+    // Existing LP token (with `lp_token_hash`) to adopt instead of instantiating a new one
+    pub lp_token_contract: Option<String>,
     pub lp_staking_contract: String,
     pub lp_staking_hash: String,
     pub protocol_fee: Uint128,
+    pub lp_token_config: Option<LpTokenConfig>,
+    // Quote side of the pair, defaults to `token_erth`
+    pub fee_side: Option<PairSide>,
+    // Use the StableSwap curve with this amplification instead of constant product
+    pub stable_swap_amp: Option<u64>,
+    // Use the weighted curve with these weights, e.g. 80/20, instead of constant product
+    pub weights: Option<PoolWeights>,
+    // Take liquidity through ranged positions instead of LP shares
+    pub concentrated: Option<ConcentratedParams>,
+    // Secret mixed with the chain randomness to seed viewing keys
+    pub entropy: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ConcentratedParams {
+    pub tick_spacing: u32,
+    pub lp_fee: Uint128,           // Basis points of the input paid to in-range positions
+    pub initial_price: Decimal256, // Token B per ERTH
+}
+
+/// Overrides for the LP token instantiated by the pool, anything left out keeps its default.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct LpTokenConfig {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+    pub admin: Option<String>, // Contract admin able to migrate the LP token, defaults to the ERTH DAO
+    pub config: Option<InitConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
@@ -64,10 +101,23 @@ pub enum ExecuteMsg {
     AddLiquidity {
         amount_erth: Uint128,
         amount_b: Uint128,
+        padding: Option<String>,
+    },
+    // For pools with a native token B, which is taken from the attached funds
+    AddLiquidityNative {
+        amount_erth: Uint128,
+        padding: Option<String>,
+    },
+    // Swaps the attached native token B, the counterpart of `ReceiveMsg::Swap`
+    SwapNative {
+        min_received: Option<Uint128>,
+        hop: Option<HopDetails>,
+        padding: Option<String>,
     },
     UpdateState {
         key: String,
         value: String,
+        padding: Option<String>,
     },
     Receive {
         sender: String,
@@ -75,20 +125,144 @@ pub enum ExecuteMsg {
         amount: Uint128,
         msg: Binary,
         memo: Option<String>,
+        padding: Option<String>,
+    },
+    SetFeeDiscount {
+        address: String,
+        discount: Option<Uint128>,
+        padding: Option<String>,
+    },
+    // Moves a StableSwap pool's amplification to `future_amp` linearly until `future_time`
+    RampAmp {
+        future_amp: u64,
+        future_time: u64,
+        padding: Option<String>,
+    },
+    // Holds the amplification at its current value
+    StopRampAmp {
+        padding: Option<String>,
+    },
+    // Opens a position over `[tick_lower, tick_upper)` in a concentrated pool. Only what the
+    // liquidity needs is taken with allowances, a native token B must be attached as `amount_b`
+    // and the unused part is refunded.
+    MintPosition {
+        tick_lower: i32,
+        tick_upper: i32,
+        amount_erth: Uint128,
+        amount_b: Uint128,
+        padding: Option<String>,
+    },
+    // Withdraws `liquidity` from the position, its fees stay collectable
+    BurnPosition {
+        position_id: u64,
+        liquidity: Uint128,
+        padding: Option<String>,
+    },
+    CollectFees {
+        position_id: u64,
+        padding: Option<String>,
+    },
+    // Fills whichever of the orders have crossed their price, skipping the rest
+    ExecuteOrders {
+        ids: Vec<u64>,
+        padding: Option<String>,
+    },
+    CancelLimitOrder {
+        id: u64,
+        padding: Option<String>,
+    },
+    // Runs the schedule's due slice, open to anyone and paying the caller a tip out of the input.
+    // Slices missed in between are skipped rather than sold together.
+    ExecuteDca {
+        id: u64,
+        padding: Option<String>,
+    },
+    // Stops the schedule and refunds what is left of the deposit
+    CancelDca {
+        id: u64,
+        padding: Option<String>,
+    },
+    // Clears the pending batch once its window has closed, open to anyone. Swaps settle it first too.
+    SettleBatch {
+        padding: Option<String>,
+    },
+    // Pays out the sender's settled batch outputs and refunds
+    ClaimBatch {
+        padding: Option<String>,
+    },
+    // Takes all of the sender's intents out of the pending batch and refunds them
+    CancelBatchSwaps {
+        padding: Option<String>,
+    },
+    SetFeeConfig {
+        fee_config: FeeConfig,
+        padding: Option<String>,
+    },
+    ClaimProtocolFees {
+        recipient: Option<String>,
+        padding: Option<String>,
+    },
+    FlushFees {
+        padding: Option<String>,
+    },
+    CreateViewingKey {
+        entropy: String,
+        padding: Option<String>,
     },
+    SetViewingKey {
+        key: String,
+        padding: Option<String>,
+    },
+    RevokePermit {
+        permit_name: String,
+        padding: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecuteAnswer {
+    CreateViewingKey { key: String },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ReceiveMsg {
-    UnbondLiquidity {},
+    UnbondLiquidity {
+        padding: Option<String>,
+    },
     Swap { 
         min_received: Option<Uint128>,
         hop: Option<HopDetails>,
         user: Option<Addr>,
+        padding: Option<String>,
+    },
+    ErthBuybackSwap {
+        padding: Option<String>,
+    },
+    AnmlBuybackSwap {
+        padding: Option<String>,
+    },
+    // Rests until the spot price of the sent token reaches `price` (output token per input token),
+    // then sells it into the pool as long as at least `min_out` comes back
+    PlaceLimitOrder {
+        price: Decimal256,
+        min_out: Uint128,
+        padding: Option<String>,
+    },
+    // Sells the sent amount in slices, the first one due right away
+    CreateDca {
+        amount_per_slice: Uint128,
+        min_out_per_slice: Uint128,
+        interval: u64,
+        padding: Option<String>,
+    },
+    // Joins the pending batch, cleared with every other intent in its window at a single price.
+    // Intents that would get less than `min_received` are refunded instead.
+    BatchSwap {
+        min_received: Option<Uint128>,
+        padding: Option<String>,
     },
-    ErthBuybackSwap {},
-    AnmlBuybackSwap {},
 }
 
 /// This struct represents the message to send to the other contract.
@@ -114,14 +288,92 @@ pub enum SendMessage {
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum MigrateMsg {
-    Migrate {},
+    Migrate {
+        // Values for state fields added since the deployed version, see `MigrateParams`
+        lp_token_decimals: Option<u8>,
+        fee_side: Option<PairSide>,
+        // Seeds viewing keys on pools that predate them, see `InstantiateMsg::entropy`
+        entropy: Option<String>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
     QueryState {},
-    QueryDeposit { address: String },
+    // User-scoped queries take the address and its viewing key, or go through `WithPermit`
+    QueryDeposit { address: String, key: String },
+    QueryFeeDiscount { address: String, key: String },
+    QueryFeeConfig {},
+    Twap { window_seconds: u64 },
+    ReserveSnapshots { page: u32, page_size: u32 },
+    PoolInfo {},
+    SimulateProvide { amount_erth: Uint128, amount_b: Uint128 },
+    SimulateWithdraw { lp_amount: Uint128 },
+    SimulateSwap { input_token: String, amount: Uint128 },
+    Version {},
+    UserHistory {
+        address: String,
+        key: String,
+        page: u32,
+        page_size: u32,
+    },
+    ConcentratedPool {},
+    LimitOrders { page: u32, page_size: u32 },
+    DcaSchedules { page: u32, page_size: u32 },
+    PendingBatch {},
+    BatchClaim { address: String, key: String },
+    Positions {
+        address: String,
+        key: String,
+        page: u32,
+        page_size: u32,
+    },
+    WithPermit {
+        permit: Permit,
+        query: QueryWithPermit,
+    },
+}
+
+/// Queries authenticated by a SNIP-24 permit, which identifies the user by its signature.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryWithPermit {
+    QueryDeposit {},
+    QueryFeeDiscount {},
+    UserHistory { page: u32, page_size: u32 },
+    Positions { page: u32, page_size: u32 },
+    BatchClaim {},
+}
+
+/// Query sent to the factory to check whether a contract is one of its pools.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FactoryQueryMsg {
+    PoolByContract { contract: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct FactoryPoolResponse {
+    pub pool: FactoryPool,
+}
+
+// The part of the factory's pool record a pool needs
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct FactoryPool {
+    pub contract: Addr,
+}
+
+/// Query sent to the registration contract to check whether a trader is registered.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationQueryMsg {
+    QueryRegistrationStatus { address: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct RegistrationStatusResponse {
+    pub registration_status: bool,
 }
 
 // We define a custom struct for each query response
@@ -141,3 +393,145 @@ pub struct UnclaimedDepositResponse {
     pub unclaimed_deposit: Uint128,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct FeeDiscountResponse {
+    pub discount: Uint128,
+    pub protocol_fee: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct FeeConfigResponse {
+    pub fee_config: FeeConfig,
+    pub accumulated_fees: AccumulatedFees,
+    pub protocol_fees_accrued: ProtocolFeesAccrued,
+    pub flush_config: FlushConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct TwapResponse {
+    pub price_erth_in_b: Decimal256,
+    pub price_b_in_erth: Decimal256,
+    pub window_seconds: u64, // Actual window covered, at least the requested one
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ReserveSnapshotsResponse {
+    pub snapshots: Vec<ReserveSnapshot>, // Oldest first
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct UserHistoryResponse {
+    pub history: Vec<UserHistoryEntry>, // Newest first
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ConcentratedPoolResponse {
+    pub sqrt_price: Decimal256,
+    pub price: Decimal256, // Token B per ERTH
+    pub tick: i32,
+    pub liquidity: Uint128, // Active at the current price
+    pub tick_spacing: u32,
+    pub lp_fee: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PositionsResponse {
+    pub positions: Vec<Position>, // Owed fees include everything earned up to now
+    pub total: u32,
+}
+
+// Resting orders are public so keepers can fill them, their owners are not
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct LimitOrderInfo {
+    pub id: u64,
+    pub input_token: Addr,
+    pub amount: Uint128,
+    pub price: Decimal256,
+    pub min_out: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct LimitOrdersResponse {
+    pub orders: Vec<LimitOrderInfo>,
+    pub total: u32,
+}
+
+// Public for keepers like limit orders, without the owner
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct DcaScheduleInfo {
+    pub id: u64,
+    pub input_token: Addr,
+    pub remaining: Uint128,
+    pub amount_per_slice: Uint128,
+    pub min_out_per_slice: Uint128,
+    pub interval: u64,
+    pub next_execution: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct DcaSchedulesResponse {
+    pub schedules: Vec<DcaScheduleInfo>,
+    pub total: u32,
+}
+
+// Only the size of the pending batch is public, not what is in it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PendingBatchResponse {
+    pub settles_at: Option<u64>, // First block height at which the batch can be settled
+    pub intents: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct BatchClaimResponse {
+    pub amount_erth: Uint128,
+    pub amount_b: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PoolInfoResponse {
+    pub token_erth_contract: Addr,
+    pub token_b_contract: Addr,
+    pub token_b_denom: Option<String>,
+    pub token_b_symbol: String,
+    pub token_erth_reserve: Uint128,
+    pub token_b_reserve: Uint128,
+    pub price_erth_in_b: Decimal256,
+    pub price_b_in_erth: Decimal256,
+    pub total_shares: Uint128,
+    pub lp_token_contract: Addr,
+    pub lp_token_decimals: u8,
+    pub protocol_fee: Uint128,
+    pub registered_user_discount: Uint128,
+    pub fee_config: FeeConfig,
+    pub fee_side: PairSide,
+    pub curve: CurveConfig,
+    pub amp: Option<u64>, // Current amplification of a StableSwap pool
+    // Underlying tokens redeemable for a single LP share unit
+    pub erth_per_share: Decimal256,
+    pub b_per_share: Decimal256,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct SimulateProvideResponse {
+    pub shares: Uint128,
+    pub adjusted_amount_erth: Uint128,
+    pub adjusted_amount_b: Uint128,
+    // Not pulled from the sender, or sent back when it is attached native funds
+    pub excess_token: Addr,
+    pub excess_amount: Uint128,
+    pub pool_share_after: Decimal256,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct SimulateWithdrawResponse {
+    pub amount_erth: Uint128,
+    pub amount_b: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct VersionResponse {
+    pub contract_info: ContractInfo,
+}
@@ -0,0 +1,196 @@
+use cosmwasm_std::{BlockInfo, Decimal256, StdError, StdResult, Storage, Uint256};
+
+use crate::curve::{Curve, load_curve};
+use crate::msg::{TwapResponse, ReserveSnapshotsResponse};
+use crate::state::{
+    State, PairSide, PriceAccumulator, PriceObservation, PRICE_ACCUMULATOR, price_observations,
+    ReserveSnapshot, reserve_snapshots, SNAPSHOT_CAPACITY,
+};
+
+// Minimum spacing between stored observations, and how many are kept (24 hours at one per minute)
+const OBSERVATION_PERIOD: u64 = 60;
+const MAX_OBSERVATIONS: u32 = 1440;
+
+const DEFAULT_SNAPSHOT_CAPACITY: u32 = 1000;
+const MAX_SNAPSHOT_PAGE_SIZE: u32 = 100;
+// Snapshots dropped per call after the capacity is lowered, so no single swap pays for all of them
+const MAX_SNAPSHOT_TRIM: u32 = 10;
+
+// Spot prices along the pool's curve as (token B per ERTH, ERTH per token B), zero while the pool is empty
+pub fn spot_prices(state: &State, curve: &dyn Curve) -> StdResult<(Decimal256, Decimal256)> {
+    if state.token_erth_reserve.is_zero() || state.token_b_reserve.is_zero() {
+        return Ok((Decimal256::zero(), Decimal256::zero()));
+    }
+
+    Ok((
+        curve.spot_price(PairSide::TokenErth, state.token_erth_reserve, state.token_b_reserve)?,
+        curve.spot_price(PairSide::TokenB, state.token_b_reserve, state.token_erth_reserve)?,
+    ))
+}
+
+// Accumulates the current price up to `now`, must be called with the reserves from before a change
+pub fn update_price_oracle(
+    storage: &mut dyn Storage,
+    state: &State,
+    now: u64,
+) -> StdResult<()> {
+    let mut accumulator = PRICE_ACCUMULATOR.may_load(storage)?.unwrap_or_default();
+
+    // The first update only starts the clock, so deployments that predate the oracle don't
+    // accumulate their current price over the whole chain history
+    if accumulator.last_update != 0 && now > accumulator.last_update {
+        let elapsed = Uint256::from(now - accumulator.last_update);
+        let (price_erth, price_b) = spot_prices(state, load_curve(storage, now)?.as_ref())?;
+        accumulator.price_erth_cumulative += price_erth.atomics() * elapsed;
+        accumulator.price_b_cumulative += price_b.atomics() * elapsed;
+    }
+
+    if accumulator.last_update == now {
+        return Ok(());
+    }
+    accumulator.last_update = now;
+    PRICE_ACCUMULATOR.save(storage, &accumulator)?;
+
+    let observations = price_observations();
+    let len = observations.get_len(storage)?;
+    let due = if len == 0 {
+        true
+    } else {
+        let last = observations.get_at(storage, len - 1)?;
+        now >= last.timestamp + OBSERVATION_PERIOD
+    };
+
+    if due {
+        if len >= MAX_OBSERVATIONS {
+            observations.pop_front(storage)?;
+        }
+        observations.push_back(storage, &PriceObservation {
+            timestamp: now,
+            price_erth_cumulative: accumulator.price_erth_cumulative,
+            price_b_cumulative: accumulator.price_b_cumulative,
+        })?;
+    }
+
+    Ok(())
+}
+
+// Latest observation at or before `target`, found by binary search over the time-ordered buffer
+fn observation_before(storage: &dyn Storage, target: u64) -> StdResult<Option<PriceObservation>> {
+    let observations = price_observations();
+    let len = observations.get_len(storage)?;
+    let (mut low, mut high) = (0u32, len);
+    let mut found = None;
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let observation = observations.get_at(storage, mid)?;
+        if observation.timestamp <= target {
+            found = Some(observation);
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    Ok(found)
+}
+
+pub fn query_twap(
+    storage: &dyn Storage,
+    state: &State,
+    now: u64,
+    window_seconds: u64,
+) -> StdResult<TwapResponse> {
+    if window_seconds == 0 {
+        return Err(StdError::generic_err("window_seconds must be greater than zero"));
+    }
+
+    let accumulator: PriceAccumulator = PRICE_ACCUMULATOR.may_load(storage)?.unwrap_or_default();
+
+    // Bring the accumulators forward to now using the unchanged current price
+    let (price_erth, price_b) = spot_prices(state, load_curve(storage, now)?.as_ref())?;
+    let since_update = Uint256::from(now.saturating_sub(accumulator.last_update));
+    let price_erth_cumulative = accumulator.price_erth_cumulative + price_erth.atomics() * since_update;
+    let price_b_cumulative = accumulator.price_b_cumulative + price_b.atomics() * since_update;
+
+    let observation = observation_before(storage, now.saturating_sub(window_seconds))?
+        .ok_or_else(|| StdError::generic_err("Not enough price history for the requested window"))?;
+
+    let elapsed = now - observation.timestamp;
+    if elapsed == 0 {
+        return Err(StdError::generic_err("Not enough price history for the requested window"));
+    }
+
+    Ok(TwapResponse {
+        price_erth_in_b: Decimal256::new(
+            (price_erth_cumulative - observation.price_erth_cumulative) / Uint256::from(elapsed),
+        ),
+        price_b_in_erth: Decimal256::new(
+            (price_b_cumulative - observation.price_b_cumulative) / Uint256::from(elapsed),
+        ),
+        window_seconds: elapsed,
+    })
+}
+
+pub fn snapshot_capacity(storage: &dyn Storage) -> StdResult<u32> {
+    Ok(SNAPSHOT_CAPACITY.may_load(storage)?.unwrap_or(DEFAULT_SNAPSHOT_CAPACITY))
+}
+
+// Records the reserves after a change, keeping at most one snapshot per block
+pub fn record_reserve_snapshot(
+    storage: &mut dyn Storage,
+    state: &State,
+    block: &BlockInfo,
+) -> StdResult<()> {
+    let snapshots = reserve_snapshots();
+    let capacity = snapshot_capacity(storage)?;
+    let mut len = snapshots.get_len(storage)?;
+
+    // A lowered capacity is caught up with a few snapshots at a time, 0 empties the buffer
+    let mut trimmed = 0;
+    while len > capacity && trimmed < MAX_SNAPSHOT_TRIM {
+        snapshots.pop_front(storage)?;
+        len -= 1;
+        trimmed += 1;
+    }
+    if capacity == 0 {
+        return Ok(());
+    }
+
+    let snapshot = ReserveSnapshot {
+        height: block.height,
+        time: block.time.seconds(),
+        token_erth_reserve: state.token_erth_reserve,
+        token_b_reserve: state.token_b_reserve,
+        total_shares: state.total_shares,
+    };
+
+    if len > 0 && snapshots.get_at(storage, len - 1)?.height == block.height {
+        return snapshots.set_at(storage, len - 1, &snapshot);
+    }
+
+    if len >= capacity {
+        snapshots.pop_front(storage)?;
+    }
+    snapshots.push_back(storage, &snapshot)
+}
+
+// Only the newest `capacity` snapshots are visible, whatever is still waiting to be trimmed
+pub fn query_reserve_snapshots(
+    storage: &dyn Storage,
+    page: u32,
+    page_size: u32,
+) -> StdResult<ReserveSnapshotsResponse> {
+    let snapshots = reserve_snapshots();
+    let page_size = page_size.min(MAX_SNAPSHOT_PAGE_SIZE);
+    let len = snapshots.get_len(storage)?;
+    let skip = len.saturating_sub(snapshot_capacity(storage)?);
+
+    let start = skip.saturating_add(page.saturating_mul(page_size));
+    let end = start.saturating_add(page_size).min(len);
+
+    Ok(ReserveSnapshotsResponse {
+        snapshots: (start..end).map(|i| snapshots.get_at(storage, i)).collect::<StdResult<_>>()?,
+        total: len - skip,
+    })
+}
@@ -1,12 +1,25 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{Addr, Uint128};
+use cosmwasm_std::{Addr, Binary, Decimal256, Uint128, Uint256};
 
-use secret_toolkit_storage::{Keymap, Item};
+use secret_toolkit_storage::{Keymap, Item, DequeStore, AppendStore};
+
+// Current layout of `STATE`. Fields are only ever added by introducing a new `StateVn`, with
+// the previous layout and its upgrade kept in `migrations`.
+pub type State = StateV4;
+
+// One side of the pair. Despite the field names both tokens may be any SNIP-20, `token_erth_*`
+// is just the first side and need not be ERTH.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PairSide {
+    TokenErth,
+    TokenB,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
-pub struct State {
+pub struct StateV4 {
     pub contract_manager: Addr,
     pub token_erth_contract: Addr,
     pub token_erth_hash: String,
@@ -24,15 +37,338 @@ pub struct State {
     pub token_b_reserve: Uint128,
     pub total_shares: Uint128,
     pub protocol_fee: Uint128,
+    pub lp_token_decimals: u8,
+    // Side protocol fees are converted to, trade volume is measured in and buybacks pay out in
+    pub fee_side: PairSide,
+    // Bank denom when token B is a native coin (uscrt or an IBC denom) rather than a SNIP-20. Then
+    // `token_b_contract` holds the denom so token comparisons keep working, and `token_b_hash` is empty.
+    pub token_b_denom: Option<String>,
 }
 
 pub static STATE: Item<State> = Item::new(b"state");
 
-pub const DEPOSITS: Keymap<Addr, Uint128> = Keymap::new(b"deposits");
+// Layout version of `STATE`, missing on deployments still on `StateV1`
+pub static STATE_VERSION: Item<u16> = Item::new(b"state_version");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ContractInfo {
+    pub name: String,
+    pub version: String,
+}
+
+// Written at instantiate and on every migrate, missing on deployments that predate it
+pub static CONTRACT_INFO: Item<ContractInfo> = Item::new(b"contract_info");
+
+pub static DEPOSITS: Keymap<Addr, Uint128> = Keymap::new(b"deposits");
+
+// Opt-in: swap and add-liquidity responses keep the same message layout on every path
+pub static CONSTANT_SHAPE_RESPONSES: Item<bool> = Item::new(b"constant_shape_responses");
+
+// Per-address protocol fee discount in basis points of the fee, set by the manager
+pub static FEE_DISCOUNTS: Keymap<Addr, Uint128> = Keymap::new(b"fee_discounts");
+
+// Discount in basis points applied to users registered in the registration contract (0 disables the lookup)
+pub static REGISTERED_USER_DISCOUNT: Item<Uint128> = Item::new(b"registered_user_discount");
+
+// Contracts that swap on behalf of the trader they name in `user`: the router, and any pool the
+// factory indexes, which hops its own output on to the next leg
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct Forwarders {
+    pub router_contract: Option<Addr>,
+    pub factory_contract: Option<Addr>,
+    pub factory_hash: String,
+}
+
+pub static FORWARDERS: Item<Forwarders> = Item::new(b"forwarders");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeToken {
+    // Convert fees paid in the other token to the fee side through a feeless swap against the pool
+    #[serde(alias = "erth")]
+    FeeSide,
+    // Keep the fee in whichever token was swapped in
+    Input,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct FeeRecipient {
+    pub address: Addr,
+    pub code_hash: Option<String>,
+    pub share: Uint128, // Basis points of the fee, all shares must sum to 10000
+    pub msg: Option<Binary>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeDestination {
+    // Send to the LP staking contract with `SendMessage::BurnErth`
+    Staking {},
+    // Hold in the contract until the manager claims them
+    Accumulate {},
+    // Split by percentage among several recipients
+    Split { recipients: Vec<FeeRecipient> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct FeeConfig {
+    pub destination: FeeDestination,
+    pub fee_token: FeeToken,
+}
+
+impl Default for FeeConfig {
+    fn default() -> Self {
+        FeeConfig {
+            destination: FeeDestination::Staking {},
+            fee_token: FeeToken::FeeSide,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct AccumulatedFees {
+    pub erth: Uint128,
+    pub token_b: Uint128,
+}
+
+// Missing on older deployments, which fall back to `FeeConfig::default()`
+pub static FEE_CONFIG: Item<FeeConfig> = Item::new(b"fee_config");
+
+pub static ACCUMULATED_FEES: Item<AccumulatedFees> = Item::new(b"accumulated_fees");
+
+// Staking-bound protocol fees (in the fee side token) waiting for the next `FlushFees`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct ProtocolFeesAccrued {
+    pub amount: Uint128,
+    pub trade_volume: Uint128,
+    pub last_flush: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct FlushConfig {
+    pub threshold: Uint128, // Minimum accrued fee side tokens before a flush is allowed
+    pub interval: u64,      // Seconds since the last flush after which any amount may be flushed
+}
+
+pub static PROTOCOL_FEES_ACCRUED: Item<ProtocolFeesAccrued> = Item::new(b"protocol_fees_accrued");
+
+pub static FLUSH_CONFIG: Item<FlushConfig> = Item::new(b"flush_config");
+
+// Amplification moves linearly from `initial_amp` at `initial_time` to `future_amp` at `future_time`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct AmpRamp {
+    pub initial_amp: u64,
+    pub future_amp: u64,
+    pub initial_time: u64,
+    pub future_time: u64,
+}
+
+// Relative value each side holds, e.g. 80 and 20 for an 80/20 pool
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PoolWeights {
+    pub erth: u64,
+    pub b: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CurveConfig {
+    // x * y = k
+    ConstantProduct {},
+    // Curve-style invariant for pegged pairs, both tokens are assumed to share decimals
+    StableSwap { amp: AmpRamp },
+    // x ^ weight_erth * y ^ weight_b = k
+    Weighted { weights: PoolWeights },
+    // Liquidity is provided over tick ranges through positions instead of LP shares, see `CONCENTRATED_POOL`
+    Concentrated {
+        tick_spacing: u32,
+        lp_fee: Uint128, // Basis points of the input paid to in-range positions
+    },
+}
+
+// Missing on pools that predate it, which are all constant product
+pub static CURVE_CONFIG: Item<CurveConfig> = Item::new(b"curve_config");
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct TickInfo {
+    pub liquidity_gross: Uint128, // Liquidity of all positions bounded by this tick
+    pub liquidity_net: i128,      // Added to the active liquidity when the price crosses upwards
+    // Fee growth on the side of the tick away from the current price, wrapping like the global values
+    pub fee_growth_outside_erth: Uint256,
+    pub fee_growth_outside_b: Uint256,
+}
+
+// Price and active liquidity of a concentrated pool. Prices are token B per ERTH and tick `i` is at
+// price 1.0001^i. Fee growth is in fees per unit of liquidity scaled by 2^128 and wraps on overflow.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ConcentratedPool {
+    pub sqrt_price: Decimal256,
+    pub tick: i32, // Highest tick at or below the current price
+    pub liquidity: Uint128,
+    pub fee_growth_global_erth: Uint256,
+    pub fee_growth_global_b: Uint256,
+    pub next_position_id: u64,
+}
+
+pub static CONCENTRATED_POOL: Item<ConcentratedPool> = Item::new(b"concentrated_pool");
+
+// Initialized ticks, suffixed by the tick's big endian bytes
+pub static TICKS: Item<TickInfo> = Item::new(b"ticks");
+
+// One bit per tick spacing for whether that tick is initialized, 128 to a word, suffixed by the
+// word's index as big endian bytes
+pub static TICK_BITMAP: Item<u128> = Item::new(b"tick_bitmap");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct Position {
+    pub id: u64,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: Uint128,
+    pub fee_growth_inside_erth_last: Uint256,
+    pub fee_growth_inside_b_last: Uint256,
+    pub tokens_owed_erth: Uint128, // Collectable fees as of the last update
+    pub tokens_owed_b: Uint128,
+}
+
+// Keyed by position id and suffixed with the owner's address
+pub static POSITIONS: Keymap<u64, Position> = Keymap::new(b"positions");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct LimitOrder {
+    pub owner: Addr,
+    pub input_token: Addr, // Token deposited and sold into the pool
+    pub amount: Uint128,
+    pub price: Decimal256, // Output token per input token at which the order triggers
+    pub min_out: Uint128,
+}
+
+// Deposited tokens of resting orders are held outside the reserves
+pub static LIMIT_ORDERS: Keymap<u64, LimitOrder> = Keymap::new(b"limit_orders");
+
+// Resting orders as (price, id) in ascending order, suffixed with the address of the token they sell
+pub static ORDER_BOOK: Item<Vec<(Decimal256, u64)>> = Item::new(b"order_book");
+
+pub static NEXT_LIMIT_ORDER_ID: Item<u64> = Item::new(b"next_limit_order_id");
+
+// Sells `remaining` into the pool `amount_per_slice` at a time, one slice every `interval` seconds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct DcaSchedule {
+    pub owner: Addr,
+    pub input_token: Addr,
+    pub remaining: Uint128,
+    pub amount_per_slice: Uint128,
+    pub min_out_per_slice: Uint128,
+    pub interval: u64,
+    pub next_execution: u64,
+}
+
+// Deposits of running schedules are held outside the reserves, like limit orders
+pub static DCA_SCHEDULES: Keymap<u64, DcaSchedule> = Keymap::new(b"dca_schedules");
+
+pub static NEXT_DCA_ID: Item<u64> = Item::new(b"next_dca_id");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct BatchIntent {
+    pub owner: Addr,
+    pub input_token: Addr,
+    pub amount: Uint128,
+    pub protocol_fee: Uint128, // Basis points after the owner's discount, fixed when submitted
+    pub min_out: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PendingBatch {
+    pub start_height: u64,
+    pub intents: Vec<BatchIntent>,
+}
 
+// Length of a batch in blocks, missing or 0 leaves batch swaps disabled
+pub static BATCH_WINDOW: Item<u64> = Item::new(b"batch_window");
 
+// Smallest intent a batch takes, valued in the fee side token, missing or 0 for no minimum
+pub static BATCH_MIN_INTENT: Item<Uint128> = Item::new(b"batch_min_intent");
 
+// Intents collected until the window closes, deposits are held outside the reserves
+pub static PENDING_BATCH: Item<PendingBatch> = Item::new(b"pending_batch");
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct BatchClaim {
+    pub amount_erth: Uint128,
+    pub amount_b: Uint128,
+}
 
+// Outputs of settled batches and refunds of intents that missed their minimum, until claimed
+pub static BATCH_CLAIMS: Keymap<Addr, BatchClaim> = Keymap::new(b"batch_claims");
 
+// Uniswap-v2-style running sums of price (18 decimal fixed point) multiplied by seconds elapsed
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct PriceAccumulator {
+    pub price_erth_cumulative: Uint256, // Token B per ERTH
+    pub price_b_cumulative: Uint256,    // ERTH per token B
+    pub last_update: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PriceObservation {
+    pub timestamp: u64,
+    pub price_erth_cumulative: Uint256,
+    pub price_b_cumulative: Uint256,
+}
+
+pub static PRICE_ACCUMULATOR: Item<PriceAccumulator> = Item::new(b"price_accumulator");
+
+// Ring buffer of accumulator checkpoints, oldest at the front. Built per use instead of held in a
+// `static`, as the toolkit caches the length in the instance and unit tests would share it.
+pub fn price_observations() -> DequeStore<'static, PriceObservation> {
+    DequeStore::new(b"price_observations")
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ReserveSnapshot {
+    pub height: u64,
+    pub time: u64,
+    pub token_erth_reserve: Uint128,
+    pub token_b_reserve: Uint128,
+    pub total_shares: Uint128,
+}
+
+// Number of snapshots kept, 0 disables snapshotting
+pub static SNAPSHOT_CAPACITY: Item<u32> = Item::new(b"snapshot_capacity");
+
+// Ring buffer of per-block reserve snapshots, oldest at the front. Built per use, like `price_observations`.
+pub fn reserve_snapshots() -> DequeStore<'static, ReserveSnapshot> {
+    DequeStore::new(b"reserve_snapshots")
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAction {
+    Swap {
+        input_token: Addr,
+        input_amount: Uint128,
+        output_token: Addr,
+        output_amount: Uint128,
+        protocol_fee_amount: Uint128,
+    },
+    AddLiquidity {
+        amount_erth: Uint128,
+        amount_b: Uint128,
+        shares: Uint128,
+    },
+    UnbondLiquidity {
+        lp_token_amount: Uint128,
+        amount_erth: Uint128,
+        amount_b: Uint128,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct UserHistoryEntry {
+    pub height: u64,
+    pub time: u64,
+    pub action: UserAction,
+}
 
+// Suffixed with the user's address, only readable with a viewing key or permit
+pub static USER_HISTORY: AppendStore<UserHistoryEntry> = AppendStore::new(b"user_history");